@@ -0,0 +1,470 @@
+use cgmath::Vector3;
+
+use crate::dynamics;
+use crate::integrator::VectorField;
+use crate::registry::{Model, Params};
+
+/// A vector field seen through a view
+///
+/// World positions are `scale` times the offset from `center` in the
+/// field's own coordinates, and time runs `speed` times as fast, so that
+/// attractors of any size and pace fill the default camera view and spawn
+/// region (a few units around the origin) at a watchable rate.
+pub struct Viewed<F: VectorField> {
+    pub field: F,
+    pub center: Vector3<f64>,
+    pub scale: f64,
+    pub speed: f64,
+}
+
+impl<F: VectorField> VectorField for Viewed<F> {
+    fn derivative(&self, state: Vector3<f64>, t: f64) -> Vector3<f64> {
+        let local = self.center + state / self.scale;
+        self.speed * self.scale * self.field.derivative(local, self.speed * t)
+    }
+}
+
+pub struct Rossler {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl VectorField for Rossler {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            -p.y - p.z,
+            p.x + self.a * p.y,
+            self.b + p.z * (p.x - self.c),
+        )
+    }
+}
+
+pub struct Chen {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl VectorField for Chen {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            self.a * (p.y - p.x),
+            (self.c - self.a) * p.x - p.x * p.z + self.c * p.y,
+            p.x * p.y - self.b * p.z,
+        )
+    }
+}
+
+/// Thomas' cyclically symmetric attractor
+pub struct Thomas {
+    pub b: f64,
+}
+
+impl VectorField for Thomas {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            p.y.sin() - self.b * p.x,
+            p.z.sin() - self.b * p.y,
+            p.x.sin() - self.b * p.z,
+        )
+    }
+}
+
+pub struct Aizawa {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+    pub e: f64,
+    pub f: f64,
+}
+
+impl VectorField for Aizawa {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            (p.z - self.b) * p.x - self.d * p.y,
+            self.d * p.x + (p.z - self.b) * p.y,
+            self.c + self.a * p.z
+                - p.z.powi(3) / 3.0
+                - (p.x * p.x + p.y * p.y) * (1.0 + self.e * p.z)
+                + self.f * p.z * p.x.powi(3),
+        )
+    }
+}
+
+pub struct Halvorsen {
+    pub a: f64,
+}
+
+impl VectorField for Halvorsen {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            -self.a * p.x - 4.0 * p.y - 4.0 * p.z - p.y * p.y,
+            -self.a * p.y - 4.0 * p.z - 4.0 * p.x - p.z * p.z,
+            -self.a * p.z - 4.0 * p.x - 4.0 * p.y - p.x * p.x,
+        )
+    }
+}
+
+pub struct Dadras {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+    pub e: f64,
+}
+
+impl VectorField for Dadras {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            p.y - self.a * p.x + self.b * p.y * p.z,
+            self.c * p.y - p.x * p.z + p.z,
+            self.d * p.x * p.y - self.e * p.z,
+        )
+    }
+}
+
+/// Sprott's 2014 "symmetric" attractor
+pub struct Sprott {
+    pub a: f64,
+    pub b: f64,
+}
+
+impl VectorField for Sprott {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            p.y + self.a * p.x * p.y + p.x * p.z,
+            1.0 - self.b * p.x * p.x + p.y * p.z,
+            p.x - p.x * p.x - p.y * p.y,
+        )
+    }
+}
+
+/// Case B of Sprott's simplest quadratic chaotic flows
+pub struct SprottB;
+
+impl VectorField for SprottB {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(p.y * p.z, p.x - p.y, 1.0 - p.x * p.y)
+    }
+}
+
+pub struct RabinovichFabrikant {
+    pub alpha: f64,
+    pub gamma: f64,
+}
+
+impl VectorField for RabinovichFabrikant {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            p.y * (p.z - 1.0 + p.x * p.x) + self.gamma * p.x,
+            p.x * (3.0 * p.z + 1.0 - p.x * p.x) + self.gamma * p.y,
+            -2.0 * p.z * (self.alpha + p.x * p.y),
+        )
+    }
+}
+
+/// Chua's circuit, with a smooth cubic diode
+pub struct Chua {
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl VectorField for Chua {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        let diode = p.x.powi(3) / 16.0 - p.x / 6.0;
+        Vector3::new(
+            self.alpha * (p.y - diode),
+            p.x - p.y + p.z,
+            -self.beta * p.y,
+        )
+    }
+}
+
+pub struct FourWing {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl VectorField for FourWing {
+    fn derivative(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(
+            self.a * p.x + p.y * p.z,
+            self.b * p.x + self.c * p.y - p.x * p.z,
+            -p.z - p.x * p.y,
+        )
+    }
+}
+
+/// Where an attractor sits in its own coordinates, and how fast to run it
+/// by default
+pub(crate) struct View {
+    pub center: [f64; 3],
+    pub scale: f64,
+    pub speed: f64,
+}
+
+/// The view of the attractor called `name`, if there is one by that name
+/// (compute.rs needs these to run the attractors on the GPU)
+pub(crate) fn view(name: &str) -> Option<View> {
+    let view = match name {
+        "rossler" => View {
+            center: [0.0, -2.0, 4.0],
+            scale: 0.35,
+            speed: 1.0,
+        },
+        "chen" => View {
+            center: [0.0, 0.0, 23.0],
+            scale: 0.16,
+            speed: 0.1,
+        },
+        "thomas" => View {
+            center: [0.0, 0.0, 0.0],
+            scale: 1.0,
+            speed: 2.0,
+        },
+        "aizawa" => View {
+            center: [0.0, 0.0, 0.7],
+            scale: 2.5,
+            speed: 1.0,
+        },
+        "halvorsen" => View {
+            center: [-2.5, -2.5, -2.5],
+            scale: 0.4,
+            speed: 0.5,
+        },
+        "dadras" => View {
+            center: [0.0, 0.0, 0.0],
+            scale: 0.3,
+            speed: 0.5,
+        },
+        "sprott" => View {
+            center: [0.5, 0.0, 0.0],
+            scale: 2.5,
+            speed: 1.0,
+        },
+        "sprott_b" => View {
+            center: [0.0, 0.0, 0.0],
+            scale: 0.7,
+            speed: 1.0,
+        },
+        "rabinovich_fabrikant" => View {
+            center: [0.0, 0.0, 0.5],
+            scale: 2.0,
+            speed: 0.5,
+        },
+        "chua" => View {
+            center: [0.0, 0.0, 0.0],
+            scale: 0.4,
+            speed: 0.5,
+        },
+        "four_wing" => View {
+            center: [0.0, 0.0, 0.0],
+            scale: 1.3,
+            speed: 2.0,
+        },
+        _ => return None,
+    };
+    Some(view)
+}
+
+/// A registry entry for an attractor: its own parameters (name, default
+/// and range), plus `speed` and `scale` for its view
+fn attractor<F, B>(
+    name: &str,
+    description: &str,
+    params: &[(&str, f64, f64, f64)],
+    make: B,
+) -> Model
+where
+    F: VectorField + 'static,
+    B: Fn(&Params) -> F + Send + Sync + 'static,
+{
+    let view = view(name).expect("every attractor has a view");
+    let center = Vector3::from(view.center);
+    let mut model = Model::new(
+        name,
+        description,
+        move |params, position, method, _chaos| {
+            let field = Viewed {
+                field: make(params),
+                center,
+                scale: params.get("scale"),
+                speed: params.get("speed"),
+            };
+            Box::new(dynamics::Flow::new(field, position, method))
+        },
+    );
+    for (name, default, min, max) in params.iter() {
+        model = model.param(name, *default, *min..=*max);
+    }
+    model
+        .param("speed", view.speed, 0.0..=100.0)
+        .param("scale", view.scale, 0.001..=1000.0)
+}
+
+/// The catalog, registered along with the other built in models
+pub fn models() -> Vec<Model> {
+    vec![
+        attractor(
+            "rossler",
+            "Rössler attractor",
+            &[
+                ("a", 0.2, -1.0, 1.0),
+                ("b", 0.2, 0.0, 5.0),
+                ("c", 5.7, 0.0, 50.0),
+            ],
+            |p| Rossler {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+            },
+        ),
+        attractor(
+            "chen",
+            "Chen attractor, a cousin of Lorenz'",
+            &[
+                ("a", 35.0, 0.0, 100.0),
+                ("b", 3.0, 0.0, 20.0),
+                ("c", 28.0, 0.0, 100.0),
+            ],
+            |p| Chen {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+            },
+        ),
+        attractor(
+            "thomas",
+            "Thomas' cyclically symmetric attractor",
+            &[("b", 0.208_186, 0.0, 1.0)],
+            |p| Thomas { b: p.get("b") },
+        ),
+        attractor(
+            "aizawa",
+            "Aizawa attractor",
+            &[
+                ("a", 0.95, 0.0, 2.0),
+                ("b", 0.7, 0.0, 2.0),
+                ("c", 0.6, 0.0, 2.0),
+                ("d", 3.5, 0.0, 10.0),
+                ("e", 0.25, 0.0, 1.0),
+                ("f", 0.1, 0.0, 1.0),
+            ],
+            |p| Aizawa {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+                d: p.get("d"),
+                e: p.get("e"),
+                f: p.get("f"),
+            },
+        ),
+        attractor(
+            "halvorsen",
+            "Halvorsen's cyclically symmetric attractor",
+            &[("a", 1.89, 0.0, 5.0)],
+            |p| Halvorsen { a: p.get("a") },
+        ),
+        attractor(
+            "dadras",
+            "Dadras attractor",
+            &[
+                ("a", 3.0, 0.0, 10.0),
+                ("b", 2.7, 0.0, 10.0),
+                ("c", 1.7, 0.0, 10.0),
+                ("d", 2.0, 0.0, 10.0),
+                ("e", 9.0, 0.0, 20.0),
+            ],
+            |p| Dadras {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+                d: p.get("d"),
+                e: p.get("e"),
+            },
+        ),
+        attractor(
+            "sprott",
+            "Sprott's symmetric attractor (2014)",
+            &[("a", 2.07, 0.0, 5.0), ("b", 1.79, 0.0, 5.0)],
+            |p| Sprott {
+                a: p.get("a"),
+                b: p.get("b"),
+            },
+        ),
+        attractor(
+            "sprott_b",
+            "Sprott case B: x' = yz, y' = x - y, z' = 1 - xy",
+            &[],
+            |_| SprottB,
+        ),
+        attractor(
+            "rabinovich_fabrikant",
+            "Rabinovich-Fabrikant equations",
+            &[("alpha", 0.14, 0.0, 2.0), ("gamma", 0.1, 0.0, 2.0)],
+            |p| RabinovichFabrikant {
+                alpha: p.get("alpha"),
+                gamma: p.get("gamma"),
+            },
+        ),
+        attractor(
+            "chua",
+            "Chua's circuit, the double scroll",
+            &[("alpha", 10.0, 0.0, 50.0), ("beta", 16.0, 0.0, 100.0)],
+            |p| Chua {
+                alpha: p.get("alpha"),
+                beta: p.get("beta"),
+            },
+        ),
+        attractor(
+            "four_wing",
+            "Four-wing attractor",
+            &[
+                ("a", 0.2, -2.0, 2.0),
+                ("b", 0.01, -2.0, 2.0),
+                ("c", -0.4, -2.0, 2.0),
+            ],
+            |p| FourWing {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+            },
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::Method;
+    use crate::rand_util::Chaos;
+    use cgmath::InnerSpace;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn attractors_stay_in_view() {
+        let mut chaos = Chaos::from_seed(3);
+        for model in models() {
+            for _ in 0..10 {
+                // some of these have small basins (halvorsen and
+                // rabinovich_fabrikant blow up from parts of the default
+                // spawn cube), so start close to the view's center
+                let start = chaos.random_position_in_cube(1.0);
+                let mut particle = model.build(&BTreeMap::new(), start, Method::Rk4, &mut chaos);
+                for _ in 0..10_000 {
+                    particle.step(1.0 / 60.0, &mut chaos);
+                }
+                let distance = particle.get_position().magnitude();
+                assert!(
+                    distance < 10.0,
+                    "{} ended up {} away from the origin",
+                    model.name,
+                    distance
+                );
+            }
+        }
+    }
+}
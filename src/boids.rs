@@ -0,0 +1,212 @@
+use cgmath::{InnerSpace, Vector3, Zero};
+use rayon::prelude::*;
+
+use crate::interaction::{Body, Interaction};
+use crate::registry::Model;
+use crate::simulation::MIN_PARTICLES_PER_TASK;
+use crate::spatial_hash::SpatialHash;
+
+/// Reynolds' flocking rules
+///
+/// Every boid steers by what it sees of the others within `radius`:
+/// away from the ones that are too close (separation), towards their
+/// average heading (alignment) and towards their center (cohesion).
+/// Neighbors are found with a spatial hash, so a step is O(N) for flocks
+/// that aren't too crowded.
+pub struct Boids {
+    pub radius: f64,
+    // boids closer than this push each other away
+    pub separation_radius: f64,
+    // weights of the three rules
+    pub separation: f64,
+    pub alignment: f64,
+    pub cohesion: f64,
+    // boids fly between half and all of this speed
+    pub max_speed: f64,
+    // most a boid can steer, as an acceleration
+    pub max_force: f64,
+    // boids turn back towards the origin when they get further out than
+    // this, 0 lets them go anywhere
+    pub bounds: f64,
+}
+
+impl Boids {
+    /// How a boid at `ix` wants to steer
+    fn steering(
+        &self,
+        bodies: &[Body],
+        positions: &[Vector3<f64>],
+        hash: &SpatialHash,
+        ix: usize,
+    ) -> Vector3<f64> {
+        let me = bodies[ix];
+        let mut n_neighbors = 0;
+        let mut center = Vector3::zero();
+        let mut heading = Vector3::zero();
+        let mut away = Vector3::zero();
+        hash.for_each_neighbor(positions, me.position, self.radius, |other, distance2| {
+            if other == ix {
+                return;
+            }
+            n_neighbors += 1;
+            center += positions[other];
+            heading += bodies[other].velocity;
+            if distance2 < self.separation_radius * self.separation_radius && distance2 > 0.0 {
+                // pushes harder the closer they are
+                away += (me.position - positions[other]) / distance2;
+            }
+        });
+
+        let mut steer = self.separation * away;
+        if n_neighbors > 0 {
+            let n = n_neighbors as f64;
+            steer += self.alignment * (heading / n - me.velocity);
+            steer += self.cohesion * (center / n - me.position);
+        }
+        let distance = me.position.magnitude();
+        if self.bounds > 0.0 && distance > self.bounds {
+            steer -= (distance - self.bounds) * me.position / distance;
+        }
+        if steer.magnitude2() > self.max_force * self.max_force {
+            steer = self.max_force * steer.normalize();
+        }
+        steer
+    }
+
+    fn limit_speed(&self, velocity: Vector3<f64>) -> Vector3<f64> {
+        let speed = velocity.magnitude();
+        if speed == 0.0 {
+            velocity
+        } else {
+            velocity * speed.max(0.5 * self.max_speed).min(self.max_speed) / speed
+        }
+    }
+}
+
+impl Interaction for Boids {
+    fn step(&mut self, bodies: &mut [Body], dt: f64) {
+        let positions = bodies.iter().map(|b| b.position).collect::<Vec<_>>();
+        let hash = SpatialHash::new(self.radius, &positions);
+        // every boid looks at where the others were at the start of the step
+        let velocities = (0..bodies.len())
+            .into_par_iter()
+            .with_min_len(MIN_PARTICLES_PER_TASK)
+            .map(|ix| {
+                let steer = self.steering(bodies, &positions, &hash, ix);
+                self.limit_speed(bodies[ix].velocity + dt * steer)
+            })
+            .collect::<Vec<_>>();
+        for (body, velocity) in bodies.iter_mut().zip(velocities) {
+            body.velocity = velocity;
+            body.position += dt * velocity;
+        }
+    }
+}
+
+/// The registry entry
+pub fn model() -> Model {
+    Model::interacting(
+        "boids",
+        "Flocking, each particle steers by its neighbors within `radius`",
+        |params, bodies, chaos| {
+            let boids = Boids {
+                radius: params.get("radius"),
+                separation_radius: params.get("separation_radius"),
+                separation: params.get("separation"),
+                alignment: params.get("alignment"),
+                cohesion: params.get("cohesion"),
+                max_speed: params.get("max_speed"),
+                max_force: params.get("max_force"),
+                bounds: params.get("bounds"),
+            };
+            // fly off in random directions, on top of the group's velocity
+            for body in bodies.iter_mut() {
+                let direction = chaos.random_position_in_ball(1.0);
+                if direction.magnitude2() > 0.0 {
+                    body.velocity += boids.max_speed * direction.normalize();
+                }
+                body.velocity = boids.limit_speed(body.velocity);
+            }
+            Box::new(boids)
+        },
+    )
+    .param("radius", 1.0, 0.01..=100.0)
+    .param("separation_radius", 0.3, 0.0..=100.0)
+    .param("separation", 1.5, 0.0..=100.0)
+    .param("alignment", 1.0, 0.0..=100.0)
+    .param("cohesion", 0.5, 0.0..=100.0)
+    .param("max_speed", 2.0, 0.0..=100.0)
+    .param("max_force", 4.0, 0.0..=1000.0)
+    .param("bounds", 5.0, 0.0..=1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_util::Chaos;
+    use std::collections::BTreeMap;
+
+    fn flock(n: usize) -> (Vec<Body>, Box<dyn Interaction>) {
+        let mut chaos = Chaos::from_seed(21);
+        let mut bodies = (0..n)
+            .map(|_| Body::at(chaos.random_position_in_cube(2.0)))
+            .collect::<Vec<_>>();
+        let boids = model()
+            .build_interaction(&BTreeMap::new(), &mut bodies, &mut chaos)
+            .unwrap();
+        (bodies, boids)
+    }
+
+    // 1 when every boid flies the same way as the boids around it, around
+    // 0 when they're random
+    fn local_order(bodies: &[Body]) -> f64 {
+        let positions = bodies.iter().map(|b| b.position).collect::<Vec<_>>();
+        let hash = SpatialHash::new(1.0, &positions);
+        let mut total = 0.0;
+        for (ix, body) in bodies.iter().enumerate() {
+            let mut heading = Vector3::zero();
+            hash.for_each_neighbor(&positions, body.position, 1.0, |other, _| {
+                if other != ix {
+                    heading += bodies[other].velocity.normalize();
+                }
+            });
+            if heading.magnitude2() > 0.0 {
+                total += heading.normalize().dot(body.velocity.normalize());
+            }
+        }
+        total / bodies.len() as f64
+    }
+
+    #[test]
+    fn boids_line_up() {
+        let (mut bodies, mut boids) = flock(200);
+        assert!(local_order(&bodies) < 0.3);
+        for _ in 0..600 {
+            boids.step(&mut bodies, 1.0 / 60.0);
+        }
+        assert!(local_order(&bodies) > 0.8, "order {}", local_order(&bodies));
+        for body in bodies.iter() {
+            assert!(body.velocity.magnitude() <= 2.0 + 1e-4);
+            assert!(body.position.magnitude() < 10.0);
+        }
+    }
+
+    #[test]
+    fn boids_keep_their_distance() {
+        let (mut bodies, mut boids) = flock(200);
+        for _ in 0..600 {
+            boids.step(&mut bodies, 1.0 / 60.0);
+        }
+        let positions = bodies.iter().map(|b| b.position).collect::<Vec<_>>();
+        let hash = SpatialHash::new(1.0, &positions);
+        let mut crowded = 0;
+        for (ix, p) in positions.iter().enumerate() {
+            hash.for_each_neighbor(&positions, *p, 0.05, |other, _| {
+                if other != ix {
+                    crowded += 1;
+                }
+            });
+        }
+        assert!(crowded < 10, "{} boids are on top of another", crowded);
+    }
+}
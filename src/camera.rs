@@ -14,13 +14,15 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
 
 #[derive(Debug)]
 pub struct Camera {
-    pub position: Point3<f32>,
+    // in f64 like the particles, so that it can be far from the world's
+    // origin and still move smoothly
+    pub position: Point3<f64>,
     yaw: Rad<f32>,
     pitch: Rad<f32>,
 }
 
 impl Camera {
-    pub fn new<V: Into<Point3<f32>>, Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(
+    pub fn new<V: Into<Point3<f64>>, Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(
         position: V,
         yaw: Y,
         pitch: P,
@@ -32,9 +34,16 @@ impl Camera {
         }
     }
 
-    pub fn calc_matrix(&self) -> Matrix4<f32> {
+    /// The position relative to `origin`, which is where the GPU's
+    /// coordinates are centered (see `sphere::render_position`)
+    pub fn eye(&self, origin: Vector3<f64>) -> Point3<f32> {
+        let offset = self.position - origin;
+        Point3::new(offset.x as f32, offset.y as f32, offset.z as f32)
+    }
+
+    pub fn calc_matrix(&self, origin: Vector3<f64>) -> Matrix4<f32> {
         Matrix4::look_to_rh(
-            self.position,
+            self.eye(origin),
             Vector3::new(self.yaw.0.cos(), self.pitch.0.sin(), self.yaw.0.sin()).normalize(),
             Vector3::unit_y(),
         )
@@ -154,8 +163,8 @@ impl CameraController {
         let (yaw_sin, yaw_cos) = camera.yaw.0.sin_cos();
         let forward = Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
         let right = Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
-        camera.position += forward * (self.amount_forward - self.amount_backward) * self.speed * dt;
-        camera.position += right * (self.amount_right - self.amount_left) * self.speed * dt;
+        let mut motion = forward * (self.amount_forward - self.amount_backward) * self.speed * dt;
+        motion += right * (self.amount_right - self.amount_left) * self.speed * dt;
 
         // Move in/out (aka. "zoom")
         // Note: this isn't an actual zoom. The camera's position
@@ -164,12 +173,13 @@ impl CameraController {
         let (pitch_sin, pitch_cos) = camera.pitch.0.sin_cos();
         let scrollward =
             Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
-        camera.position += scrollward * self.scroll * self.speed * self.sensitivity * dt;
+        motion += scrollward * self.scroll * self.speed * self.sensitivity * dt;
         self.scroll = 0.0;
 
         // Move up/down. Since we don't use roll, we can just
         // modify the y coordinate directly.
-        camera.position.y += (self.amount_up - self.amount_down) * self.speed * dt;
+        motion.y += (self.amount_up - self.amount_down) * self.speed * dt;
+        camera.position += Vector3::new(motion.x as f64, motion.y as f64, motion.z as f64);
 
         // Rotate
         camera.yaw += Rad(self.rotate_horizontal) * self.sensitivity * dt;
@@ -0,0 +1,333 @@
+use cgmath::{InnerSpace, Vector3, Zero};
+
+use crate::dynamics::DynamicSystem;
+use crate::rand_util::Chaos;
+use crate::registry::{Model, Params};
+
+/// Electric and magnetic fields, both static
+pub trait EmField: Send + Sync {
+    /// E and B at a point
+    fn at(&self, p: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>);
+}
+
+/// The same E and B everywhere: gyration along B, drifting along E x B
+pub struct Uniform {
+    pub e: Vector3<f64>,
+    pub b: Vector3<f64>,
+}
+
+impl EmField for Uniform {
+    fn at(&self, _p: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+        (self.e, self.b)
+    }
+}
+
+/// A magnetic dipole at the origin pointing along z, like the Earth's
+/// field: particles bounce between the poles and drift around the axis
+pub struct Dipole {
+    pub moment: f64,
+    // the field is smoothed out inside this radius instead of blowing up
+    pub core: f64,
+}
+
+impl EmField for Dipole {
+    fn at(&self, p: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+        // the curl of the softened vector potential m (z x p) / r^3, so it
+        // stays divergence free inside the core too
+        let c2 = self.core * self.core;
+        let r2 = p.magnitude2() + c2;
+        let r5 = r2 * r2 * r2.sqrt();
+        let b = 3.0 * p.z * p - (r2 - 3.0 * c2) * Vector3::unit_z();
+        (Vector3::zero(), self.moment * b / r5)
+    }
+}
+
+/// A magnetic mirror along z: the field is `b0` in the middle and gets
+/// stronger away from it (twice as strong at z = +-`length`), so particles
+/// that aren't moving too much along the axis are reflected back
+pub struct Bottle {
+    pub b0: f64,
+    pub length: f64,
+}
+
+impl EmField for Bottle {
+    fn at(&self, p: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+        // the radial part keeps the field divergence free
+        let l2 = self.length * self.length;
+        let b = self.b0 * Vector3::new(-p.x * p.z / l2, -p.y * p.z / l2, 1.0 + p.z * p.z / l2);
+        (Vector3::zero(), b)
+    }
+}
+
+/// A field along z with a transverse part that turns around the axis once
+/// every `pitch`, so field lines are helices
+pub struct Helical {
+    pub b0: f64,
+    pub transverse: f64,
+    pub pitch: f64,
+}
+
+impl EmField for Helical {
+    fn at(&self, p: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+        let angle = 2.0 * std::f64::consts::PI * p.z / self.pitch;
+        let b = Vector3::new(
+            self.transverse * angle.cos(),
+            self.transverse * angle.sin(),
+            self.b0,
+        );
+        (Vector3::zero(), b)
+    }
+}
+
+/// A charged particle pushed around by the Lorentz force,
+/// a = q/m (E + v x B)
+///
+/// Stepped with the Boris pusher: half an electric kick, an exact-length
+/// rotation of the velocity around B, then the other half kick.  The
+/// magnetic part never changes the speed, so gyration stays stable over
+/// long runs instead of spiralling out like it does with RK4.
+pub struct ChargedParticle<F: EmField> {
+    pub field: F,
+    pub charge_to_mass: f64,
+    pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+}
+
+impl<F: EmField> DynamicSystem for ChargedParticle<F> {
+    fn step(&mut self, dt: f64, _chaos: &mut Chaos) {
+        let (e, b) = self.field.at(self.position);
+        let half = 0.5 * self.charge_to_mass * dt;
+        let v_minus = self.velocity + half * e;
+        let t = half * b;
+        let s = 2.0 * t / (1.0 + t.magnitude2());
+        let v_prime = v_minus + v_minus.cross(t);
+        let v_plus = v_minus + v_prime.cross(s);
+        self.velocity = v_plus + half * e;
+        self.position += dt * self.velocity;
+    }
+
+    fn get_position(&self) -> Vector3<f64> {
+        self.position
+    }
+
+    // keeps the velocity
+    fn set_position(&mut self, position: Vector3<f64>) {
+        self.position = position;
+    }
+}
+
+/// A registry entry for a charged particle in a kind of field, along with
+/// the parameters every one of them has
+fn charged<F, B>(name: &str, description: &str, params: &[(&str, f64, f64, f64)], make: B) -> Model
+where
+    F: EmField + 'static,
+    B: Fn(&Params) -> F + Send + Sync + 'static,
+{
+    let mut model = Model::new(
+        name,
+        description,
+        move |params, position, _method, chaos| {
+            // particles start off in random directions
+            let direction = chaos.random_position_in_ball(1.0);
+            let velocity = if direction.magnitude2() > 0.0 {
+                params.get("initial_speed") * direction.normalize()
+            } else {
+                Vector3::zero()
+            };
+            Box::new(ChargedParticle {
+                field: make(params),
+                charge_to_mass: params.get("charge_to_mass"),
+                position,
+                velocity,
+            })
+        },
+    );
+    for (name, default, min, max) in params.iter() {
+        model = model.param(name, *default, *min..=*max);
+    }
+    model
+        .param("charge_to_mass", 1.0, -100.0..=100.0)
+        .param("initial_speed", 1.0, 0.0..=100.0)
+}
+
+/// The charged particle models, registered along with the other built in
+/// models
+pub fn models() -> Vec<Model> {
+    vec![
+        charged(
+            "charged_uniform",
+            "Charged particles in uniform electric and magnetic fields",
+            &[
+                ("ex", 0.0, -100.0, 100.0),
+                ("ey", 0.0, -100.0, 100.0),
+                ("ez", 0.0, -100.0, 100.0),
+                ("bx", 0.0, -100.0, 100.0),
+                ("by", 0.0, -100.0, 100.0),
+                ("bz", 2.0, -100.0, 100.0),
+            ],
+            |p| Uniform {
+                e: Vector3::new(p.get("ex"), p.get("ey"), p.get("ez")),
+                b: Vector3::new(p.get("bx"), p.get("by"), p.get("bz")),
+            },
+        ),
+        charged(
+            "charged_dipole",
+            "Charged particles trapped in a magnetic dipole, like the radiation belts",
+            &[("moment", 50.0, -1000.0, 1000.0), ("core", 0.5, 0.01, 10.0)],
+            |p| Dipole {
+                moment: p.get("moment"),
+                core: p.get("core"),
+            },
+        ),
+        charged(
+            "charged_bottle",
+            "Charged particles bouncing in a magnetic bottle along z",
+            &[("b0", 2.0, -100.0, 100.0), ("length", 3.0, 0.01, 100.0)],
+            |p| Bottle {
+                b0: p.get("b0"),
+                length: p.get("length"),
+            },
+        ),
+        charged(
+            "charged_helix",
+            "Charged particles following a helical magnetic field along z",
+            &[
+                ("b0", 2.0, -100.0, 100.0),
+                ("transverse", 1.0, -100.0, 100.0),
+                ("pitch", 4.0, 0.01, 100.0),
+            ],
+            |p| Helical {
+                b0: p.get("b0"),
+                transverse: p.get("transverse"),
+                pitch: p.get("pitch"),
+            },
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particle<F: EmField>(field: F, velocity: Vector3<f64>) -> ChargedParticle<F> {
+        ChargedParticle {
+            field,
+            charge_to_mass: 1.0,
+            position: Vector3::zero(),
+            velocity,
+        }
+    }
+
+    #[test]
+    fn gyration_is_stable() {
+        let b = Vector3::new(0.0, 0.0, 2.0);
+        let mut p = particle(
+            Uniform {
+                e: Vector3::zero(),
+                b,
+            },
+            Vector3::unit_x(),
+        );
+        let mut chaos = Chaos::from_seed(1);
+        // around a thousand turns, at a coarse 20 steps per turn
+        let period = 2.0 * std::f64::consts::PI / 2.0;
+        let mut turn = || {
+            let mut center = Vector3::zero();
+            let mut radii = Vec::new();
+            for _ in 0..20 {
+                p.step(period / 20.0, &mut chaos);
+                assert!((p.velocity.magnitude() - 1.0).abs() < 1e-4);
+                center += p.position / 20.0;
+                radii.push(p.position);
+            }
+            let radii = radii
+                .iter()
+                .map(|q| (q - center).magnitude())
+                .collect::<Vec<_>>();
+            (center, radii)
+        };
+        let (first_center, first_radii) = turn();
+        for _ in 0..1000 {
+            let (center, radii) = turn();
+            // no drift, and the gyroradius stays the same
+            assert!((center - first_center).magnitude() < 1e-2);
+            for (r, first) in radii.iter().zip(first_radii.iter()) {
+                assert!((r - first).abs() < 1e-2);
+            }
+        }
+        assert!((first_radii[0] - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn drifts_along_e_cross_b() {
+        let e = Vector3::new(0.5, 0.0, 0.0);
+        let b = Vector3::new(0.0, 0.0, 2.0);
+        let mut p = particle(Uniform { e, b }, Vector3::zero());
+        let mut chaos = Chaos::from_seed(1);
+        let n_steps = 100_000;
+        let dt = 0.001;
+        for _ in 0..n_steps {
+            p.step(dt, &mut chaos);
+        }
+        let drift = p.position / (n_steps as f64 * dt);
+        let expected = e.cross(b) / b.magnitude2();
+        assert!((drift - expected).magnitude() < 0.01 * expected.magnitude());
+    }
+
+    #[test]
+    fn bottle_reflects_particles() {
+        let bottle = Bottle {
+            b0: 2.0,
+            length: 3.0,
+        };
+        // 60 degrees from the axis is outside the loss cone (45 degrees for
+        // a mirror ratio of 2)
+        let angle = std::f64::consts::PI / 3.0;
+        let mut p = particle(bottle, Vector3::new(angle.sin(), 0.0, angle.cos()));
+        let mut chaos = Chaos::from_seed(1);
+        let mut max_z: f64 = 0.0;
+        let mut min_z: f64 = 0.0;
+        for _ in 0..50_000 {
+            p.step(0.01, &mut chaos);
+            max_z = max_z.max(p.position.z);
+            min_z = min_z.min(p.position.z);
+        }
+        assert!(max_z > 1.0 && max_z < 3.0, "max z {}", max_z);
+        assert!(min_z < -1.0 && min_z > -3.0, "min z {}", min_z);
+    }
+
+    #[test]
+    fn magnetic_fields_are_divergence_free() {
+        let fields: Vec<Box<dyn EmField>> = vec![
+            Box::new(Dipole {
+                moment: 50.0,
+                core: 0.5,
+            }),
+            Box::new(Bottle {
+                b0: 2.0,
+                length: 3.0,
+            }),
+            Box::new(Helical {
+                b0: 2.0,
+                transverse: 1.0,
+                pitch: 4.0,
+            }),
+        ];
+        let mut chaos = Chaos::from_seed(2);
+        let h = 1e-2;
+        for field in fields.iter() {
+            for _ in 0..20 {
+                let p = chaos.random_position_in_cube(3.0);
+                let b = |d: Vector3<f64>| field.at(p + d).1;
+                let div = (b(h * Vector3::unit_x()).x - b(-h * Vector3::unit_x()).x
+                    + b(h * Vector3::unit_y()).y
+                    - b(-h * Vector3::unit_y()).y
+                    + b(h * Vector3::unit_z()).z
+                    - b(-h * Vector3::unit_z()).z)
+                    / (2.0 * h);
+                let scale = field.at(p).1.magnitude();
+                assert!(div.abs() < 1e-2 * scale.max(1.0), "divergence {}", div);
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+use wagoo::screenshot;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Particles, attractors and trails rendered with wgpu")]
+pub struct Opts {
+    /// Scene file (TOML) to load, the built in Lorenz scene is used if not given
+    #[structopt(parse(from_os_str))]
+    pub scene: Option<PathBuf>,
+
+    /// Open a regular window instead of going exclusive fullscreen
+    #[structopt(long)]
+    pub windowed: bool,
+
+    /// Window size or fullscreen resolution, e.g. 1920x1080
+    #[structopt(long)]
+    pub size: Option<Size>,
+
+    /// Index of the monitor to use
+    #[structopt(long, default_value = "0")]
+    pub monitor: usize,
+
+    /// Random seed, overrides the scene's seed
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Start with the simulation paused
+    #[structopt(long)]
+    pub paused: bool,
+
+    /// Dynamics model to use instead of the scene's, with its default
+    /// parameters (see --list-models)
+    #[structopt(long)]
+    pub model: Option<String>,
+
+    /// List the available dynamics models and their parameters, then exit
+    #[structopt(long)]
+    pub list_models: bool,
+
+    /// Estimate the Lyapunov exponents of the scene's model, print them and
+    /// whether it looks chaotic, then exit.  Exits with status 2 if it
+    /// doesn't, for sorting out parameter sets in scripts.  Not for
+    /// interacting models (nbody, boids), whose particles move together.
+    #[structopt(long)]
+    pub lyapunov: bool,
+
+    /// Total number of particles, split across the scene's groups
+    #[structopt(long)]
+    pub particles: Option<usize>,
+
+    /// Step the particles in a compute shader on the GPU, see the README
+    /// for the models that support it
+    #[structopt(long)]
+    pub gpu: bool,
+
+    /// Render a single image without opening a window, --size sets the
+    /// resolution (default 1920x1080)
+    #[structopt(long)]
+    pub headless: bool,
+
+    /// Headless: number of simulation steps to run before rendering
+    /// (default 3600, or 0 when recording)
+    #[structopt(long, conflicts_with = "time")]
+    pub steps: Option<u64>,
+
+    /// Headless: simulated time (seconds) to run before rendering
+    #[structopt(long)]
+    pub time: Option<f64>,
+
+    /// Headless: where to save the image, defaults to a timestamped file
+    /// in screenshots/
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// Also save screenshots untonemapped, as exr (OpenEXR) or hdr
+    /// (Radiance)
+    #[structopt(long)]
+    pub hdr: Option<screenshot::HdrFormat>,
+
+    /// Record an animation, to a .y4m video or else a directory of
+    /// numbered PNG frames
+    #[structopt(long, parse(from_os_str))]
+    pub record: Option<PathBuf>,
+
+    /// Recording: number of frames to save (default 600 when headless,
+    /// until exit otherwise)
+    #[structopt(long)]
+    pub frames: Option<u64>,
+
+    /// Recording: save every Nth rendered frame
+    #[structopt(long, default_value = "1")]
+    pub every: u32,
+
+    /// Recording: rate frames are rendered at, each frame advances the
+    /// simulation by exactly 1/fps seconds (times the time scale)
+    #[structopt(long, default_value = "60")]
+    pub fps: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Size {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |dim: &str| -> Result<u32> {
+            match dim.trim().parse::<u32>() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(anyhow!("Expected a size like 1920x1080, got '{}'", s)),
+            }
+        };
+        let mut parts = s.splitn(2, &['x', 'X'][..]);
+        match (parts.next(), parts.next()) {
+            (Some(w), Some(h)) => Ok(Self {
+                width: parse(w)?,
+                height: parse(h)?,
+            }),
+            _ => Err(anyhow!("Expected a size like 1920x1080, got '{}'", s)),
+        }
+    }
+}
+
+impl From<Size> for winit::dpi::PhysicalSize<u32> {
+    fn from(size: Size) -> Self {
+        winit::dpi::PhysicalSize::new(size.width, size.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(
+            "1920x1080".parse::<Size>().unwrap(),
+            Size {
+                width: 1920,
+                height: 1080
+            }
+        );
+        assert_eq!("640X480".parse::<Size>().unwrap().height, 480);
+        assert!("1920".parse::<Size>().is_err());
+        assert!("0x100".parse::<Size>().is_err());
+        assert!("axb".parse::<Size>().is_err());
+    }
+}
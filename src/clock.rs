@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+/// Fixed timestep simulation clock
+///
+/// Real frame time (scaled by `time_scale`) is accumulated and paid out
+/// in whole steps of `fixed_dt`, so the simulation advances at the same
+/// rate regardless of the frame rate.
+pub struct SimulationClock {
+    pub fixed_dt: f64,
+    pub time_scale: f64,
+    // most steps we'll take in one frame, anything beyond that is dropped
+    // so that a slow frame can't snowball into ever slower frames
+    pub max_substeps: u32,
+    accumulator: f64,
+    time: f64,
+    steps: u64,
+}
+
+impl SimulationClock {
+    pub fn new(fixed_dt: f64, time_scale: f64, max_substeps: u32) -> Self {
+        Self {
+            fixed_dt,
+            time_scale,
+            max_substeps,
+            accumulator: 0.0,
+            time: 0.0,
+            steps: 0,
+        }
+    }
+
+    /// Accumulate a frame's worth of real time and return the number of
+    /// fixed steps that the simulation should take.  Each step taken is
+    /// recorded with `tick()`.
+    pub fn advance(&mut self, frame_dt: Duration) -> u32 {
+        self.accumulator += frame_dt.as_secs_f64() * self.time_scale;
+
+        let mut n = (self.accumulator / self.fixed_dt).floor() as u32;
+        if n > self.max_substeps {
+            n = self.max_substeps;
+            self.accumulator = self.fixed_dt * n as f64;
+        }
+        self.accumulator -= self.fixed_dt * n as f64;
+        n
+    }
+
+    /// Record that one fixed step was taken
+    pub fn tick(&mut self) {
+        self.time += self.fixed_dt;
+        self.steps += 1;
+    }
+
+    /// Simulated time elapsed
+    #[allow(dead_code)]
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Total number of fixed steps taken
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn speed_up(&mut self) {
+        self.time_scale *= 2.0;
+    }
+
+    pub fn slow_down(&mut self) {
+        self.time_scale *= 0.5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(clock: &mut SimulationClock, frame_hz: f64, seconds: f64) -> u32 {
+        let frame_dt = Duration::from_secs_f64(1.0 / frame_hz);
+        let frames = (seconds * frame_hz).round() as u32;
+        (0..frames).map(|_| clock.advance(frame_dt)).sum()
+    }
+
+    #[test]
+    fn steps_do_not_depend_on_frame_rate() {
+        let mut slow = SimulationClock::new(1.0 / 120.0, 1.0, 8);
+        let mut fast = SimulationClock::new(1.0 / 120.0, 1.0, 8);
+        let n_slow = run(&mut slow, 60.0, 2.0);
+        let n_fast = run(&mut fast, 144.0, 2.0);
+        assert!((n_slow as i32 - 240).abs() <= 1);
+        assert!((n_fast as i32 - 240).abs() <= 1);
+    }
+
+    #[test]
+    fn time_scale_multiplies_steps() {
+        let mut clock = SimulationClock::new(0.01, 0.5, 8);
+        let n = run(&mut clock, 50.0, 1.0);
+        assert!((n as i32 - 50).abs() <= 1);
+    }
+
+    #[test]
+    fn catch_up_is_limited() {
+        let mut clock = SimulationClock::new(0.01, 1.0, 4);
+        assert_eq!(clock.advance(Duration::from_secs(1)), 4);
+        // the backlog was dropped rather than carried over
+        assert_eq!(clock.advance(Duration::from_millis(0)), 0);
+    }
+}
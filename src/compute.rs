@@ -0,0 +1,461 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::attractors;
+use crate::integrator;
+use crate::scene;
+use crate::simulation::Simulation;
+use crate::tail_store;
+
+/// Storage buffers compute.wgsl binds besides the tail pages: particles,
+/// sphere instances and tail groups
+pub const STORAGE_BUFFERS: u32 = 3;
+// particles per workgroup, see compute.wgsl
+const WORKGROUP_SIZE: u32 = 64;
+// long runs (e.g. warming up a headless render) are split into several
+// dispatches so that no single one keeps the GPU busy for too long
+const MAX_STEPS_PER_DISPATCH: u32 = 256;
+
+// the models compute.wgsl knows, in the order of their model ids, with the
+// parameters each takes in `params`.  Past lorenz they are the continuous
+// attractors of attractors.rs, seen through their views.
+const GPU_MODELS: [(&str, &[&str]); 12] = [
+    ("lorenz", &["sigma", "rho", "beta"]),
+    ("rossler", &["a", "b", "c"]),
+    ("chen", &["a", "b", "c"]),
+    ("thomas", &["b"]),
+    ("aizawa", &["a", "b", "c", "d", "e", "f"]),
+    ("halvorsen", &["a"]),
+    ("dadras", &["a", "b", "c", "d", "e"]),
+    ("sprott", &["a", "b"]),
+    ("sprott_b", &[]),
+    ("rabinovich_fabrikant", &["alpha", "gamma"]),
+    ("chua", &["alpha", "beta"]),
+    ("four_wing", &["a", "b", "c"]),
+];
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Uniforms {
+    params: [f32; 8],
+    // the center of the view (see attractors::Viewed) and its scale
+    view: [f32; 4],
+    dt: f32,
+    first_step: u32,
+    n_steps: u32,
+    n_particles: u32,
+    model: u32,
+    method: u32,
+    speed: f32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ParticleRaw {
+    position: [f32; 4],
+    radius: f32,
+    enable_probability: f32,
+    enabled: u32,
+    rng: u32,
+}
+
+/// The uniforms compute.wgsl uses for a dynamics config (all but the step
+/// counts), or why it can't run on the GPU
+fn model_uniforms(dynamics: &scene::DynamicsConfig) -> Result<Uniforms> {
+    let name = dynamics.model.as_str();
+    let (model, param_names) = match GPU_MODELS.iter().position(|(model, _)| *model == name) {
+        Some(ix) => (ix, GPU_MODELS[ix].1),
+        None => bail!(
+            "Model '{}' can't run on the GPU, supported models are: {}",
+            name,
+            GPU_MODELS
+                .iter()
+                .map(|(model, _)| *model)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut params = [0.0; 8];
+    for (param, name) in params.iter_mut().zip(param_names.iter()) {
+        *param = dynamics.param(name)? as f32;
+    }
+    // lorenz is its own view
+    let view = match attractors::view(name) {
+        Some(view) => {
+            let [x, y, z] = view.center;
+            [
+                x as f32,
+                y as f32,
+                z as f32,
+                dynamics.param("scale")? as f32,
+            ]
+        }
+        None => [0.0, 0.0, 0.0, 1.0],
+    };
+    let method = match dynamics.integrator {
+        // the GPU models have no noise, so these are plain Euler
+        integrator::Method::Euler
+        | integrator::Method::EulerMaruyama
+        | integrator::Method::Milstein => 0,
+        integrator::Method::Midpoint => 1,
+        integrator::Method::Rk4 => 2,
+        integrator::Method::DormandPrince { .. } => {
+            bail!("The GPU backend has no adaptive integrator, use euler, midpoint or rk4")
+        }
+    };
+    Ok(Uniforms {
+        params,
+        view,
+        dt: 0.0,
+        first_step: 0,
+        n_steps: 0,
+        n_particles: 0,
+        model: model as u32,
+        method,
+        speed: dynamics.param("speed")? as f32,
+        _padding: 0,
+    })
+}
+
+/// Check that a dynamics config can run on the GPU backend
+pub fn check_supported(dynamics: &scene::DynamicsConfig) -> Result<()> {
+    model_uniforms(dynamics).map(|_| ())
+}
+
+/// Steps the particles of a `Simulation` in a compute shader
+///
+/// Particle state lives in a storage buffer on the GPU, and each step
+/// writes the sphere instances and the tail points straight into the
+/// renderer's buffers.  The `Simulation` only keeps time, and its tail
+/// store only moves the heads along, so nothing is copied back to the CPU.
+/// WGSL has no f64, so particles are stepped in f32 here and part ways
+/// with the CPU backend's sooner on chaotic models.
+pub struct ComputeSimulation {
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    uniforms: Uniforms,
+    uniform_buffer: wgpu::Buffer,
+    // the bind group refers to this
+    _particle_buffer: wgpu::Buffer,
+}
+
+impl ComputeSimulation {
+    /// `instance_buffer` and the tail buffers are the renderer's, and need
+    /// to be usable as storage buffers
+    pub fn new(
+        device: &wgpu::Device,
+        simulation: &Simulation,
+        dynamics: &scene::DynamicsConfig,
+        instance_buffer: &wgpu::Buffer,
+        tail_group_buffer: &wgpu::Buffer,
+        tail_pages: &tail_store::Pages,
+        tail_page_buffers: &[wgpu::Buffer],
+    ) -> Result<Self> {
+        let uniforms = Uniforms {
+            dt: simulation.clock.fixed_dt as f32,
+            n_particles: simulation.sphere_instances.len() as u32,
+            ..model_uniforms(dynamics)?
+        };
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Compute uniform buffer"),
+            contents: bytemuck::cast_slice(&[uniforms]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let particles = simulation
+            .sphere_instances
+            .iter()
+            .map(|s| {
+                let [x, y, z] = s.position();
+                let seed = s.chaos.seed();
+                ParticleRaw {
+                    position: [x as f32, y as f32, z as f32, 1.0],
+                    radius: s.radius,
+                    enable_probability: s.enable_probability as f32,
+                    enabled: s.enabled as u32,
+                    rng: (seed ^ (seed >> 32)) as u32,
+                }
+            })
+            .collect::<Vec<_>>();
+        let particle_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Compute particle buffer"),
+            contents: bytemuck::cast_slice(&particles),
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_SRC,
+        });
+
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        // the tail pages come after the fixed bindings
+        let first_page = STORAGE_BUFFERS + 1;
+        let mut layout_entries = vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            storage_entry(1, false),
+            storage_entry(2, false),
+            storage_entry(3, true),
+        ];
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: particle_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: instance_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: tail_group_buffer.as_entire_binding(),
+            },
+        ];
+        for (page, buffer) in tail_page_buffers.iter().enumerate() {
+            let binding = first_page + page as u32;
+            layout_entries.push(storage_entry(binding, false));
+            entries.push(wgpu::BindGroupEntry {
+                binding,
+                resource: buffer.as_entire_binding(),
+            });
+        }
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &layout_entries,
+            label: Some("compute_bind_group_layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &entries,
+            label: Some("compute_bind_group"),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Compute Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Compute Shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(
+                tail_pages
+                    .fill_in(include_str!("compute.wgsl"), 0, first_page, true)
+                    .into(),
+            ),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Compute Pipeline"),
+            layout: Some(&layout),
+            module: &module,
+            entry_point: "main",
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group,
+            uniforms,
+            uniform_buffer,
+            _particle_buffer: particle_buffer,
+        })
+    }
+
+    /// Take `n_steps` steps, the first of which is step number `first_step`
+    /// of the simulation
+    pub fn run(&self, device: &wgpu::Device, queue: &wgpu::Queue, first_step: u64, n_steps: u32) {
+        let mut uniforms = self.uniforms;
+        let n_particles = uniforms.n_particles;
+        let n_workgroups = n_particles / WORKGROUP_SIZE + (n_particles % WORKGROUP_SIZE).min(1);
+
+        let mut done = 0;
+        while done < n_steps {
+            // every dispatch needs its own uniforms, so each one is
+            // submitted on its own
+            uniforms.first_step = (first_step + done as u64) as u32;
+            uniforms.n_steps = (n_steps - done).min(MAX_STEPS_PER_DISPATCH);
+            queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Compute Encoder"),
+            });
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Compute Pass"),
+                });
+                compute_pass.set_pipeline(&self.pipeline);
+                compute_pass.set_bind_group(0, &self.bind_group, &[]);
+                compute_pass.dispatch(n_workgroups, 1, 1);
+            }
+            queue.submit(std::iter::once(encoder.finish()));
+            done += uniforms.n_steps;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::Headless;
+    use crate::sphere;
+    use futures::executor::block_on;
+
+    impl ComputeSimulation {
+        fn read_positions(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<[f32; 3]> {
+            let size = (self.uniforms.n_particles as usize * std::mem::size_of::<ParticleRaw>())
+                as wgpu::BufferAddress;
+            let staging = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Particle readback buffer"),
+                size,
+                usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Readback Encoder"),
+            });
+            encoder.copy_buffer_to_buffer(&self._particle_buffer, 0, &staging, 0, size);
+            queue.submit(std::iter::once(encoder.finish()));
+
+            let slice = staging.slice(..);
+            let mapping = slice.map_async(wgpu::MapMode::Read);
+            device.poll(wgpu::Maintain::Wait);
+            block_on(mapping).unwrap();
+            let data = slice.get_mapped_range();
+            bytemuck::cast_slice::<u8, ParticleRaw>(&data)
+                .iter()
+                .map(|p| [p.position[0], p.position[1], p.position[2]])
+                .collect()
+        }
+    }
+
+    /// Step a scene `n_steps` times in the compute shader, returns the
+    /// positions or None when there's no adapter to run it on
+    fn run_on_gpu(scene: &scene::Scene, n_steps: u32) -> Option<Vec<[f32; 3]>> {
+        let headless = match block_on(Headless::new(winit::dpi::PhysicalSize::new(64, 64))) {
+            Ok(headless) => headless,
+            Err(err) => {
+                eprintln!("Not checking the GPU backend: {:#}", err);
+                return None;
+            }
+        };
+        let device = &headless.device;
+        let mut scene = scene.clone();
+        scene.simulation.backend = scene::Backend::Gpu;
+        let simulation = Simulation::new(&mut scene).unwrap();
+
+        let instances = simulation
+            .sphere_instances
+            .iter()
+            .map(|s| s.to_raw(simulation.origin()))
+            .collect::<Vec<sphere::SphereInstanceRaw>>();
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sphere instance buffer"),
+            contents: bytemuck::cast_slice(&instances),
+            usage: wgpu::BufferUsage::STORAGE,
+        });
+        let tail_group_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tail group buffer"),
+            contents: bytemuck::cast_slice(&simulation.tails.to_raw()),
+            usage: wgpu::BufferUsage::STORAGE,
+        });
+        let pages =
+            tail_store::check_fits(&scene.groups, &device.limits(), STORAGE_BUFFERS).unwrap();
+        let page_buffers = (0..pages.count)
+            .map(|page| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Tail point buffer"),
+                    size: (pages.len(page) * tail_store::POINT_SIZE) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsage::STORAGE,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let compute = ComputeSimulation::new(
+            device,
+            &simulation,
+            &scene.dynamics,
+            &instance_buffer,
+            &tail_group_buffer,
+            &pages,
+            &page_buffers,
+        )
+        .unwrap();
+        compute.run(device, &headless.queue, 0, n_steps);
+        Some(compute.read_positions(device, &headless.queue))
+    }
+
+    #[test]
+    fn every_gpu_model_is_known_to_the_registry() {
+        for (name, params) in GPU_MODELS.iter() {
+            let model = crate::registry::get(name).unwrap();
+            for param in params.iter().chain(["speed"].iter()) {
+                model.default_value(param);
+            }
+            let dynamics = scene::DynamicsConfig {
+                model: name.to_string(),
+                ..scene::DynamicsConfig::default()
+            };
+            check_supported(&dynamics).unwrap();
+        }
+    }
+
+    #[test]
+    fn gpu_steps_match_the_cpu() {
+        for (name, _) in GPU_MODELS.iter() {
+            let mut scene = scene::Scene {
+                seed: Some(11),
+                ..scene::Scene::default()
+            };
+            scene.dynamics.model = name.to_string();
+            scene.dynamics.params.clear();
+            scene.dynamics.integrator = integrator::Method::Rk4;
+            scene.groups[0].count = 64;
+            scene.groups[0].enable_probability = 1.0;
+            // close to the middle of the view, where all of them stay put
+            scene.groups[0].spawn = scene::SpawnConfig::Cube {
+                center: [0.0, 0.0, 0.0],
+                half_width: 1.0,
+            };
+
+            let n_steps = 60;
+            let gpu = match run_on_gpu(&scene, n_steps) {
+                Some(positions) => positions,
+                None => return,
+            };
+            let mut cpu = Simulation::new(&mut scene).unwrap();
+            for _ in 0..n_steps {
+                cpu.step();
+            }
+            for (s, g) in cpu.sphere_instances.iter().zip(gpu.iter()) {
+                let c = s.position();
+                for axis in 0..3 {
+                    let difference = (c[axis] - g[axis] as f64).abs();
+                    assert!(
+                        difference < 1e-3 * (1.0 + c[axis].abs()),
+                        "{}: {:?} on the CPU, {:?} on the GPU",
+                        name,
+                        c,
+                        g
+                    );
+                }
+            }
+        }
+    }
+}
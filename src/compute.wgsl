@@ -0,0 +1,260 @@
+// Steps every particle of a scene, see compute.rs
+
+[[block]]
+struct Uniforms {
+    // model parameters, see compute.rs for their order
+    params_0: vec4<f32>;
+    params_1: vec4<f32>;
+    // the field's coordinates at the origin, and the scale (w), see
+    // attractors::Viewed
+    view: vec4<f32>;
+    dt: f32;
+    // index of the first of the `n_steps` steps to take
+    first_step: u32;
+    n_steps: u32;
+    n_particles: u32;
+    model: u32;
+    method: u32;
+    speed: f32;
+    padding: u32;
+};
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+struct Particle {
+    position: vec4<f32>;
+    radius: f32;
+    enable_probability: f32;
+    enabled: u32;
+    rng: u32;
+};
+[[block]]
+struct Particles {
+    particles: [[stride(32)]] array<Particle>;
+};
+[[group(0), binding(1)]]
+var<storage> particles: [[access(read_write)]] Particles;
+
+// sphere::SphereInstanceRaw
+struct Instance {
+    model: mat4x4<f32>;
+    color: vec4<f32>;
+    attrs: i32;
+    group: u32;
+    padding_0: u32;
+    padding_1: u32;
+};
+[[block]]
+struct Instances {
+    instances: [[stride(96)]] array<Instance>;
+};
+[[group(0), binding(2)]]
+var<storage> instances: [[access(read_write)]] Instances;
+
+// the same tail store as tail_shader.wgsl
+[[block]]
+struct TailPoints {
+    points: [[stride(4)]] array<f32>;
+};
+struct TailGroup {
+    first_particle: u32;
+    count: u32;
+    base: u32;
+    capacity: u32;
+    head: u32;
+    len: u32;
+    period: u32;
+    padding: u32;
+};
+[[block]]
+struct TailGroups {
+    groups: [[stride(32)]] array<TailGroup>;
+};
+[[group(0), binding(3)]]
+var<storage> tail_groups: [[access(read)]] TailGroups;
+// tail pages
+
+// the vector fields of dynamics.rs and attractors.rs, in their own
+// coordinates, by model id (see GPU_MODELS in compute.rs)
+fn field(p: vec3<f32>) -> vec3<f32> {
+    let a = uniforms.params_0;
+    let b = uniforms.params_1;
+    let model = uniforms.model;
+    if (model == 0u) {
+        // lorenz: sigma, rho, beta
+        return vec3<f32>(
+            a.x * (p.y - p.x),
+            p.x * (a.y - p.z) - p.y,
+            p.x * p.y - a.z * p.z,
+        );
+    }
+    if (model == 1u) {
+        // rossler: a, b, c
+        return vec3<f32>(
+            -p.y - p.z,
+            p.x + a.x * p.y,
+            a.y + p.z * (p.x - a.z),
+        );
+    }
+    if (model == 2u) {
+        // chen: a, b, c
+        return vec3<f32>(
+            a.x * (p.y - p.x),
+            (a.z - a.x) * p.x - p.x * p.z + a.z * p.y,
+            p.x * p.y - a.y * p.z,
+        );
+    }
+    if (model == 3u) {
+        // thomas: b
+        return vec3<f32>(
+            sin(p.y) - a.x * p.x,
+            sin(p.z) - a.x * p.y,
+            sin(p.x) - a.x * p.z,
+        );
+    }
+    if (model == 4u) {
+        // aizawa: a, b, c, d, e, f
+        return vec3<f32>(
+            (p.z - a.y) * p.x - a.w * p.y,
+            a.w * p.x + (p.z - a.y) * p.y,
+            a.z + a.x * p.z
+                - p.z * p.z * p.z / 3.0
+                - (p.x * p.x + p.y * p.y) * (1.0 + b.x * p.z)
+                + b.y * p.z * p.x * p.x * p.x,
+        );
+    }
+    if (model == 5u) {
+        // halvorsen: a
+        return vec3<f32>(
+            -a.x * p.x - 4.0 * p.y - 4.0 * p.z - p.y * p.y,
+            -a.x * p.y - 4.0 * p.z - 4.0 * p.x - p.z * p.z,
+            -a.x * p.z - 4.0 * p.x - 4.0 * p.y - p.x * p.x,
+        );
+    }
+    if (model == 6u) {
+        // dadras: a, b, c, d, e
+        return vec3<f32>(
+            p.y - a.x * p.x + a.y * p.y * p.z,
+            a.z * p.y - p.x * p.z + p.z,
+            a.w * p.x * p.y - b.x * p.z,
+        );
+    }
+    if (model == 7u) {
+        // sprott: a, b
+        return vec3<f32>(
+            p.y + a.x * p.x * p.y + p.x * p.z,
+            1.0 - a.y * p.x * p.x + p.y * p.z,
+            p.x - p.x * p.x - p.y * p.y,
+        );
+    }
+    if (model == 8u) {
+        // sprott_b
+        return vec3<f32>(p.y * p.z, p.x - p.y, 1.0 - p.x * p.y);
+    }
+    if (model == 9u) {
+        // rabinovich_fabrikant: alpha, gamma
+        return vec3<f32>(
+            p.y * (p.z - 1.0 + p.x * p.x) + a.y * p.x,
+            p.x * (3.0 * p.z + 1.0 - p.x * p.x) + a.y * p.y,
+            -2.0 * p.z * (a.x + p.x * p.y),
+        );
+    }
+    if (model == 10u) {
+        // chua: alpha, beta
+        let diode = p.x * p.x * p.x / 16.0 - p.x / 6.0;
+        return vec3<f32>(
+            a.x * (p.y - diode),
+            p.x - p.y + p.z,
+            -a.y * p.y,
+        );
+    }
+    // four_wing: a, b, c
+    return vec3<f32>(
+        a.x * p.x + p.y * p.z,
+        a.y * p.x + a.z * p.y - p.x * p.z,
+        -p.z - p.x * p.y,
+    );
+}
+
+fn derivative(p: vec3<f32>) -> vec3<f32> {
+    let scale = uniforms.view.w;
+    let local = uniforms.view.xyz + p / scale;
+    return uniforms.speed * scale * field(local);
+}
+
+fn advance(p: vec3<f32>, dt: f32) -> vec3<f32> {
+    if (uniforms.method == 0u) {
+        return p + dt * derivative(p);
+    } elseif (uniforms.method == 1u) {
+        let k1 = derivative(p);
+        return p + dt * derivative(p + 0.5 * dt * k1);
+    }
+    let k1 = derivative(p);
+    let k2 = derivative(p + 0.5 * dt * k1);
+    let k3 = derivative(p + 0.5 * dt * k2);
+    let k4 = derivative(p + dt * k3);
+    return p + (dt / 6.0) * (k1 + 2.0 * k2 + 2.0 * k3 + k4);
+}
+
+// PCG: an LCG state and a permutation of it as the output
+fn next_state(state: u32) -> u32 {
+    return state * 747796405u + 2891336453u;
+}
+
+fn unit_sample(state: u32) -> f32 {
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return f32(((word >> 22u) ^ word) >> 8u) / 16777216.0;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let ix = id.x;
+    if (ix >= uniforms.n_particles) {
+        return;
+    }
+
+    var particle: Particle = particles.particles[ix];
+    let group = tail_groups.groups[instances.instances[ix].group];
+    let point_ix = group.base + ix - group.first_particle;
+    var p: vec3<f32> = particle.position.xyz;
+
+    var i: u32 = 0u;
+    loop {
+        if (i >= uniforms.n_steps) {
+            break;
+        }
+
+        if (particle.enabled != 0u) {
+            p = advance(p, uniforms.dt);
+        } else {
+            // if not enabled, randomly enable
+            particle.rng = next_state(particle.rng);
+            if (unit_sample(particle.rng) < particle.enable_probability) {
+                particle.enabled = 1u;
+            }
+        }
+
+        // the same schedule as the group's sampler on the CPU
+        let k = uniforms.first_step + i;
+        if (k % group.period == 0u) {
+            let row = (k / group.period) % group.capacity;
+            store_tail_point(point_ix + row * group.count, p);
+        }
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    particle.position = vec4<f32>(p, 1.0);
+    particles.particles[ix] = particle;
+
+    let r = particle.radius;
+    instances.instances[ix].model = mat4x4<f32>(
+        vec4<f32>(r, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, r, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, r, 0.0),
+        vec4<f32>(p, 1.0),
+    );
+    instances.instances[ix].attrs = i32(particle.enabled);
+}
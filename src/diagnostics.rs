@@ -0,0 +1,438 @@
+use anyhow::{bail, Result};
+use cgmath::{InnerSpace, Vector3, Zero};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::dynamics::DynamicSystem;
+use crate::integrator::Method;
+use crate::rand_util::Chaos;
+use crate::registry::Model;
+use crate::scene;
+
+// a reference trajectory this far out is taken to be running off to
+// infinity
+const ESCAPE_RADIUS: f64 = 1e9;
+
+/// How a spectrum is measured
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub dt: f64,
+    // steps taken before measuring, to get onto the attractor
+    pub warmup_steps: u64,
+    pub steps: u64,
+    // how far the twins are kept from the reference trajectory, in world
+    // units
+    pub separation: f64,
+    // exponents closer than this to 0 count as 0
+    pub tolerance: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            dt: 1.0 / 60.0,
+            warmup_steps: 3600,
+            steps: 36_000,
+            separation: 1e-8,
+            tolerance: 0.01,
+        }
+    }
+}
+
+/// What a trajectory does in the long run, judging by its largest
+/// Lyapunov exponent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    // escaped to infinity
+    Diverging,
+    // neighbors close in on each other
+    FixedPoint,
+    // neighbors stay as far apart: a limit cycle or a torus
+    Periodic,
+    // neighbors part exponentially
+    Chaotic,
+}
+
+impl fmt::Display for Behavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Behavior::Diverging => "diverging",
+            Behavior::FixedPoint => "settles on a fixed point",
+            Behavior::Periodic => "periodic or quasi-periodic",
+            Behavior::Chaotic => "chaotic",
+        };
+        f.write_str(text)
+    }
+}
+
+/// The Lyapunov exponents of a trajectory: how fast nearby trajectories
+/// part (or close in) along each of three directions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spectrum {
+    // largest first, per unit of simulated time or, for discrete models,
+    // per iteration
+    pub exponents: [f64; 3],
+    pub per_iteration: bool,
+    // the reference trajectory escaped before the end, the exponents are
+    // from the steps before that
+    pub diverged: bool,
+    tolerance: f64,
+}
+
+impl Spectrum {
+    pub fn largest(&self) -> f64 {
+        self.exponents[0]
+    }
+
+    pub fn behavior(&self) -> Behavior {
+        let largest = self.largest();
+        // -inf is twins collapsing exactly onto the reference, a fixed point
+        if self.diverged || largest.is_nan() || largest == f64::INFINITY {
+            Behavior::Diverging
+        } else if largest > self.tolerance {
+            Behavior::Chaotic
+        } else if largest < -self.tolerance {
+            Behavior::FixedPoint
+        } else {
+            Behavior::Periodic
+        }
+    }
+
+    /// The Kaplan-Yorke estimate of the attractor's fractal dimension: 0
+    /// for a fixed point, 1 for a limit cycle, a little over 2 for Lorenz
+    pub fn kaplan_yorke_dimension(&self) -> f64 {
+        let mut sum = 0.0;
+        for (j, exponent) in self.exponents.iter().enumerate() {
+            // a limit cycle's 0 comes out a hair under
+            if sum + exponent < -self.tolerance {
+                return j as f64 + sum / exponent.abs();
+            }
+            sum += exponent;
+        }
+        self.exponents.len() as f64
+    }
+}
+
+impl fmt::Display for Spectrum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [l1, l2, l3] = self.exponents;
+        let unit = if self.per_iteration {
+            "iteration"
+        } else {
+            "unit time"
+        };
+        write!(
+            f,
+            "Lyapunov exponents {:.3}, {:.3}, {:.3} per {}: {}",
+            l1,
+            l2,
+            l3,
+            unit,
+            self.behavior()
+        )?;
+        if self.behavior() != Behavior::Diverging {
+            write!(
+                f,
+                " (Kaplan-Yorke dimension {:.2})",
+                self.kaplan_yorke_dimension()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measure the Lyapunov spectrum of a model from `start`
+///
+/// Three twins follow the reference trajectory `separation` away from it.
+/// After every step their offsets are orthonormalized (Gram-Schmidt), the
+/// logs of how much each one grew are summed up, and the twins are put
+/// back `separation` away along the new directions.  The twins get the
+/// same random start and noise as the reference, so for stochastic models
+/// this is the spectrum for one realization of the noise.
+///
+/// Particles need to be movable (see `DynamicSystem::set_position`), and
+/// only their positions are compared: for models with more state than a
+/// position, like charged particles or scripts that keep a velocity, this
+/// is rough.  Interacting models (`nbody`, `boids`) are not supported,
+/// their particles don't have trajectories of their own.
+pub fn spectrum(
+    model: &Model,
+    params: &BTreeMap<String, f64>,
+    method: Method,
+    start: Vector3<f64>,
+    settings: &Settings,
+    chaos: &mut Chaos,
+) -> Result<Spectrum> {
+    if model.is_interacting() {
+        bail!(
+            "{} moves all of its particles together, Lyapunov exponents are only measured for particles that move on their own",
+            model.name
+        );
+    }
+    let chaos = chaos.fork();
+    let mut particles = (0..4)
+        .map(|_| {
+            let mut chaos = chaos.clone();
+            let system = model.build(params, start, method, &mut chaos);
+            (system, chaos)
+        })
+        .collect::<Vec<_>>();
+    let step = |particles: &mut Vec<(Box<dyn DynamicSystem>, Chaos)>| {
+        for (system, chaos) in particles.iter_mut() {
+            system.step(settings.dt, chaos);
+        }
+        let position = particles[0].0.get_position();
+        position.magnitude() < ESCAPE_RADIUS
+    };
+
+    // all four are the same trajectory until the twins are moved
+    let mut diverged = false;
+    for _ in 0..settings.warmup_steps {
+        if !step(&mut particles) {
+            diverged = true;
+            break;
+        }
+    }
+
+    let eps = settings.separation;
+    let mut directions = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+    let reference = particles[0].0.get_position();
+    for ((twin, _), direction) in particles[1..].iter_mut().zip(directions.iter()) {
+        let target = reference + eps * direction;
+        twin.set_position(target);
+        if (twin.get_position() - target).magnitude() > 1e-3 * eps {
+            bail!(
+                "{} particles can't be moved, so there are no twins to measure Lyapunov exponents with",
+                model.name
+            );
+        }
+    }
+
+    let mut sums = [0.0; 3];
+    let mut measured = 0;
+    while !diverged && measured < settings.steps {
+        if !step(&mut particles) {
+            diverged = true;
+            break;
+        }
+        measured += 1;
+        let reference = particles[0].0.get_position();
+        for i in 0..3 {
+            let mut offset = (particles[i + 1].0.get_position() - reference) / eps;
+            for previous in directions[..i].iter() {
+                offset -= offset.dot(*previous) * previous;
+            }
+            let growth = offset.magnitude();
+            sums[i] += growth.ln();
+            directions[i] = if growth > 0.0 {
+                offset / growth
+            } else {
+                // collapsed onto the others, any direction will do
+                orthogonal_unit(&directions[..i])
+            };
+            particles[i + 1]
+                .0
+                .set_position(reference + eps * directions[i]);
+        }
+    }
+
+    let duration = if model.discrete {
+        measured as f64
+    } else {
+        measured as f64 * settings.dt
+    };
+    // Gram-Schmidt sorts them, except when a twin starts out in a subspace
+    // it never leaves
+    let mut exponents = [sums[0] / duration, sums[1] / duration, sums[2] / duration];
+    exponents.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(Spectrum {
+        exponents,
+        per_iteration: model.discrete,
+        diverged,
+        tolerance: settings.tolerance,
+    })
+}
+
+/// The spectrum of a scene's model at its time step, from a point of its
+/// first group
+pub fn scene_spectrum(scene: &scene::Scene) -> Result<Spectrum> {
+    let mut chaos = match scene.seed {
+        Some(seed) => Chaos::from_seed(seed),
+        None => Chaos::new(),
+    };
+    let start = scene.groups[0].spawn.sample(&mut chaos);
+    let settings = Settings {
+        dt: scene.simulation.fixed_dt,
+        ..Settings::default()
+    };
+    spectrum(
+        &*scene.dynamics.model()?,
+        &scene.dynamics.params,
+        scene.dynamics.integrator,
+        start,
+        &settings,
+        &mut chaos,
+    )
+}
+
+/// A unit vector orthogonal to the orthonormal `previous`
+fn orthogonal_unit(previous: &[Vector3<f64>]) -> Vector3<f64> {
+    let mut best = Vector3::zero();
+    for axis in [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()].iter() {
+        let mut candidate = *axis;
+        for p in previous {
+            candidate -= candidate.dot(*p) * p;
+        }
+        if candidate.magnitude2() > best.magnitude2() {
+            best = candidate;
+        }
+    }
+    best.normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expression, registry};
+
+    fn params(values: &[(&str, f64)]) -> BTreeMap<String, f64> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
+
+    fn measure(model: &Model, values: &[(&str, f64)], settings: &Settings) -> Spectrum {
+        let mut chaos = Chaos::from_seed(3);
+        spectrum(
+            model,
+            &params(values),
+            Method::Rk4,
+            Vector3::new(1.0, 1.0, 1.0),
+            settings,
+            &mut chaos,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn classic_lorenz_is_chaotic() {
+        let lorenz = registry::get("lorenz").unwrap();
+        let settings = Settings {
+            dt: 0.01,
+            warmup_steps: 1000,
+            steps: 100_000,
+            ..Settings::default()
+        };
+        let values = [
+            ("sigma", 10.0),
+            ("rho", 28.0),
+            ("beta", 8.0 / 3.0),
+            ("speed", 1.0),
+        ];
+        let spectrum = measure(&lorenz, &values, &settings);
+        let [l1, l2, l3] = spectrum.exponents;
+        // 0.906, 0 and -14.57
+        assert!((l1 - 0.906).abs() < 0.05, "{}", spectrum);
+        assert!(l2.abs() < 0.02, "{}", spectrum);
+        // they add up to the divergence of the flow, -(sigma + 1 + beta)
+        assert!((l1 + l2 + l3 + 13.667).abs() < 0.05, "{}", spectrum);
+        assert_eq!(spectrum.behavior(), Behavior::Chaotic);
+        assert!((spectrum.kaplan_yorke_dimension() - 2.06).abs() < 0.01);
+
+        // the default parameters spiral into one of the two fixed points
+        let spectrum = measure(&lorenz, &[], &Settings::default());
+        assert_eq!(spectrum.behavior(), Behavior::FixedPoint, "{}", spectrum);
+    }
+
+    #[test]
+    fn limit_cycles_are_periodic() {
+        // the Hopf normal form, with a stable circle of radius 1 in the XY
+        // plane: exponents 0, -1 and -2
+        let equations = expression::Equations::compile(
+            "x - y - x * (x^2 + y^2)",
+            "x + y - y * (x^2 + y^2)",
+            "-z",
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        let model = expression::model(equations, &BTreeMap::new());
+        let spectrum = measure(&model, &[], &Settings::default());
+        assert_eq!(spectrum.behavior(), Behavior::Periodic, "{}", spectrum);
+        let [_, l2, l3] = spectrum.exponents;
+        assert!(
+            (l2 + 1.0).abs() < 0.01 && (l3 + 2.0).abs() < 0.01,
+            "{}",
+            spectrum
+        );
+        assert!((spectrum.kaplan_yorke_dimension() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn henon_is_chaotic_per_iteration() {
+        let henon = registry::get("polynomial_map").unwrap();
+        let mut chaos = Chaos::from_seed(5);
+        let spectrum = spectrum(
+            &henon,
+            &BTreeMap::new(),
+            Method::Rk4,
+            Vector3::zero(),
+            &Settings::default(),
+            &mut chaos,
+        )
+        .unwrap();
+        assert!(spectrum.per_iteration);
+        assert!((spectrum.largest() - 0.419).abs() < 0.01, "{}", spectrum);
+        assert_eq!(spectrum.behavior(), Behavior::Chaotic);
+    }
+
+    #[test]
+    fn collapsed_twins_are_a_fixed_point() {
+        let with_largest = |largest: f64| Spectrum {
+            exponents: [largest, f64::NEG_INFINITY, f64::NEG_INFINITY],
+            per_iteration: true,
+            diverged: false,
+            tolerance: 0.01,
+        };
+        let collapsed = with_largest(f64::NEG_INFINITY);
+        assert_eq!(collapsed.behavior(), Behavior::FixedPoint);
+        assert_eq!(collapsed.kaplan_yorke_dimension(), 0.0);
+        assert_eq!(with_largest(f64::INFINITY).behavior(), Behavior::Diverging);
+        assert_eq!(with_largest(f64::NAN).behavior(), Behavior::Diverging);
+    }
+
+    #[test]
+    fn moving_particles_are_measured() {
+        let mut chaos = Chaos::from_seed(2);
+        for name in ["charged_uniform", "circler"].iter() {
+            let model = registry::get(name).unwrap();
+            let result = spectrum(
+                &model,
+                &BTreeMap::new(),
+                Method::Rk4,
+                Vector3::new(1.0, 0.0, 0.0),
+                &Settings::default(),
+                &mut chaos,
+            );
+            let spectrum = result.unwrap();
+            assert_ne!(spectrum.behavior(), Behavior::Diverging, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unsupported_models_are_rejected() {
+        let mut chaos = Chaos::from_seed(1);
+        for name in ["nbody", "boids"].iter() {
+            let model = registry::get(name).unwrap();
+            let result = spectrum(
+                &model,
+                &BTreeMap::new(),
+                Method::Rk4,
+                Vector3::zero(),
+                &Settings::default(),
+                &mut chaos,
+            );
+            assert!(result.is_err(), "{}", name);
+        }
+    }
+}
@@ -1,77 +1,141 @@
+use crate::integrator::{Integrator, Method, VectorField};
+use crate::interaction::Body;
 use crate::rand_util::Chaos;
 
-pub trait DynamicSystem {
-    fn step(&mut self, chaos: &mut Chaos);
-    fn get_position(&self) -> cgmath::Vector3<f32>;
+/// Particles are stepped and read in parallel, each one on whichever
+/// thread picks it up
+pub trait DynamicSystem: Send + Sync {
+    fn step(&mut self, dt: f64, chaos: &mut Chaos);
+    fn get_position(&self) -> cgmath::Vector3<f64>;
+
+    /// The state of a particle of an interacting model, which the
+    /// simulation steps together with all the others (see `interaction`)
+    fn body_mut(&mut self) -> Option<&mut Body> {
+        None
+    }
+
+    /// Move the particle somewhere else, e.g. when a script respawns it.
+    /// Systems that can't be moved ignore this.
+    fn set_position(&mut self, _position: cgmath::Vector3<f64>) {}
+}
+
+/// A deterministic system integrated from a vector field
+pub struct Flow<F: VectorField> {
+    pub field: F,
+    pub integrator: Integrator,
+    pub position: cgmath::Vector3<f64>,
+    pub t: f64,
+}
+
+impl<F: VectorField> Flow<F> {
+    pub fn new(field: F, position: cgmath::Vector3<f64>, method: Method) -> Self {
+        Self {
+            field,
+            integrator: Integrator::new(method),
+            position,
+            t: 0.0,
+        }
+    }
+}
+
+impl<F: VectorField> DynamicSystem for Flow<F> {
+    fn step(&mut self, dt: f64, _chaos: &mut Chaos) {
+        self.position = self
+            .integrator
+            .advance(&self.field, self.position, self.t, dt);
+        self.t += dt;
+    }
+
+    fn get_position(&self) -> cgmath::Vector3<f64> {
+        self.position
+    }
+
+    fn set_position(&mut self, position: cgmath::Vector3<f64>) {
+        self.position = position;
+    }
 }
 
 pub struct Circler {
-    pub heading: f32,
-    pub omega: f32,
-    pub speed: f32,
-    pub position: cgmath::Vector3<f32>,
+    pub heading: f64,
+    pub omega: f64,
+    pub speed: f64,
+    pub position: cgmath::Vector3<f64>,
 }
 
 impl Circler {
-    #[allow(dead_code)]
-    pub fn new(mean_speed: f32, mean_omega: f32, lims: f32, chaos: &mut Chaos) -> Self {
+    pub fn new(
+        mean_speed: f64,
+        mean_omega: f64,
+        position: cgmath::Vector3<f64>,
+        chaos: &mut Chaos,
+    ) -> Self {
         Self {
             heading: chaos.unit_radian_noise(),
             omega: mean_omega + 0.1 * mean_omega * chaos.unit_noise(),
             speed: mean_speed + 0.1 * mean_speed * chaos.unit_noise(),
-            position: chaos.random_position_in_cube(lims),
+            position,
         }
     }
 }
 
+// Circler's parameters are amounts per step at this step size, from
+// before it took dt into account
+const CIRCLER_DT: f64 = 1.0 / 60.0;
+// standard deviation of the uniform noise it used to add, see `diffusion`
+const UNIFORM_STD: f64 = 0.288_675_134_594_812_9;
+
 impl DynamicSystem for Circler {
-    fn step(&mut self, chaos: &mut Chaos) {
+    fn step(&mut self, dt: f64, chaos: &mut Chaos) {
+        let steps = dt / CIRCLER_DT;
+        // Gaussian kicks that spread as far per CIRCLER_DT as the old uniform
+        // noise did per step, growing with sqrt(dt) like a Wiener process
+        let diffusion = |amplitude: f64| amplitude * UNIFORM_STD * steps.sqrt();
         let vx = self.speed * self.heading.cos();
         let vy = self.speed * self.heading.sin();
 
-        self.position.x += vx + 0.005 * chaos.unit_noise();
-        self.position.y += vy + 0.005 * chaos.unit_noise();
-        self.position.z += -0.001 * self.position.z + 0.01 * chaos.unit_noise();
-        self.heading += self.omega + 0.05 * chaos.unit_noise();
+        self.position.x += steps * vx + diffusion(0.005) * chaos.gaussian();
+        self.position.y += steps * vy + diffusion(0.005) * chaos.gaussian();
+        self.position.z += -0.001 * steps * self.position.z + diffusion(0.01) * chaos.gaussian();
+        self.heading += steps * self.omega + diffusion(0.05) * chaos.gaussian();
     }
 
-    fn get_position(&self) -> cgmath::Vector3<f32> {
+    fn get_position(&self) -> cgmath::Vector3<f64> {
         self.position
     }
+
+    fn set_position(&mut self, position: cgmath::Vector3<f64>) {
+        self.position = position;
+    }
 }
 
 pub struct Lorenz {
-    pub sigma: f32,
-    pub rho: f32,
-    pub beta: f32,
-    pub speed: f32,
-    pub position: cgmath::Vector3<f32>,
+    pub sigma: f64,
+    pub rho: f64,
+    pub beta: f64,
+    pub speed: f64,
 }
 
 impl Lorenz {
-    pub fn new(sigma: f32, rho: f32, beta: f32, speed: f32, lims: f32, chaos: &mut Chaos) -> Self {
+    pub fn new(sigma: f64, rho: f64, beta: f64, speed: f64) -> Self {
         Self {
             sigma,
             rho,
             beta,
             speed,
-            position: chaos.random_position_in_cube(lims),
         }
     }
 }
 
-impl DynamicSystem for Lorenz {
-    fn step(&mut self, _chaos: &mut Chaos) {
-        let dt = 0.016666;
-        let px = self.position.x;
-        let py = self.position.y;
-        let pz = self.position.z;
-        self.position.x += dt * self.speed * (self.sigma * (py - px));
-        self.position.y += dt * self.speed * (px * (self.rho - pz) - py);
-        self.position.z += dt * self.speed * (px * py - self.beta * pz);
-    }
-
-    fn get_position(&self) -> cgmath::Vector3<f32> {
-        self.position
+impl VectorField for Lorenz {
+    fn derivative(&self, state: cgmath::Vector3<f64>, _t: f64) -> cgmath::Vector3<f64> {
+        let px = state.x;
+        let py = state.y;
+        let pz = state.z;
+        self.speed
+            * cgmath::Vector3::new(
+                self.sigma * (py - px),
+                px * (self.rho - pz) - py,
+                px * py - self.beta * pz,
+            )
     }
 }
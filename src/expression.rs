@@ -0,0 +1,656 @@
+use anyhow::*;
+use cgmath::Vector3;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::dynamics::Flow;
+use crate::integrator::VectorField;
+use crate::registry::Model;
+
+// the variables every expression can use, in the order `eval` takes them
+const VARIABLES: [&str; 4] = ["x", "y", "z", "t"];
+// evaluation uses a fixed size stack, deeper expressions are rejected
+const MAX_DEPTH: usize = 64;
+
+// a parameter with one of these names would hide the variable or constant
+fn is_reserved(name: &str) -> bool {
+    VARIABLES.contains(&name) || name == "pi"
+}
+
+fn reserved_message(name: &str) -> String {
+    format!(
+        "parameter '{}' has the name of a variable or constant, rename it",
+        name
+    )
+}
+
+type Function1 = fn(f64) -> f64;
+type Function2 = fn(f64, f64) -> f64;
+
+const FUNCTIONS_1: [(&str, Function1); 14] = [
+    ("sin", f64::sin),
+    ("cos", f64::cos),
+    ("tan", f64::tan),
+    ("asin", f64::asin),
+    ("acos", f64::acos),
+    ("atan", f64::atan),
+    ("sinh", f64::sinh),
+    ("cosh", f64::cosh),
+    ("tanh", f64::tanh),
+    ("exp", f64::exp),
+    ("ln", f64::ln),
+    ("sqrt", f64::sqrt),
+    ("abs", f64::abs),
+    ("sign", sign),
+];
+
+const FUNCTIONS_2: [(&str, Function2); 4] = [
+    ("atan2", f64::atan2),
+    ("min", f64::min),
+    ("max", f64::max),
+    ("pow", f64::powf),
+];
+
+// unlike f64::signum, 0 for 0
+fn sign(x: f64) -> f64 {
+    if x == 0.0 {
+        0.0
+    } else {
+        x.signum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(f64),
+    Name,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    Open,
+    Close,
+    Comma,
+}
+
+/// A token and where it starts, as a 1-based column
+#[derive(Debug, Clone, Copy)]
+struct Spanned {
+    token: Token,
+    column: usize,
+    // byte range in the source, for names
+    start: usize,
+    end: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Spanned>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().enumerate().peekable();
+    while let Some((ix, (start, c))) = chars.next() {
+        let column = ix + 1;
+        let single = match c {
+            '+' => Some(Token::Plus),
+            '-' => Some(Token::Minus),
+            '*' => Some(Token::Star),
+            '/' => Some(Token::Slash),
+            '^' => Some(Token::Caret),
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            ',' => Some(Token::Comma),
+            _ => None,
+        };
+        if let Some(token) = single {
+            tokens.push(Spanned {
+                token,
+                column,
+                start,
+                end: start + 1,
+            });
+            continue;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut end = start + c.len_utf8();
+        if c.is_ascii_digit() || c == '.' {
+            // digits and a fraction, then maybe an exponent like 1e-3
+            let mut last = c;
+            while let Some(&(_, (ix, next))) = chars.peek() {
+                let exponent_sign = (next == '+' || next == '-') && (last == 'e' || last == 'E');
+                if next.is_ascii_digit()
+                    || next == '.'
+                    || next == 'e'
+                    || next == 'E'
+                    || exponent_sign
+                {
+                    end = ix + next.len_utf8();
+                    last = next;
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let text = &source[start..end];
+            let value = match text.parse::<f64>() {
+                Ok(value) => value,
+                Err(_) => bail!("Column {}: '{}' is not a number", column, text),
+            };
+            tokens.push(Spanned {
+                token: Token::Number(value),
+                column,
+                start,
+                end,
+            });
+        } else if c.is_alphabetic() || c == '_' {
+            while let Some(&(_, (ix, next))) = chars.peek() {
+                if next.is_alphanumeric() || next == '_' {
+                    end = ix + next.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Spanned {
+                token: Token::Name,
+                column,
+                start,
+                end,
+            });
+        } else {
+            bail!("Column {}: unexpected '{}'", column, c);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Instructions for a stack machine, an expression compiles to a list of
+/// them in postfix order
+#[derive(Clone, Copy)]
+enum Op {
+    Constant(f64),
+    // one of `VARIABLES`
+    Variable(usize),
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    // powers with a small whole exponent are multiplied out
+    PowerInt(i32),
+    Call1(Function1),
+    Call2(Function2),
+}
+
+impl Op {
+    // how the stack height changes
+    fn effect(&self) -> isize {
+        match self {
+            Op::Constant(_) | Op::Variable(_) => 1,
+            Op::Negate | Op::PowerInt(_) | Op::Call1(_) => 0,
+            _ => -1,
+        }
+    }
+}
+
+/// Recursive descent over the tokens, emitting ops as it goes:
+///
+/// ```text
+/// sum     = product (('+' | '-') product)*
+/// product = unary (('*' | '/') unary)*
+/// unary   = '-' unary | power
+/// power   = atom ('^' unary)?
+/// atom    = number | name | name '(' sum (',' sum)* ')' | '(' sum ')'
+/// ```
+///
+/// So powers bind tightest and go right to left, `-x^2` is `-(x^2)`.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Spanned>,
+    next: usize,
+    params: &'a BTreeMap<String, f64>,
+    ops: Vec<Op>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.next).map(|s| s.token)
+    }
+
+    // where the next token starts, or just past the end
+    fn column(&self) -> usize {
+        match self.tokens.get(self.next) {
+            Some(spanned) => spanned.column,
+            None => self.source.chars().count() + 1,
+        }
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<()> {
+        if self.peek() == Some(token) {
+            self.next += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected {}", what))
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        let found = match self.tokens.get(self.next) {
+            Some(s) => format!("'{}'", &self.source[s.start..s.end]),
+            None => "the end".to_string(),
+        };
+        bail!("Column {}: {}, found {}", self.column(), message, found)
+    }
+
+    fn sum(&mut self) -> Result<()> {
+        self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => Op::Add,
+                Some(Token::Minus) => Op::Subtract,
+                _ => return Ok(()),
+            };
+            self.next += 1;
+            self.product()?;
+            self.emit(op);
+        }
+    }
+
+    fn product(&mut self) -> Result<()> {
+        self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => Op::Multiply,
+                Some(Token::Slash) => Op::Divide,
+                _ => return Ok(()),
+            };
+            self.next += 1;
+            self.unary()?;
+            self.emit(op);
+        }
+    }
+
+    fn unary(&mut self) -> Result<()> {
+        if self.peek() == Some(Token::Minus) {
+            self.next += 1;
+            self.unary()?;
+            self.emit(Op::Negate);
+            Ok(())
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<()> {
+        self.atom()?;
+        if self.peek() == Some(Token::Caret) {
+            self.next += 1;
+            self.unary()?;
+            self.emit(Op::Power);
+        }
+        Ok(())
+    }
+
+    fn atom(&mut self) -> Result<()> {
+        let spanned = match self.tokens.get(self.next) {
+            Some(spanned) => *spanned,
+            None => return self.error("expected a number, a name or '('"),
+        };
+        match spanned.token {
+            Token::Number(value) => {
+                self.next += 1;
+                self.emit(Op::Constant(value));
+            }
+            Token::Open => {
+                self.next += 1;
+                self.sum()?;
+                self.expect(Token::Close, "')'")?;
+            }
+            Token::Name => {
+                self.next += 1;
+                let name = &self.source[spanned.start..spanned.end];
+                if self.peek() == Some(Token::Open) {
+                    self.call(name, spanned.column)?;
+                } else {
+                    self.name(name, spanned.column)?;
+                }
+            }
+            _ => return self.error("expected a number, a name or '('"),
+        }
+        Ok(())
+    }
+
+    fn name(&mut self, name: &str, column: usize) -> Result<()> {
+        if is_reserved(name) && self.params.contains_key(name) {
+            bail!("Column {}: {}", column, reserved_message(name));
+        }
+        // parameters are known now, so they go in as constants
+        if let Some(value) = self.params.get(name) {
+            self.emit(Op::Constant(*value));
+        } else if let Some(ix) = VARIABLES.iter().position(|v| *v == name) {
+            self.emit(Op::Variable(ix));
+        } else if name == "pi" {
+            self.emit(Op::Constant(std::f64::consts::PI));
+        } else {
+            let mut known = VARIABLES.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+            known.extend(self.params.keys().cloned());
+            known.push("pi".to_string());
+            bail!(
+                "Column {}: unknown name '{}', expected one of: {}",
+                column,
+                name,
+                known.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, column: usize) -> Result<()> {
+        self.expect(Token::Open, "'('")?;
+        let mut n_args = 1;
+        self.sum()?;
+        while self.peek() == Some(Token::Comma) {
+            self.next += 1;
+            self.sum()?;
+            n_args += 1;
+        }
+        self.expect(Token::Close, "')'")?;
+
+        let one = FUNCTIONS_1.iter().find(|(n, _)| *n == name);
+        let two = FUNCTIONS_2.iter().find(|(n, _)| *n == name);
+        match (one, two, n_args) {
+            (Some((_, f)), _, 1) => self.emit(Op::Call1(*f)),
+            (_, Some((_, f)), 2) => self.emit(Op::Call2(*f)),
+            (Some(_), _, _) => bail!(
+                "Column {}: {} takes 1 argument, got {}",
+                column,
+                name,
+                n_args
+            ),
+            (_, Some(_), _) => bail!(
+                "Column {}: {} takes 2 arguments, got {}",
+                column,
+                name,
+                n_args
+            ),
+            _ => bail!(
+                "Column {}: unknown function '{}', expected one of: {}",
+                column,
+                name,
+                FUNCTIONS_1
+                    .iter()
+                    .map(|(n, _)| *n)
+                    .chain(FUNCTIONS_2.iter().map(|(n, _)| *n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    /// Push an op, working out anything that only involves constants
+    fn emit(&mut self, op: Op) {
+        let n = self.ops.len();
+        let folded = match (op, &self.ops[n.saturating_sub(2)..]) {
+            (Op::Negate, [.., Op::Constant(a)]) => Some((1, -a)),
+            (Op::Call1(f), [.., Op::Constant(a)]) => Some((1, f(*a))),
+            (Op::Add, [Op::Constant(a), Op::Constant(b)]) => Some((2, a + b)),
+            (Op::Subtract, [Op::Constant(a), Op::Constant(b)]) => Some((2, a - b)),
+            (Op::Multiply, [Op::Constant(a), Op::Constant(b)]) => Some((2, a * b)),
+            (Op::Divide, [Op::Constant(a), Op::Constant(b)]) => Some((2, a / b)),
+            (Op::Power, [Op::Constant(a), Op::Constant(b)]) => Some((2, a.powf(*b))),
+            (Op::Call2(f), [Op::Constant(a), Op::Constant(b)]) => Some((2, f(*a, *b))),
+            (Op::Power, [.., Op::Constant(b)]) if b.fract() == 0.0 && b.abs() <= 16.0 => {
+                self.ops[n - 1] = Op::PowerInt(*b as i32);
+                return;
+            }
+            _ => None,
+        };
+        match folded {
+            Some((n_args, value)) => {
+                self.ops.truncate(n - n_args);
+                self.ops.push(Op::Constant(value));
+            }
+            None => self.ops.push(op),
+        }
+    }
+}
+
+/// A math expression over x, y, z, t and named parameters, compiled for
+/// evaluating over and over
+///
+/// Supports `+ - * / ^`, parentheses, `pi` and the usual functions (`sin`,
+/// `exp`, `sqrt`, `atan2`, `min`, ...).  Parameter values are baked in
+/// when compiling, so changing one means compiling again.
+pub struct Expression {
+    ops: Vec<Op>,
+}
+
+impl Expression {
+    /// Parse and compile, errors give the column of the problem
+    pub fn compile(source: &str, params: &BTreeMap<String, f64>) -> Result<Self> {
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            next: 0,
+            params,
+            ops: Vec::new(),
+        };
+        parser.sum()?;
+        if parser.next < parser.tokens.len() {
+            return parser.error("expected an operator");
+        }
+        // the ones the expression uses were reported with their column
+        if let Some(name) = params.keys().find(|name| is_reserved(name)) {
+            bail!("{}", reserved_message(name));
+        }
+
+        let mut depth: isize = 0;
+        for op in parser.ops.iter() {
+            depth += op.effect();
+            if depth as usize > MAX_DEPTH {
+                bail!("Expression is nested too deeply");
+            }
+        }
+        Ok(Self { ops: parser.ops })
+    }
+
+    /// The value at a point in space and time
+    pub fn eval(&self, p: Vector3<f64>, t: f64) -> f64 {
+        let variables = [p.x, p.y, p.z, t];
+        let mut stack = [0.0; MAX_DEPTH];
+        let mut top = 0;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Constant(value) => {
+                    stack[top] = value;
+                    top += 1;
+                }
+                Op::Variable(ix) => {
+                    stack[top] = variables[ix];
+                    top += 1;
+                }
+                Op::Negate => stack[top - 1] = -stack[top - 1],
+                Op::PowerInt(n) => stack[top - 1] = stack[top - 1].powi(n),
+                Op::Call1(f) => stack[top - 1] = f(stack[top - 1]),
+                _ => {
+                    top -= 1;
+                    let (a, b) = (stack[top - 1], stack[top]);
+                    stack[top - 1] = match *op {
+                        Op::Add => a + b,
+                        Op::Subtract => a - b,
+                        Op::Multiply => a * b,
+                        Op::Divide => a / b,
+                        Op::Power => a.powf(b),
+                        Op::Call2(f) => f(a, b),
+                        _ => unreachable!(),
+                    };
+                }
+            }
+        }
+        stack[0]
+    }
+}
+
+/// A vector field given by one expression per coordinate
+pub struct Equations {
+    pub dx: Expression,
+    pub dy: Expression,
+    pub dz: Expression,
+}
+
+impl Equations {
+    /// Compile the right hand sides of dx/dt, dy/dt and dz/dt
+    pub fn compile(dx: &str, dy: &str, dz: &str, params: &BTreeMap<String, f64>) -> Result<Self> {
+        let compile = |name: &str, source: &str| {
+            Expression::compile(source, params)
+                .with_context(|| format!("In {} = \"{}\"", name, source))
+        };
+        Ok(Self {
+            dx: compile("dx", dx)?,
+            dy: compile("dy", dy)?,
+            dz: compile("dz", dz)?,
+        })
+    }
+}
+
+impl VectorField for Equations {
+    fn derivative(&self, state: Vector3<f64>, t: f64) -> Vector3<f64> {
+        Vector3::new(
+            self.dx.eval(state, t),
+            self.dy.eval(state, t),
+            self.dz.eval(state, t),
+        )
+    }
+}
+
+impl VectorField for Arc<Equations> {
+    fn derivative(&self, state: Vector3<f64>, t: f64) -> Vector3<f64> {
+        self.as_ref().derivative(state, t)
+    }
+}
+
+/// A model for a scene's own equations, not in the registry since it only
+/// exists once the scene is loaded.  Its parameters are the scene's, which
+/// are also their defaults.
+pub fn model(equations: Equations, params: &BTreeMap<String, f64>) -> Model {
+    let equations = Arc::new(equations);
+    let mut model = Model::new(
+        "equations",
+        "The scene's own equations",
+        move |_params, position, method, _chaos| {
+            Box::new(Flow::new(equations.clone(), position, method))
+        },
+    );
+    for (name, value) in params.iter() {
+        model = model.param(name, *value, f64::MIN..=f64::MAX);
+    }
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> f64 {
+        let mut params = BTreeMap::new();
+        params.insert("a".to_string(), 2.0);
+        Expression::compile(source, &params)
+            .unwrap()
+            .eval(Vector3::new(1.0, 2.0, 3.0), 0.5)
+    }
+
+    fn error(source: &str) -> String {
+        match Expression::compile(source, &BTreeMap::new()) {
+            Ok(_) => panic!("'{}' compiled", source),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn operators_have_the_usual_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("-2 ^ 2"), -4.0);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+        assert_eq!(eval("8 / 4 / 2"), 1.0);
+        assert_eq!(eval("1 - 2 - 3"), -4.0);
+        assert_eq!(eval("x - -y"), 3.0);
+    }
+
+    #[test]
+    fn variables_params_and_functions() {
+        assert_eq!(eval("x + y * z + t"), 7.5);
+        assert_eq!(eval("a * x"), 2.0);
+        assert_eq!(eval("max(x, min(y, z)) + abs(-a)"), 4.0);
+        assert_eq!(eval("z ^ a"), 9.0);
+        assert_eq!(eval("z ^ t"), 3.0f64.sqrt());
+        assert!((eval("sin(pi / 2) + exp(0) + sqrt(4e0) + 1.5e-1") - 4.15).abs() < 1e-6);
+        assert!((eval("atan2(y, x)") - 2.0f64.atan2(1.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn errors_point_at_the_column() {
+        assert_eq!(error("x + (y"), "Column 7: expected ')', found the end");
+        assert_eq!(
+            error("x + * y"),
+            "Column 5: expected a number, a name or '(', found '*'"
+        );
+        assert_eq!(error("x y"), "Column 3: expected an operator, found 'y'");
+        assert_eq!(error("2 $ x"), "Column 3: unexpected '$'");
+        assert!(error("x + sigma").starts_with("Column 5: unknown name 'sigma'"));
+        assert!(error("1 + sinn(x)").starts_with("Column 5: unknown function 'sinn'"));
+        assert_eq!(error("min(x)"), "Column 1: min takes 2 arguments, got 1");
+        assert_eq!(error("1.2.3"), "Column 1: '1.2.3' is not a number");
+    }
+
+    #[test]
+    fn params_can_not_hide_variables() {
+        for name in ["x", "t", "pi"].iter() {
+            let mut params = BTreeMap::new();
+            params.insert(name.to_string(), 1.0);
+            let err = match Expression::compile(&format!("2 * {}", name), &params) {
+                Ok(_) => panic!("a parameter named {} compiled", name),
+                Err(err) => err.to_string(),
+            };
+            assert_eq!(
+                err,
+                format!(
+                    "Column 5: parameter '{}' has the name of a variable or constant, rename it",
+                    name
+                )
+            );
+            // even when the expression doesn't use it
+            assert!(Expression::compile("y", &params).is_err());
+        }
+    }
+
+    #[test]
+    fn lorenz_from_equations_matches_the_builtin() {
+        let mut params = BTreeMap::new();
+        for (name, value) in [
+            ("sigma", 10.0),
+            ("rho", 28.0),
+            ("beta", 8.0 / 3.0),
+            ("speed", 0.1),
+        ]
+        .iter()
+        {
+            params.insert(name.to_string(), *value);
+        }
+        let equations = Equations::compile(
+            "speed * sigma * (y - x)",
+            "speed * (x * (rho - z) - y)",
+            "speed * (x * y - beta * z)",
+            &params,
+        )
+        .unwrap();
+        let lorenz = crate::dynamics::Lorenz::new(10.0, 28.0, 8.0 / 3.0, 0.1);
+        let p = Vector3::new(1.0, -2.0, 20.0);
+        let difference = equations.derivative(p, 0.0) - lorenz.derivative(p, 0.0);
+        assert!(difference.x.abs() + difference.y.abs() + difference.z.abs() < 1e-5);
+    }
+}
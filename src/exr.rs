@@ -0,0 +1,153 @@
+use anyhow::*;
+use std::io::Write;
+
+// pixel type of a channel, 16 bit floats
+const HALF: i32 = 1;
+
+fn attribute<W: Write>(out: &mut W, name: &str, kind: &str, value: &[u8]) -> Result<()> {
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0])?;
+    out.write_all(kind.as_bytes())?;
+    out.write_all(&[0])?;
+    out.write_all(&(value.len() as i32).to_le_bytes())?;
+    out.write_all(value)?;
+    Ok(())
+}
+
+fn bytes(values: &[i32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Write an uncompressed scanline OpenEXR image with half float R, G and B
+/// channels
+///
+/// `rgba` holds the raw bits of 16 bit floats, four per pixel in row order
+/// (the layout of an `Rgba16Float` texture), alpha is dropped.
+pub fn write_exr<W: Write>(mut out: W, width: u32, height: u32, rgba: &[u16]) -> Result<()> {
+    if rgba.len() != (width * height * 4) as usize {
+        bail!("Expected {}x{} RGBA pixels", width, height);
+    }
+
+    // channels have to be listed, and stored, in alphabetical order
+    let channels = [("B", 2), ("G", 1), ("R", 0)];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+    // version 2, single part scanline file
+    header.extend_from_slice(&2i32.to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for (name, _) in channels.iter() {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&HALF.to_le_bytes());
+        // linear flag and padding
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&bytes(&[1, 1]));
+    }
+    chlist.push(0);
+    attribute(&mut header, "channels", "chlist", &chlist)?;
+    attribute(&mut header, "compression", "compression", &[0])?;
+    let window = bytes(&[0, 0, width as i32 - 1, height as i32 - 1]);
+    attribute(&mut header, "dataWindow", "box2i", &window)?;
+    attribute(&mut header, "displayWindow", "box2i", &window)?;
+    attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    header.push(0);
+    out.write_all(&header)?;
+
+    // one scanline per block, the offset table points at each of them
+    let line_bytes = width as usize * channels.len() * 2;
+    let block_bytes = 8 + line_bytes as u64;
+    let first_block = header.len() as u64 + 8 * height as u64;
+    for y in 0..height as u64 {
+        out.write_all(&(first_block + y * block_bytes).to_le_bytes())?;
+    }
+
+    let mut line = Vec::with_capacity(line_bytes);
+    for (y, row) in rgba.chunks(width as usize * 4).enumerate() {
+        line.clear();
+        for (_, channel) in channels.iter() {
+            for pixel in row.chunks(4) {
+                line.extend_from_slice(&pixel[*channel].to_le_bytes());
+            }
+        }
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(line_bytes as i32).to_le_bytes())?;
+        out.write_all(&line)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Convert the bits of an IEEE 754 half float
+pub fn half_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn halves_convert() {
+        assert_eq!(half_to_f32(0x3c00), 1.0);
+        assert_eq!(half_to_f32(0xc000), -2.0);
+        assert_eq!(half_to_f32(0x7bff), 65504.0);
+        assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+        assert!(half_to_f32(0x7c00).is_infinite());
+        assert!(half_to_f32(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn exr_layout() {
+        let (width, height) = (3, 2);
+        // every pixel is R = 1, G = 2, B = 0.5
+        let rgba = [0x3c00, 0x4000, 0x3800, 0x3c00].repeat(width * height);
+        let mut out = Vec::new();
+        write_exr(&mut out, width as u32, height as u32, &rgba).unwrap();
+
+        assert_eq!(&out[..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        assert_eq!(&out[8..17], b"channels\0");
+
+        // last block: y, size, then the B, G and R lines
+        let line_bytes = width * 3 * 2;
+        let block = &out[out.len() - 8 - line_bytes..];
+        assert_eq!(&block[..4], &1i32.to_le_bytes());
+        assert_eq!(&block[4..8], &(line_bytes as i32).to_le_bytes());
+        assert_eq!(&block[8..10], &0x3800u16.to_le_bytes());
+        assert_eq!(&block[block.len() - 2..], &0x3c00u16.to_le_bytes());
+
+        // the offset table points at the last block
+        let offsets_end = out.len() - 2 * (8 + line_bytes);
+        let last_offset = &out[offsets_end - 8..offsets_end];
+        assert_eq!(
+            u64::from_le_bytes(last_offset.try_into().unwrap()),
+            (out.len() - 8 - line_bytes) as u64
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::*;
+use std::iter;
+use std::path::Path;
+
+use crate::recorder;
+use crate::renderer;
+use crate::scene;
+use crate::screenshot;
+use crate::simulation;
+
+// rgba so that the readback can be saved without swizzling
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// How long to run the simulation before taking the picture
+pub enum RunLength {
+    Steps(u64),
+    Time(f64),
+}
+
+/// Everything needed to render without a window
+pub struct Headless {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+}
+
+impl Headless {
+    /// Set up a device without a surface.  Any adapter will do, including
+    /// software ones like lavapipe or SwiftShader.
+    pub async fn new(max_size: winit::dpi::PhysicalSize<u32>) -> Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY | wgpu::BackendBit::SECONDARY);
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+            })
+            .await
+            .context("No graphics adapter found")?;
+        let info = adapter.get_info();
+        log::info!("Using {} ({:?})", info.name, info.backend);
+
+        // wallpapers can be bigger than the default limits allow
+        let limits = adapter.limits();
+        let max_dimension = max_size.width.max(max_size.height);
+        if max_dimension > limits.max_texture_dimension_2d {
+            bail!(
+                "{}x{} is too large, this adapter can render at most {}x{}",
+                max_size.width,
+                max_size.height,
+                limits.max_texture_dimension_2d,
+                limits.max_texture_dimension_2d
+            );
+        }
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: wgpu::Features::empty(),
+                    limits,
+                },
+                None, // Trace path
+            )
+            .await?;
+
+        Ok(Self { device, queue })
+    }
+
+    /// Run the scene's simulation for `run_length` and save a `size` picture
+    /// of the result to `path`, along with the scene that reproduces it and,
+    /// if `hdr` is given, the untonemapped picture
+    pub fn render_scene<P: AsRef<Path>>(
+        &self,
+        mut scene: scene::Scene,
+        size: winit::dpi::PhysicalSize<u32>,
+        run_length: RunLength,
+        path: P,
+        hdr: Option<screenshot::HdrFormat>,
+    ) -> Result<()> {
+        let mut simulation = warm_up(&mut scene, run_length)?;
+
+        let mut renderer =
+            renderer::Renderer::new(&self.device, size, FORMAT, &simulation, &scene)?;
+        let camera = scene.camera.camera();
+        let projection = scene.camera.projection(size.width, size.height);
+        renderer.update_camera(&self.queue, &camera, &projection, simulation.origin());
+        renderer.upload(&self.device, &self.queue, &mut simulation);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Headless Render Encoder"),
+            });
+        let mut screenshot = screenshot::ScreenShot::init(size, FORMAT, &self.device);
+        if hdr.is_some() {
+            screenshot = screenshot.with_hdr(&self.device);
+        }
+        renderer.render_to(&screenshot.output_texture.view, &mut encoder, &simulation);
+        screenshot.copy_back_buffer(&mut encoder);
+        screenshot.copy_hdr_buffer(&mut encoder, renderer.hdr_texture());
+        self.queue.submit(iter::once(encoder.finish()));
+
+        screenshot.save(&self.device, path.as_ref())?;
+        if let Some(format) = hdr {
+            let hdr_path = path.as_ref().with_extension(format.extension());
+            screenshot.save_hdr(&self.device, &hdr_path, format)?;
+            println!("Saved {}", hdr_path.display());
+        }
+        screenshot::save_scene(path.as_ref(), &scene)?;
+        println!("Saved {}", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Run the scene's simulation for `run_length`, then render frames for
+    /// `recorder` until it has saved all of the frames it wants
+    pub fn record_scene(
+        &self,
+        mut scene: scene::Scene,
+        run_length: RunLength,
+        mut recorder: recorder::Recorder,
+    ) -> Result<()> {
+        let size = recorder.size();
+        let mut simulation = warm_up(&mut scene, run_length)?;
+        recorder.save_scene(&scene)?;
+
+        let mut renderer =
+            renderer::Renderer::new(&self.device, size, FORMAT, &simulation, &scene)?;
+        let camera = scene.camera.camera();
+        let projection = scene.camera.projection(size.width, size.height);
+        renderer.update_camera(&self.queue, &camera, &projection, simulation.origin());
+        let mut screenshot = screenshot::ScreenShot::init(size, FORMAT, &self.device);
+
+        while !recorder.is_done() {
+            if recorder.wants_frame() {
+                renderer.upload(&self.device, &self.queue, &mut simulation);
+                let mut encoder =
+                    self.device
+                        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                            label: Some("Headless Record Encoder"),
+                        });
+                renderer.render_to(&screenshot.output_texture.view, &mut encoder, &simulation);
+                screenshot.copy_back_buffer(&mut encoder);
+                self.queue.submit(iter::once(encoder.finish()));
+            }
+            recorder.frame(|| screenshot.read_rgba(&self.device))?;
+            simulation.advance(recorder.frame_dt());
+        }
+
+        let saved = recorder.finish()?;
+        println!("Saved {} frames", saved);
+        Ok(())
+    }
+}
+
+/// Build the scene's simulation and run it for `run_length`
+fn warm_up(scene: &mut scene::Scene, run_length: RunLength) -> Result<simulation::Simulation> {
+    let mut simulation = simulation::Simulation::new(scene)?;
+    println!("Seed: {}", simulation.seed());
+
+    let n_steps = match run_length {
+        RunLength::Steps(n) => n,
+        RunLength::Time(t) => (t / scene.simulation.fixed_dt).round() as u64,
+    };
+    for ix in 0..n_steps {
+        simulation.step();
+        if (ix + 1) % 1000 == 0 {
+            log::info!("Step {} of {}", ix + 1, n_steps);
+        }
+    }
+    Ok(simulation)
+}
@@ -0,0 +1,228 @@
+use cgmath::Vector3;
+use serde::{Deserialize, Serialize};
+
+/// The right hand side of an ODE: dx/dt = f(x, t)
+pub trait VectorField: Send + Sync {
+    fn derivative(&self, state: Vector3<f64>, t: f64) -> Vector3<f64>;
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Method {
+    Euler,
+    Midpoint,
+    Rk4,
+    // adaptive RK45, error tolerances are per component
+    DormandPrince { rtol: f64, atol: f64 },
+    // for stochastic models (see `sde`), with white noise; on a plain
+    // vector field both are forward Euler
+    EulerMaruyama,
+    Milstein,
+}
+
+pub struct Integrator {
+    pub method: Method,
+    // adaptive step size carried over from the last call so that we
+    // don't have to rediscover it every frame
+    h: Option<f64>,
+}
+
+// Dormand-Prince 5(4) tableau
+const A21: f64 = 1.0 / 5.0;
+const A31: f64 = 3.0 / 40.0;
+const A32: f64 = 9.0 / 40.0;
+const A41: f64 = 44.0 / 45.0;
+const A42: f64 = -56.0 / 15.0;
+const A43: f64 = 32.0 / 9.0;
+const A51: f64 = 19372.0 / 6561.0;
+const A52: f64 = -25360.0 / 2187.0;
+const A53: f64 = 64448.0 / 6561.0;
+const A54: f64 = -212.0 / 729.0;
+const A61: f64 = 9017.0 / 3168.0;
+const A62: f64 = -355.0 / 33.0;
+const A63: f64 = 46732.0 / 5247.0;
+const A64: f64 = 49.0 / 176.0;
+const A65: f64 = -5103.0 / 18656.0;
+// 5th order weights (also the last row of the tableau, i.e. FSAL)
+const B1: f64 = 35.0 / 384.0;
+const B3: f64 = 500.0 / 1113.0;
+const B4: f64 = 125.0 / 192.0;
+const B5: f64 = -2187.0 / 6784.0;
+const B6: f64 = 11.0 / 84.0;
+// difference between the 5th and 4th order weights
+const E1: f64 = 71.0 / 57600.0;
+const E3: f64 = -71.0 / 16695.0;
+const E4: f64 = 71.0 / 1920.0;
+const E5: f64 = -17253.0 / 339200.0;
+const E6: f64 = 22.0 / 525.0;
+const E7: f64 = -1.0 / 40.0;
+
+// limits on how much the adaptive step can change in one go
+const SAFETY: f64 = 0.9;
+const MIN_FACTOR: f64 = 0.2;
+const MAX_FACTOR: f64 = 5.0;
+// give up refining and accept the step after this many rejections
+const MAX_REJECTS: u32 = 32;
+
+impl Integrator {
+    pub fn new(method: Method) -> Self {
+        Self { method, h: None }
+    }
+
+    /// Advance `state` from `t` to `t + dt`
+    pub fn advance<F: VectorField + ?Sized>(
+        &mut self,
+        field: &F,
+        state: Vector3<f64>,
+        t: f64,
+        dt: f64,
+    ) -> Vector3<f64> {
+        match self.method {
+            Method::Euler | Method::EulerMaruyama | Method::Milstein => {
+                state + dt * field.derivative(state, t)
+            }
+            Method::Midpoint => {
+                let k1 = field.derivative(state, t);
+                let k2 = field.derivative(state + 0.5 * dt * k1, t + 0.5 * dt);
+                state + dt * k2
+            }
+            Method::Rk4 => {
+                let k1 = field.derivative(state, t);
+                let k2 = field.derivative(state + 0.5 * dt * k1, t + 0.5 * dt);
+                let k3 = field.derivative(state + 0.5 * dt * k2, t + 0.5 * dt);
+                let k4 = field.derivative(state + dt * k3, t + dt);
+                state + (dt / 6.0) * (k1 + 2.0 * k2 + 2.0 * k3 + k4)
+            }
+            Method::DormandPrince { rtol, atol } => {
+                self.advance_adaptive(field, state, t, dt, rtol, atol)
+            }
+        }
+    }
+
+    fn advance_adaptive<F: VectorField + ?Sized>(
+        &mut self,
+        field: &F,
+        state: Vector3<f64>,
+        t: f64,
+        dt: f64,
+        rtol: f64,
+        atol: f64,
+    ) -> Vector3<f64> {
+        let t_end = t + dt;
+        let mut t = t;
+        let mut y = state;
+        let mut h = self.h.unwrap_or(dt).min(dt);
+        let mut k1 = field.derivative(y, t);
+        let mut rejects = 0;
+
+        while t < t_end {
+            let last = t + h >= t_end;
+            if last {
+                h = t_end - t;
+            }
+
+            let k2 = field.derivative(y + h * A21 * k1, t + h / 5.0);
+            let k3 = field.derivative(y + h * (A31 * k1 + A32 * k2), t + 3.0 * h / 10.0);
+            let k4 = field.derivative(y + h * (A41 * k1 + A42 * k2 + A43 * k3), t + 4.0 * h / 5.0);
+            let k5 = field.derivative(
+                y + h * (A51 * k1 + A52 * k2 + A53 * k3 + A54 * k4),
+                t + 8.0 * h / 9.0,
+            );
+            let k6 = field.derivative(
+                y + h * (A61 * k1 + A62 * k2 + A63 * k3 + A64 * k4 + A65 * k5),
+                t + h,
+            );
+            let y_new = y + h * (B1 * k1 + B3 * k3 + B4 * k4 + B5 * k5 + B6 * k6);
+            let k7 = field.derivative(y_new, t + h);
+
+            let err = h * (E1 * k1 + E3 * k3 + E4 * k4 + E5 * k5 + E6 * k6 + E7 * k7);
+            let err_norm = error_norm(err, y, y_new, rtol, atol);
+
+            let factor = if err_norm == 0.0 {
+                MAX_FACTOR
+            } else {
+                (SAFETY * err_norm.powf(-0.2)).clamp(MIN_FACTOR, MAX_FACTOR)
+            };
+
+            if err_norm <= 1.0 || rejects >= MAX_REJECTS || !err_norm.is_finite() {
+                t = if last { t_end } else { t + h };
+                y = y_new;
+                k1 = k7;
+                rejects = 0;
+                // don't let the shortened final step shrink the next frame's first step
+                if !last {
+                    self.h = Some(h * factor);
+                }
+                h *= factor;
+            } else {
+                rejects += 1;
+                h *= factor;
+            }
+        }
+
+        y
+    }
+}
+
+fn error_norm(
+    err: Vector3<f64>,
+    y: Vector3<f64>,
+    y_new: Vector3<f64>,
+    rtol: f64,
+    atol: f64,
+) -> f64 {
+    let scaled = |e: f64, a: f64, b: f64| (e / (atol + rtol * a.abs().max(b.abs()))).abs();
+    scaled(err.x, y.x, y_new.x)
+        .max(scaled(err.y, y.y, y_new.y))
+        .max(scaled(err.z, y.z, y_new.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // dx/dt = -x, exact solution x(t) = x0 * exp(-t)
+    struct Decay;
+
+    impl VectorField for Decay {
+        fn derivative(&self, state: Vector3<f64>, _t: f64) -> Vector3<f64> {
+            -state
+        }
+    }
+
+    fn integrate(method: Method, dt: f64, steps: usize) -> f64 {
+        let mut integrator = Integrator::new(method);
+        let mut state = Vector3::new(1.0, 1.0, 1.0);
+        let mut t = 0.0;
+        for _ in 0..steps {
+            state = integrator.advance(&Decay, state, t, dt);
+            t += dt;
+        }
+        (state.x - (-t).exp()).abs()
+    }
+
+    #[test]
+    fn higher_order_methods_are_more_accurate() {
+        let euler = integrate(Method::Euler, 0.1, 10);
+        let midpoint = integrate(Method::Midpoint, 0.1, 10);
+        let rk4 = integrate(Method::Rk4, 0.1, 10);
+        assert!(midpoint < euler);
+        assert!(rk4 < midpoint);
+        assert!(rk4 < 1.0e-5);
+    }
+
+    #[test]
+    fn dormand_prince_meets_tolerance() {
+        // one big step that has to be subdivided internally
+        let err = integrate(
+            Method::DormandPrince {
+                rtol: 1.0e-5,
+                atol: 1.0e-7,
+            },
+            2.0,
+            1,
+        );
+        assert!(err < 1.0e-4, "error was {}", err);
+    }
+}
@@ -0,0 +1,56 @@
+use cgmath::Vector3;
+
+use crate::dynamics::DynamicSystem;
+use crate::rand_util::Chaos;
+
+/// The state of a particle of an interacting model
+///
+/// Particles of these models are stepped together by an `Interaction`
+/// rather than one by one, on their own a body just drifts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Body {
+    pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    pub mass: f64,
+    // index of the scene group the particle belongs to
+    pub group: usize,
+}
+
+impl Body {
+    pub fn at(position: Vector3<f64>) -> Self {
+        Self {
+            position,
+            velocity: Vector3::new(0.0, 0.0, 0.0),
+            mass: 1.0,
+            group: 0,
+        }
+    }
+}
+
+impl DynamicSystem for Body {
+    fn step(&mut self, dt: f64, _chaos: &mut Chaos) {
+        self.position += dt * self.velocity;
+    }
+
+    fn get_position(&self) -> Vector3<f64> {
+        self.position
+    }
+
+    fn body_mut(&mut self) -> Option<&mut Body> {
+        Some(self)
+    }
+}
+
+/// Dynamics where particles act on each other, e.g. gravity
+///
+/// Every step the simulation hands over the bodies of all particles at
+/// once (enabled or not, disabled ones just aren't drawn).
+pub trait Interaction: Send + Sync {
+    fn step(&mut self, bodies: &mut [Body], dt: f64);
+
+    /// Something worth logging now and then, e.g. how well conserved
+    /// quantities are kept
+    fn report(&self, _bodies: &[Body]) -> Option<String> {
+        None
+    }
+}
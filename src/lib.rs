@@ -0,0 +1,94 @@
+//! wagoo: particles following dynamical systems, drawn with their trails
+//!
+//! The pieces the `wagoo` binary is built from, for embedding the
+//! visualizer in other winit/wgpu applications: a `Simulation` holds the
+//! particles, their dynamics and the clock, a `Renderer` draws a
+//! simulation into any texture view, and the camera types move the view
+//! around.  Everything is set up from a `Scene`, which can be loaded from
+//! a TOML file or built in code.
+//!
+//! The application owns the device, the queue and the surface.  Drawing a
+//! frame looks like this:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! fn frame(
+//!     device: &wgpu::Device,
+//!     queue: &wgpu::Queue,
+//!     view: &wgpu::TextureView,
+//!     format: wgpu::TextureFormat,
+//! ) -> anyhow::Result<()> {
+//!     let mut scene = wagoo::Scene::default();
+//!     let mut simulation = wagoo::Simulation::new(&mut scene)?;
+//!     let size = winit::dpi::PhysicalSize::new(1280, 720);
+//!     let mut renderer = wagoo::Renderer::new(device, size, format, &simulation, &scene)?;
+//!
+//!     let camera = scene.camera.camera();
+//!     let projection = scene.camera.projection(size.width, size.height);
+//!     renderer.update_camera(queue, &camera, &projection, simulation.origin());
+//!
+//!     // then, every frame
+//!     if simulation.advance(Duration::from_secs_f64(1.0 / 60.0)) > 0 {
+//!         renderer.upload(device, queue, &mut simulation);
+//!     }
+//!     let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+//!         label: Some("Frame"),
+//!     });
+//!     renderer.render_to(view, &mut encoder, &simulation);
+//!     queue.submit(std::iter::once(encoder.finish()));
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Particles are simulated in f64 and converted to f32 relative to
+//! `Simulation::origin()` on the way to the GPU.  For scenes with
+//! `camera_relative` set, call `simulation.follow_camera(camera.position)`
+//! before `update_camera` whenever the camera moves, and upload again if
+//! it returns true.
+//!
+//! The device needs the adapter's limits (`adapter.limits()`) rather than
+//! the defaults for scenes with long tails on many particles: the tails are
+//! split over several storage buffers, which the defaults don't allow
+//! enough of.
+
+pub mod attractors;
+pub mod boids;
+pub mod camera;
+pub mod charged;
+pub mod clock;
+mod compute;
+pub mod diagnostics;
+pub mod dynamics;
+pub mod expression;
+mod exr;
+pub mod headless;
+pub mod integrator;
+pub mod interaction;
+pub mod maps;
+mod model;
+pub mod nbody;
+pub mod post;
+mod quad;
+pub mod rand_util;
+pub mod recorder;
+pub mod registry;
+pub mod renderer;
+mod sampler;
+pub mod scene;
+pub mod screenshot;
+pub mod script;
+pub mod sde;
+pub mod simulation;
+pub mod spatial_hash;
+pub mod sphere;
+pub mod tail_store;
+pub mod texture;
+pub mod util;
+
+pub use camera::{Camera, CameraController, Projection};
+pub use dynamics::DynamicSystem;
+pub use integrator::VectorField;
+pub use renderer::Renderer;
+pub use scene::Scene;
+pub use simulation::Simulation;
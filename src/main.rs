@@ -1,50 +1,18 @@
-use cgmath::prelude::*;
+use anyhow::Context;
 use std::iter;
-use wgpu::util::DeviceExt;
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
     window::Window,
 };
 
-mod camera;
-mod dynamics;
-mod model;
-mod post;
-mod quad;
-mod rand_util;
-mod sampler;
-mod screenshot;
-mod sphere;
-mod tail_buffer;
-mod texture;
-mod util;
-
-use model::Vertex;
-use quad::DrawQuad;
-use sphere::DrawSphere;
-
-#[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct Uniforms {
-    view_position: [f32; 4],
-    view_proj: [[f32; 4]; 4],
-}
+mod cli;
 
-impl Uniforms {
-    fn new() -> Self {
-        Self {
-            view_position: [0.0; 4],
-            view_proj: cgmath::Matrix4::identity().into(),
-        }
-    }
+use wagoo::{
+    camera, diagnostics, headless, recorder, registry, renderer, scene, screenshot, simulation,
+};
 
-    // UPDATED!
-    fn update_view_proj(&mut self, camera: &camera::Camera, projection: &camera::Projection) {
-        self.view_position = camera.position.to_homogeneous().into();
-        self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into()
-    }
-}
+use structopt::StructOpt;
 
 struct State {
     surface: wgpu::Surface,
@@ -52,33 +20,31 @@ struct State {
     queue: wgpu::Queue,
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
-    render_pipeline_no_light: wgpu::RenderPipeline,
-    render_pipeline_tails: wgpu::RenderPipeline,
     camera: camera::Camera,
     projection: camera::Projection,
     camera_controller: camera::CameraController,
-    uniforms: Uniforms,
-    uniform_buffer: wgpu::Buffer,
-    uniform_bind_group: wgpu::BindGroup,
-    sphere_mesh: sphere::SphereMesh,
-    sphere_instances: Vec<sphere::SphereInstance>,
-
-    sphere_instance_buffer: wgpu::Buffer,
-    tail_buffers: Vec<wgpu::Buffer>,
-    #[allow(dead_code)]
-    depth_texture: texture::Texture,
+    renderer: renderer::Renderer,
+    simulation: simulation::Simulation,
     size: winit::dpi::PhysicalSize<u32>,
-    post: post::Post,
     #[allow(dead_code)]
     mouse_pressed: bool,
-    paused: bool,
     need_screenshot: bool,
-    chaos: rand_util::Chaos,
+    recorder: Option<recorder::Recorder>,
+    // also save untonemapped screenshots in this format
+    hdr_format: Option<screenshot::HdrFormat>,
+    scene: scene::Scene,
 }
 
 impl State {
-    async fn new(window: &Window, size: winit::dpi::PhysicalSize<u32>) -> Self {
-        let mut chaos = rand_util::Chaos::new();
+    async fn new(
+        window: &Window,
+        size: winit::dpi::PhysicalSize<u32>,
+        mut scene: scene::Scene,
+        paused: bool,
+    ) -> anyhow::Result<Self> {
+        let mut simulation = simulation::Simulation::new(&mut scene)?;
+        simulation.paused = paused;
+        println!("Seed: {}", simulation.seed());
 
         // The instance is a handle to our GPU
         // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
@@ -96,7 +62,8 @@ impl State {
                 &wgpu::DeviceDescriptor {
                     label: None,
                     features: wgpu::Features::empty(),
-                    limits: wgpu::Limits::default(),
+                    // large tail stores need big storage buffers
+                    limits: adapter.limits(),
                 },
                 None, // Trace path
             )
@@ -114,189 +81,33 @@ impl State {
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
 
         // UPDATED!
-        let camera = camera::Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0));
-        let projection =
-            camera::Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(45.0), 0.1, 100.0);
-        let camera_controller = camera::CameraController::new(4.0, 0.4);
-
-        let mut uniforms = Uniforms::new();
-        uniforms.update_view_proj(&camera, &projection);
-
-        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[uniforms]),
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-        });
-
-        let sphere_mesh = sphere::SphereMesh::new(&device, 64, 64);
-
-        let lims = 4.0;
-        let n_spheres = 1000;
-        let sphere_instances = (0..n_spheres)
-            .map(|_ix| {
-                /*
-                let dynamics = dynamics::Circler::new(0.01, 0.01, lims, &mut chaos);
-                sphere::SphereInstance::randomized(
-                    &mut chaos,
-                    Box::new(dynamics),
-                )
-                */
-                let s = 0.1;
-                let sigma = 18.0;
-                let rho = 8.0;
-                let beta = 8.0 / 3.0;
-                let dynamics = dynamics::Lorenz::new(sigma, rho, beta, s, lims, &mut chaos);
-                sphere::SphereInstance::randomized(&mut chaos, Box::new(dynamics))
-            })
-            .collect::<Vec<_>>();
-        let sphere_instance_data = sphere_instances
-            .iter()
-            .map(sphere::SphereInstance::to_raw)
-            .collect::<Vec<_>>();
-        let sphere_instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Sphere instance buffer"),
-            contents: bytemuck::cast_slice(&sphere_instance_data),
-            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-        });
-
-        let buffer_fill = (0..1024)
-            .map(|_ix| sphere::SphereVertex {
-                position: [0.0, 0.0, 0.0],
-            })
-            .collect::<Vec<_>>();
-        let tail_buffers = sphere_instances
-            .iter()
-            .map(|_s| {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Tails buffer"),
-                    contents: bytemuck::cast_slice(&buffer_fill),
-                    usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-                })
-            })
-            .into_iter()
-            .collect::<Vec<_>>();
-
-        let uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-                label: Some("uniform_bind_group_layout"),
-            });
-
-        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
-            label: Some("uniform_bind_group"),
-        });
-
-        let depth_texture = texture::Texture::create_depth_texture(&device, size, "depth_texture");
-
-        let render_pipeline_layout_no_light =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout (No Light)"),
-                bind_group_layouts: &[&uniform_bind_group_layout],
-                push_constant_ranges: &[],
-            });
+        let camera = scene.camera.camera();
+        let projection = scene.camera.projection(sc_desc.width, sc_desc.height);
+        let camera_controller = scene.camera.controller();
 
-        let render_pipeline_no_light = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader (No Light)"),
-                flags: wgpu::ShaderFlags::all(),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader_no_light.wgsl").into()),
-            };
-            util::create_render_pipeline(
-                &device,
-                &render_pipeline_layout_no_light,
-                sc_desc.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[
-                    sphere::SphereVertex::desc(),
-                    sphere::SphereInstanceRaw::desc(),
-                ],
-                shader,
-            )
-        };
-
-        let render_pipeline_layout_tails =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout (Tails)"),
-                bind_group_layouts: &[&uniform_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let render_pipeline_tails = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader (No Light)"),
-                flags: wgpu::ShaderFlags::all(),
-                source: wgpu::ShaderSource::Wgsl(include_str!("tail_shader.wgsl").into()),
-            };
-            let primitive = wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::LineStrip,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                polygon_mode: wgpu::PolygonMode::Fill,
-                // Requires Features::DEPTH_CLAMPING
-                clamp_depth: false,
-                // Requires Features::CONSERVATIVE_RASTERIZATION
-                conservative: false,
-            };
-
-            util::create_render_pipeline_with_primitive(
-                &device,
-                &render_pipeline_layout_tails,
-                sc_desc.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[
-                    sphere::SphereVertex::desc(),
-                    sphere::SphereInstanceRaw::desc(),
-                ],
-                shader,
-                primitive,
-            )
-        };
+        let mut renderer =
+            renderer::Renderer::new(&device, size, sc_desc.format, &simulation, &scene)?;
+        renderer.update_camera(&queue, &camera, &projection, simulation.origin());
 
-        let post = post::Post::new(&device, size, sc_desc.format);
-
-        Self {
+        Ok(Self {
             surface,
             device,
             queue,
             sc_desc,
             swap_chain,
-            render_pipeline_no_light,
-            render_pipeline_tails,
             camera,
             projection,
             camera_controller,
-            uniform_buffer,
-            uniform_bind_group,
-            uniforms,
-            depth_texture,
+            renderer,
+            simulation,
             size,
-            post,
-            sphere_mesh,
-            sphere_instances,
-            sphere_instance_buffer,
-            tail_buffers,
             #[allow(dead_code)]
             mouse_pressed: false,
-            paused: false,
             need_screenshot: false,
-            chaos,
-        }
+            recorder: None,
+            hdr_format: None,
+            scene,
+        })
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -305,8 +116,29 @@ impl State {
         self.sc_desc.width = new_size.width;
         self.sc_desc.height = new_size.height;
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
-        //self.depth_texture =
-        //    texture::Texture::create_depth_texture(&self.device, &self.sc_desc, self.size, "depth_texture");
+        self.renderer.resize(&self.device, new_size);
+
+        if let Some(recorder) = &self.recorder {
+            if recorder.size() != new_size {
+                eprintln!("Window size changed, stopping the recording");
+                self.stop_recording();
+            }
+        }
+    }
+
+    fn start_recording(&mut self, recorder: recorder::Recorder) -> anyhow::Result<()> {
+        recorder.save_scene(&self.scene)?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            match recorder.finish() {
+                Ok(saved) => println!("Saved {} frames", saved),
+                Err(e) => eprintln!("Could not finish the recording: {:#}", e),
+            }
+        }
     }
 
     fn input(&mut self, event: &DeviceEvent) -> bool {
@@ -317,7 +149,7 @@ impl State {
                 ..
             }) => {
                 if *state == ElementState::Pressed {
-                    self.paused = !self.paused;
+                    self.simulation.paused = !self.simulation.paused;
                 }
                 true
             }
@@ -331,6 +163,28 @@ impl State {
                 }
                 true
             }
+            DeviceEvent::Key(KeyboardInput {
+                virtual_keycode: Some(VirtualKeyCode::Period),
+                state,
+                ..
+            }) => {
+                if *state == ElementState::Pressed {
+                    self.simulation.clock.speed_up();
+                    log::info!("Time scale {}", self.simulation.clock.time_scale);
+                }
+                true
+            }
+            DeviceEvent::Key(KeyboardInput {
+                virtual_keycode: Some(VirtualKeyCode::Comma),
+                state,
+                ..
+            }) => {
+                if *state == ElementState::Pressed {
+                    self.simulation.clock.slow_down();
+                    log::info!("Time scale {}", self.simulation.clock.time_scale);
+                }
+                true
+            }
             DeviceEvent::Key(KeyboardInput {
                 virtual_keycode: Some(key),
                 state,
@@ -359,75 +213,89 @@ impl State {
 
     fn update(&mut self, dt: std::time::Duration) {
         self.camera_controller.update_camera(&mut self.camera, dt);
-        self.uniforms
-            .update_view_proj(&self.camera, &self.projection);
-        self.queue.write_buffer(
-            &self.uniform_buffer,
-            0,
-            bytemuck::cast_slice(&[self.uniforms]),
+        // everything already on the GPU is relative to the old origin
+        let recentered = self.simulation.follow_camera(self.camera.position);
+        self.renderer.update_camera(
+            &self.queue,
+            &self.camera,
+            &self.projection,
+            self.simulation.origin(),
         );
 
-        // Update the light
-        if !self.paused {
-            for ix in 0..self.sphere_instances.len() {
-                if self.sphere_instances[ix].enabled {
-                    self.sphere_instances[ix].update(&mut self.chaos);
-                } else {
-                    // if not enabled, randomly enable
-                    let p_enable = 0.001;
-                    if self.chaos.bernoulli(p_enable) {
-                        self.sphere_instances[ix].enabled = true;
-                    }
+        // while recording, the simulation moves at the recording's frame
+        // rate rather than in real time
+        let sim_dt = match &self.recorder {
+            Some(recorder) => recorder.frame_dt(),
+            None => dt,
+        };
+        if self.simulation.advance(sim_dt) > 0 || recentered {
+            self.renderer
+                .upload(&self.device, &self.queue, &mut self.simulation);
+        }
+
+        if let Some(mut recorder) = self.recorder.take() {
+            match recorder.frame(|| self.render_offscreen(false).read_rgba(&self.device)) {
+                Ok(_) if !recorder.is_done() => self.recorder = Some(recorder),
+                Ok(_) => {
+                    self.recorder = Some(recorder);
+                    self.stop_recording();
+                }
+                Err(e) => {
+                    eprintln!("Could not record frame: {:#}", e);
+                    self.recorder = Some(recorder);
+                    self.stop_recording();
                 }
             }
-            let sphere_instance_data = self
-                .sphere_instances
-                .iter()
-                .map(sphere::SphereInstance::to_raw)
-                .collect::<Vec<_>>();
-
-            self.queue.write_buffer(
-                &self.sphere_instance_buffer,
-                0,
-                bytemuck::cast_slice(&sphere_instance_data),
-            );
-            for ix in 0..self.sphere_instances.len() {
-                let raw = self.sphere_instances[ix].raw_tail();
-                self.queue
-                    .write_buffer(&self.tail_buffers[ix], 0, bytemuck::cast_slice(&raw))
-            }
-
-            /*
-            let old_position: cgmath::Vector3<_> = self.light.position.into();
-            self.light.position =
-                (cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0))
-                    * old_position)
-                    .into();
-            self.queue
-                .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light]));
-                */
         }
 
         if self.need_screenshot {
-            let mut encoder = self
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Screenshot Render Encoder"),
-                });
+            if let Err(e) = self.save_screenshot() {
+                eprintln!("Could not save screenshot: {:#}", e);
+            }
+            self.need_screenshot = false;
+        }
+    }
 
-            let mut screenshot =
-                screenshot::ScreenShot::init(self.size, self.sc_desc.format, &self.device);
+    fn save_screenshot(&mut self) -> anyhow::Result<()> {
+        let screenshot = self.render_offscreen(self.hdr_format.is_some());
+        let path = screenshot::build_path();
+        screenshot.save(&self.device, &path)?;
+        if let Some(format) = self.hdr_format {
+            screenshot.save_hdr(
+                &self.device,
+                path.with_extension(format.extension()),
+                format,
+            )?;
+        }
+        screenshot::save_scene(&path, &self.scene)?;
+        Ok(())
+    }
 
-            self.render_to(&screenshot.output_texture.view, &mut encoder)
-                .unwrap();
+    /// Render the current frame into a texture that can be read back,
+    /// optionally along with the untonemapped frame
+    fn render_offscreen(&self, hdr: bool) -> screenshot::ScreenShot {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Screenshot Render Encoder"),
+            });
 
-            screenshot.copy_back_buffer(&mut encoder);
-            self.queue.submit(iter::once(encoder.finish()));
+        let mut screenshot =
+            screenshot::ScreenShot::init(self.size, self.sc_desc.format, &self.device);
+        if hdr {
+            screenshot = screenshot.with_hdr(&self.device);
+        }
 
-            screenshot.save(&self.device, screenshot::build_path());
+        self.renderer.render_to(
+            &screenshot.output_texture.view,
+            &mut encoder,
+            &self.simulation,
+        );
 
-            self.need_screenshot = false;
-        }
+        screenshot.copy_back_buffer(&mut encoder);
+        screenshot.copy_hdr_buffer(&mut encoder, self.renderer.hdr_texture());
+        self.queue.submit(iter::once(encoder.finish()));
+        screenshot
     }
 
     fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
@@ -437,142 +305,193 @@ impl State {
                 label: Some("Render Encoder"),
             });
         let frame = self.swap_chain.get_current_frame()?.output;
-        self.render_to(&frame.view, &mut encoder).unwrap();
+        self.renderer
+            .render_to(&frame.view, &mut encoder, &self.simulation);
         self.queue.submit(iter::once(encoder.finish()));
         Ok(())
     }
+}
 
-    fn render_to(
-        &mut self,
-        view: &wgpu::TextureView,
-        encoder: &mut wgpu::CommandEncoder,
-    ) -> Result<(), wgpu::SwapChainError> {
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &self.post.ping_texture.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+fn describe_monitors(monitors: &[winit::monitor::MonitorHandle]) -> String {
+    monitors
+        .iter()
+        .enumerate()
+        .map(|(ix, monitor)| {
+            let size = monitor.size();
+            format!(
+                "  {}: {} ({}x{})",
+                ix,
+                monitor.name().unwrap_or_else(|| "unknown".to_string()),
+                size.width,
+                size.height
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-            render_pass.set_vertex_buffer(1, self.sphere_instance_buffer.slice(..));
-            render_pass.set_pipeline(&self.render_pipeline_no_light);
-            render_pass.draw_sphere_instanced(
-                &self.sphere_mesh,
-                &self.uniform_bind_group,
-                0..self.sphere_instances.len() as u32,
-            );
-
-            render_pass.set_vertex_buffer(1, self.sphere_instance_buffer.slice(..));
-            for (ix, s) in self.sphere_instances.iter().enumerate() {
-                let n = s.tail_len();
-                render_pass.set_vertex_buffer(0, self.tail_buffers[ix].slice(..));
-                render_pass.set_pipeline(&self.render_pipeline_tails);
-                render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
-                render_pass.draw(0..(n as u32), (ix as u32)..((ix as u32) + 1));
+fn list_models() -> String {
+    registry::models()
+        .iter()
+        .map(|model| {
+            let mut text = format!("{} - {}", model.name, model.description);
+            for param in model.params.iter() {
+                text += &format!(
+                    "\n    {} = {} ({} to {})",
+                    param.name,
+                    param.default,
+                    param.range.start(),
+                    param.range.end()
+                );
             }
-        }
+            text
+        })
+        // not registered models, they're compiled from the scene
+        .chain(
+            [
+                "equations - The scene's own equations, dx, dy and dz in [dynamics.equations]",
+                "script - The scene's own Rhai script, see `script` in [dynamics]",
+            ]
+            .iter()
+            .map(|text| text.to_string()),
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass 2"),
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &self.post.pong_texture.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
 
-            render_pass.set_pipeline(&self.post.render_pipeline);
-            render_pass.set_bind_group(0, &self.post.ping_texture_bind_group, &[]);
-            render_pass.set_bind_group(1, &self.post.ping_uniform_bind_group, &[]);
-            render_pass.draw_quad(&self.post.fullscreen_quad);
-        }
+    let opts = cli::Opts::from_args();
+    if opts.list_models {
+        println!("{}", list_models());
+        return Ok(());
+    }
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass 3"),
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
+    let mut scene = match &opts.scene {
+        Some(path) => scene::Scene::load(path)?,
+        None => scene::Scene::default(),
+    };
+    if let Some(seed) = opts.seed {
+        scene.seed = Some(seed);
+    }
+    if let Some(particles) = opts.particles {
+        scene.set_particle_count(particles)?;
+    }
+    if let Some(model) = &opts.model {
+        if *model != scene.dynamics.model {
+            // the scene's parameters belong to its own model
+            scene.dynamics.model = model.clone();
+            scene.dynamics.params.clear();
+        }
+    }
+    if opts.gpu {
+        scene.simulation.backend = scene::Backend::Gpu;
+    }
+    scene.validate()?;
 
-            render_pass.set_pipeline(&self.post.render_pipeline);
-            render_pass.set_bind_group(0, &self.post.pong_texture_bind_group, &[]);
-            render_pass.set_bind_group(1, &self.post.pong_uniform_bind_group, &[]);
-            render_pass.draw_quad(&self.post.fullscreen_quad);
+    if opts.lyapunov {
+        let spectrum = diagnostics::scene_spectrum(&scene)?;
+        println!("{}", spectrum);
+        if spectrum.behavior() != diagnostics::Behavior::Chaotic {
+            std::process::exit(2);
         }
+        return Ok(());
+    }
 
-        Ok(())
+    use futures::executor::block_on;
+
+    if opts.headless {
+        let size = opts
+            .size
+            .unwrap_or(cli::Size {
+                width: 1920,
+                height: 1080,
+            })
+            .into();
+        let default_steps = if opts.record.is_some() { 0 } else { 3600 };
+        let run_length = match opts.time {
+            Some(t) => headless::RunLength::Time(t),
+            None => headless::RunLength::Steps(opts.steps.unwrap_or(default_steps)),
+        };
+        let headless = block_on(headless::Headless::new(size))?;
+        return match &opts.record {
+            Some(path) => {
+                let recorder = recorder::Recorder::create(
+                    path,
+                    size,
+                    opts.fps,
+                    opts.every,
+                    Some(opts.frames.unwrap_or(600)),
+                )?;
+                headless.record_scene(scene, run_length, recorder)
+            }
+            None => {
+                let path = opts.output.unwrap_or_else(screenshot::build_path);
+                headless.render_scene(scene, size, run_length, path, opts.hdr)
+            }
+        };
     }
-}
 
-fn main() {
-    env_logger::init();
     let event_loop = EventLoop::new();
 
-    let monitor = event_loop
-        .available_monitors()
-        .nth(0)
-        .expect("No monitor 0");
-    let video_mode = monitor.video_modes().nth(0).expect("No video mode 0");
-
-    let size = video_mode.size().clone();
-    let fullscreen = Some(winit::window::Fullscreen::Exclusive(video_mode));
+    let monitors = event_loop.available_monitors().collect::<Vec<_>>();
+    let monitor = monitors.get(opts.monitor).with_context(|| {
+        format!(
+            "No monitor {}, available monitors are:\n{}",
+            opts.monitor,
+            describe_monitors(&monitors)
+        )
+    })?;
 
     let title = env!("CARGO_PKG_NAME");
-    let window = winit::window::WindowBuilder::new()
-        .with_title(title)
-        //        .with_inner_size(winit::dpi::LogicalSize::new(1280, 1024))
-        .with_fullscreen(fullscreen.clone())
-        .build(&event_loop)
-        .unwrap();
-
-    use futures::executor::block_on;
-    let mut state = block_on(State::new(&window, size)); // NEW!
+    let window_builder = winit::window::WindowBuilder::new().with_title(title);
+    let window = if opts.windowed {
+        let size = opts.size.unwrap_or(cli::Size {
+            width: 1280,
+            height: 720,
+        });
+        let window = window_builder
+            .with_inner_size(winit::dpi::PhysicalSize::<u32>::from(size))
+            .build(&event_loop)?;
+        window.set_outer_position(monitor.position());
+        window
+    } else {
+        let video_mode = match opts.size {
+            // prefer the best refresh rate at the requested resolution
+            Some(size) => monitor
+                .video_modes()
+                .filter(|mode| mode.size() == size.into())
+                .max_by_key(|mode| (mode.refresh_rate(), mode.bit_depth()))
+                .with_context(|| {
+                    format!(
+                        "Monitor {} has no {}x{} video mode",
+                        opts.monitor, size.width, size.height
+                    )
+                })?,
+            None => monitor
+                .video_modes()
+                .next()
+                .with_context(|| format!("Monitor {} has no video modes", opts.monitor))?,
+        };
+        window_builder
+            .with_fullscreen(Some(winit::window::Fullscreen::Exclusive(video_mode)))
+            .build(&event_loop)?
+    };
+    let size = window.inner_size();
+
+    let mut state = block_on(State::new(&window, size, scene, opts.paused))?; // NEW!
+    state.hdr_format = opts.hdr;
+    if let Some(path) = &opts.record {
+        let recorder = recorder::Recorder::create(path, size, opts.fps, opts.every, opts.frames)?;
+        state.start_recording(recorder)?;
+    }
     let mut last_render_time = std::time::Instant::now();
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
         match event {
             Event::MainEventsCleared => window.request_redraw(),
+            Event::LoopDestroyed => state.stop_recording(),
             Event::DeviceEvent {
                 ref event,
                 .. // We're not using device_id currently
@@ -0,0 +1,361 @@
+use cgmath::{InnerSpace, Vector3};
+
+use crate::dynamics::DynamicSystem;
+use crate::rand_util::Chaos;
+use crate::registry::{Model, Params};
+
+// orbits that get further than this (in the map's own coordinates) have
+// left the attractor's basin and start over
+const ESCAPE_RADIUS: f64 = 1e3;
+// iterations done before a particle first shows up, so that it starts on
+// the attractor instead of leaving a stray trail getting there
+const WARMUP_ITERATIONS: usize = 100;
+
+/// A discrete time system: every step jumps to the image of the current
+/// point, time doesn't come into it
+pub trait IteratedMap: Send + Sync {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64>;
+}
+
+/// A particle hopping along an orbit of a map, one iteration per step
+///
+/// Like `attractors::Viewed`, world positions are `scale` times the offset
+/// from `center` in the map's own coordinates.  Consecutive iterates land
+/// far apart, so these models draw their tails as points.
+pub struct Iterated<M: IteratedMap> {
+    pub map: M,
+    pub center: Vector3<f64>,
+    pub scale: f64,
+    // in the map's own coordinates
+    pub point: Vector3<f64>,
+}
+
+impl<M: IteratedMap> Iterated<M> {
+    fn iterate(&mut self, chaos: &mut Chaos) {
+        self.point = self.map.apply(self.point);
+        let distance2 = self.point.magnitude2();
+        if !distance2.is_finite() || distance2 > ESCAPE_RADIUS * ESCAPE_RADIUS {
+            self.point = self.center + chaos.random_position_in_cube(0.1);
+        }
+    }
+}
+
+impl<M: IteratedMap> DynamicSystem for Iterated<M> {
+    fn step(&mut self, _dt: f64, chaos: &mut Chaos) {
+        self.iterate(chaos);
+    }
+
+    fn get_position(&self) -> Vector3<f64> {
+        self.scale * (self.point - self.center)
+    }
+
+    fn set_position(&mut self, position: Vector3<f64>) {
+        self.point = self.center + position / self.scale;
+    }
+}
+
+/// Clifford Pickover's map, in the XY plane
+pub struct Clifford {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+}
+
+impl IteratedMap for Clifford {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64> {
+        Vector3::new(
+            (self.a * p.y).sin() + self.c * (self.a * p.x).cos(),
+            (self.b * p.x).sin() + self.d * (self.b * p.y).cos(),
+            0.0,
+        )
+    }
+}
+
+/// Peter de Jong's map, in the XY plane
+pub struct DeJong {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+}
+
+impl IteratedMap for DeJong {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64> {
+        Vector3::new(
+            (self.a * p.y).sin() - (self.b * p.x).cos(),
+            (self.c * p.x).sin() - (self.d * p.y).cos(),
+            0.0,
+        )
+    }
+}
+
+/// The generalized Hénon map in three dimensions (Baier and Klein)
+pub struct Henon3d {
+    pub a: f64,
+    pub b: f64,
+}
+
+impl IteratedMap for Henon3d {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64> {
+        Vector3::new(self.a - p.y * p.y - self.b * p.z, p.x, p.y)
+    }
+}
+
+/// Lozi's piecewise linear cousin of the Hénon map, in the XY plane
+pub struct Lozi {
+    pub a: f64,
+    pub b: f64,
+}
+
+impl IteratedMap for Lozi {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64> {
+        Vector3::new(1.0 - self.a * p.x.abs() + p.y, self.b * p.x, 0.0)
+    }
+}
+
+/// The Ikeda map, a model of light going around a nonlinear optical
+/// resonator, in the XY plane
+pub struct Ikeda {
+    pub u: f64,
+}
+
+impl IteratedMap for Ikeda {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64> {
+        let t = 0.4 - 6.0 / (1.0 + p.x * p.x + p.y * p.y);
+        let (sin, cos) = t.sin_cos();
+        Vector3::new(
+            1.0 + self.u * (p.x * cos - p.y * sin),
+            self.u * (p.x * sin + p.y * cos),
+            0.0,
+        )
+    }
+}
+
+// the terms of a quadratic polynomial in x, y and z, in the order of the
+// coefficients of `Polynomial`
+const MONOMIALS: [&str; 10] = ["1", "x", "y", "z", "xx", "xy", "xz", "yy", "yz", "zz"];
+
+/// A map where each new coordinate is a quadratic polynomial of the old
+/// ones, with coefficients from the scene
+pub struct Polynomial {
+    // one row per output coordinate, see `MONOMIALS`
+    pub coefficients: [[f64; 10]; 3],
+}
+
+impl IteratedMap for Polynomial {
+    fn apply(&self, p: Vector3<f64>) -> Vector3<f64> {
+        let terms = [
+            1.0,
+            p.x,
+            p.y,
+            p.z,
+            p.x * p.x,
+            p.x * p.y,
+            p.x * p.z,
+            p.y * p.y,
+            p.y * p.z,
+            p.z * p.z,
+        ];
+        let row = |coefficients: &[f64; 10]| {
+            coefficients
+                .iter()
+                .zip(terms.iter())
+                .map(|(c, t)| c * t)
+                .sum::<f64>()
+        };
+        Vector3::new(
+            row(&self.coefficients[0]),
+            row(&self.coefficients[1]),
+            row(&self.coefficients[2]),
+        )
+    }
+}
+
+/// A registry entry for a map: its own parameters (name, default and
+/// range), plus `scale` for the view
+fn map<M, B>(
+    name: &str,
+    description: &str,
+    center: [f64; 3],
+    scale: f64,
+    params: &[(&str, f64, f64, f64)],
+    make: B,
+) -> Model
+where
+    M: IteratedMap + 'static,
+    B: Fn(&Params) -> M + Send + Sync + 'static,
+{
+    let center = Vector3::from(center);
+    let mut model = Model::new(
+        name,
+        description,
+        move |params, position, _method, chaos| {
+            let scale = params.get("scale");
+            let mut particle = Iterated {
+                map: make(params),
+                center,
+                scale,
+                point: center + position / scale,
+            };
+            for _ in 0..WARMUP_ITERATIONS {
+                particle.iterate(chaos);
+            }
+            Box::new(particle)
+        },
+    )
+    .discrete();
+    for (name, default, min, max) in params.iter() {
+        model = model.param(name, *default, *min..=*max);
+    }
+    model.param("scale", scale, 0.001..=1000.0)
+}
+
+/// The iterated maps, registered along with the other built in models
+pub fn models() -> Vec<Model> {
+    let abcd = |a, b, c, d| {
+        vec![
+            ("a", a, -10.0, 10.0),
+            ("b", b, -10.0, 10.0),
+            ("c", c, -10.0, 10.0),
+            ("d", d, -10.0, 10.0),
+        ]
+    };
+
+    // the Hénon map, until the scene says otherwise
+    let mut polynomial = Vec::new();
+    for (axis, defaults) in [
+        ("x", [1.0, 0.0, 1.0, 0.0, -1.4, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        ("y", [0.0, 0.3, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        ("z", [0.0; 10]),
+    ]
+    .iter()
+    {
+        for (monomial, default) in MONOMIALS.iter().zip(defaults.iter()) {
+            polynomial.push((format!("{}_{}", axis, monomial), *default));
+        }
+    }
+    let polynomial_params = polynomial
+        .iter()
+        .map(|(name, default)| (name.as_str(), *default, -10.0, 10.0))
+        .collect::<Vec<_>>();
+
+    vec![
+        map(
+            "clifford",
+            "Clifford attractor, an iterated map in the XY plane",
+            [0.0, 0.0, 0.0],
+            1.5,
+            &abcd(-1.4, 1.6, 1.0, 0.7),
+            |p| Clifford {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+                d: p.get("d"),
+            },
+        ),
+        map(
+            "de_jong",
+            "Peter de Jong attractor, an iterated map in the XY plane",
+            [0.0, 0.0, 0.0],
+            1.5,
+            &abcd(1.4, -2.3, 2.4, -2.1),
+            |p| DeJong {
+                a: p.get("a"),
+                b: p.get("b"),
+                c: p.get("c"),
+                d: p.get("d"),
+            },
+        ),
+        map(
+            "henon_3d",
+            "Generalized Hénon map in three dimensions",
+            [0.0, 0.0, 0.0],
+            1.5,
+            &[("a", 1.76, -10.0, 10.0), ("b", 0.1, -10.0, 10.0)],
+            |p| Henon3d {
+                a: p.get("a"),
+                b: p.get("b"),
+            },
+        ),
+        map(
+            "lozi",
+            "Lozi map, an iterated map in the XY plane",
+            [0.0, 0.0, 0.0],
+            2.5,
+            &[("a", 1.7, -10.0, 10.0), ("b", 0.5, -10.0, 10.0)],
+            |p| Lozi {
+                a: p.get("a"),
+                b: p.get("b"),
+            },
+        ),
+        map(
+            "ikeda",
+            "Ikeda map, an iterated map in the XY plane",
+            [0.7, -0.7, 0.0],
+            2.5,
+            &[("u", 0.9, 0.0, 1.0)],
+            |p| Ikeda { u: p.get("u") },
+        ),
+        map(
+            "polynomial_map",
+            "Quadratic map with coefficients from the scene, e.g. x_xy is the xy term of the new x",
+            [0.0, 0.0, 0.0],
+            2.5,
+            &polynomial_params,
+            |p| {
+                let mut coefficients = [[0.0; 10]; 3];
+                for (row, axis) in coefficients.iter_mut().zip(["x", "y", "z"].iter()) {
+                    for (c, monomial) in row.iter_mut().zip(MONOMIALS.iter()) {
+                        *c = p.get(&format!("{}_{}", axis, monomial));
+                    }
+                }
+                Polynomial { coefficients }
+            },
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::Method;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn maps_stay_in_view() {
+        let mut chaos = Chaos::from_seed(8);
+        for model in models() {
+            assert!(model.discrete);
+            for _ in 0..10 {
+                // Ikeda's attractor shares the plane with a stable fixed
+                // point, so starts have to be near the attractor
+                let start = chaos.random_position_in_cube(1.0);
+                let mut particle = model.build(&BTreeMap::new(), start, Method::Rk4, &mut chaos);
+                for _ in 0..10_000 {
+                    particle.step(1.0 / 60.0, &mut chaos);
+                    let distance = particle.get_position().magnitude();
+                    assert!(distance < 8.0, "{} jumped {} away", model.name, distance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn polynomial_defaults_to_henon() {
+        let henon = |p: Vector3<f64>| Vector3::new(1.0 - 1.4 * p.x * p.x + p.y, 0.3 * p.x, 0.0);
+        let model = crate::registry::get("polynomial_map").unwrap();
+        let mut chaos = Chaos::from_seed(1);
+        let mut particle = model.build(
+            &BTreeMap::new(),
+            Vector3::new(0.0, 0.0, 0.0),
+            Method::Rk4,
+            &mut chaos,
+        );
+        let scale = model.default_value("scale");
+        let before = particle.get_position() / scale;
+        particle.step(1.0, &mut chaos);
+        let after = particle.get_position() / scale;
+        assert!((after - henon(before)).magnitude() < 1e-5);
+    }
+}
@@ -0,0 +1,446 @@
+use cgmath::{InnerSpace, Vector3, Zero};
+use rayon::prelude::*;
+use std::ops::Range;
+
+use crate::interaction::{Body, Interaction};
+use crate::registry::Model;
+use crate::simulation::MIN_PARTICLES_PER_TASK;
+
+// bodies in a leaf of the octree, these are summed over directly
+const LEAF_SIZE: usize = 8;
+// cells aren't split any further than this, so that bodies sitting on top
+// of each other end up in one leaf instead of splitting forever
+const MIN_CELL_SIZE: f64 = 1e-5;
+
+/// Softened Newtonian gravity between every pair of bodies
+///
+/// Stepped with velocity Verlet (kick-drift-kick leapfrog), which is
+/// symplectic: energy errors stay bounded instead of piling up, so orbits
+/// don't spiral in or out over long runs.  With `theta` above 0 the forces
+/// are approximated with a Barnes-Hut octree, O(N log N) instead of O(N^2).
+pub struct Gravity {
+    pub g: f64,
+    // Plummer softening length, keeps close encounters from blowing up
+    pub softening: f64,
+    // Barnes-Hut opening angle, 0 sums over every pair
+    pub theta: f64,
+    accelerations: Vec<Vector3<f64>>,
+    initial: Conserved,
+}
+
+/// Energy and momentum of a set of bodies
+#[derive(Debug, Clone, Copy)]
+struct Conserved {
+    kinetic: f64,
+    potential: f64,
+    momentum: Vector3<f64>,
+    mass: f64,
+}
+
+impl Conserved {
+    fn energy(&self) -> f64 {
+        self.kinetic + self.potential
+    }
+
+    // drifts are measured relative to these, rather than to the totals,
+    // which can be close to 0
+    fn energy_scale(&self) -> f64 {
+        self.kinetic + self.potential.abs()
+    }
+
+    fn momentum_scale(&self) -> f64 {
+        (2.0 * self.mass * self.energy_scale()).sqrt()
+    }
+}
+
+impl Gravity {
+    pub fn new(g: f64, softening: f64, theta: f64, bodies: &[Body]) -> Self {
+        let mut gravity = Self {
+            g,
+            softening,
+            theta,
+            accelerations: Vec::new(),
+            initial: Conserved {
+                kinetic: 0.0,
+                potential: 0.0,
+                momentum: Vector3::zero(),
+                mass: 0.0,
+            },
+        };
+        gravity.accelerations = gravity.fields(bodies).into_iter().map(|f| f.0).collect();
+        gravity.initial = gravity.conserved(bodies);
+        gravity
+    }
+
+    /// Acceleration and potential (per unit mass) at every body
+    fn fields(&self, bodies: &[Body]) -> Vec<(Vector3<f64>, f64)> {
+        let tree = if self.theta > 0.0 {
+            Some(Octree::new(bodies))
+        } else {
+            None
+        };
+        (0..bodies.len())
+            .into_par_iter()
+            .with_min_len(MIN_PARTICLES_PER_TASK)
+            .map(|i| match &tree {
+                Some(tree) => tree.field(self, bodies, i),
+                None => {
+                    let mut field = (Vector3::zero(), 0.0);
+                    for (j, other) in bodies.iter().enumerate() {
+                        if j != i {
+                            self.pull(&mut field, bodies[i].position, other.position, other.mass);
+                        }
+                    }
+                    field
+                }
+            })
+            .collect()
+    }
+
+    /// Add the pull of `mass` at `to` on a body at `from`
+    fn pull(
+        &self,
+        field: &mut (Vector3<f64>, f64),
+        from: Vector3<f64>,
+        to: Vector3<f64>,
+        mass: f64,
+    ) {
+        let d = to - from;
+        let inv_r = 1.0 / (d.magnitude2() + self.softening * self.softening).sqrt();
+        field.0 += self.g * mass * inv_r * inv_r * inv_r * d;
+        field.1 -= self.g * mass * inv_r;
+    }
+
+    fn conserved(&self, bodies: &[Body]) -> Conserved {
+        let fields = self.fields(bodies);
+        let mut conserved = Conserved {
+            kinetic: 0.0,
+            potential: 0.0,
+            momentum: Vector3::zero(),
+            mass: 0.0,
+        };
+        for (body, (_, potential)) in bodies.iter().zip(fields) {
+            let mass = body.mass;
+            let velocity = body.velocity;
+            conserved.kinetic += 0.5 * mass * velocity.magnitude2();
+            // every pair is counted twice
+            conserved.potential += 0.5 * mass * potential;
+            conserved.momentum += mass * velocity;
+            conserved.mass += mass;
+        }
+        conserved
+    }
+}
+
+impl Interaction for Gravity {
+    fn step(&mut self, bodies: &mut [Body], dt: f64) {
+        let kick = |bodies: &mut [Body], accelerations: &[Vector3<f64>]| {
+            bodies
+                .par_iter_mut()
+                .zip(accelerations.par_iter())
+                .with_min_len(MIN_PARTICLES_PER_TASK)
+                .for_each(|(body, a)| body.velocity += 0.5 * dt * a);
+        };
+        if self.accelerations.len() != bodies.len() {
+            self.accelerations = self.fields(bodies).into_iter().map(|f| f.0).collect();
+        }
+        kick(bodies, &self.accelerations);
+        for body in bodies.iter_mut() {
+            body.position += dt * body.velocity;
+        }
+        self.accelerations = self.fields(bodies).into_iter().map(|f| f.0).collect();
+        kick(bodies, &self.accelerations);
+    }
+
+    fn report(&self, bodies: &[Body]) -> Option<String> {
+        let now = self.conserved(bodies);
+        let energy_drift = (now.energy() - self.initial.energy()) / self.initial.energy_scale();
+        let momentum_drift =
+            (now.momentum - self.initial.momentum).magnitude() / self.initial.momentum_scale();
+        Some(format!(
+            "Energy drift {:+.2e}, momentum drift {:.2e}",
+            energy_drift, momentum_drift
+        ))
+    }
+}
+
+/// Barnes-Hut octree over the bodies, rebuilt every step
+struct Octree {
+    nodes: Vec<Node>,
+    // body indices, every node's bodies are a range of these
+    order: Vec<usize>,
+}
+
+struct Node {
+    mass: f64,
+    center_of_mass: Vector3<f64>,
+    // width of the cell
+    size: f64,
+    bodies: Range<usize>,
+    // empty for leaves
+    children: Vec<usize>,
+}
+
+impl Octree {
+    fn new(bodies: &[Body]) -> Self {
+        let mut lo = Vector3::new(f64::MAX, f64::MAX, f64::MAX);
+        let mut hi = -lo;
+        for body in bodies.iter() {
+            let p = body.position;
+            lo = Vector3::new(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z));
+            hi = Vector3::new(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z));
+        }
+        let d = hi - lo;
+        let half_width = 0.5 * d.x.max(d.y).max(d.z).max(MIN_CELL_SIZE);
+
+        let mut order = (0..bodies.len()).collect::<Vec<_>>();
+        let mut tree = Self {
+            nodes: Vec::new(),
+            order: Vec::new(),
+        };
+        tree.build(bodies, &mut order, 0, 0.5 * (lo + hi), half_width);
+        tree.order = order;
+        tree
+    }
+
+    /// Add the node for the bodies in `order` (which start at `offset` in
+    /// the final order), returns its index
+    fn build(
+        &mut self,
+        bodies: &[Body],
+        order: &mut [usize],
+        offset: usize,
+        center: Vector3<f64>,
+        half_width: f64,
+    ) -> usize {
+        let mut mass = 0.0;
+        let mut weighted = Vector3::zero();
+        for &i in order.iter() {
+            mass += bodies[i].mass;
+            weighted += bodies[i].mass * bodies[i].position;
+        }
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            mass,
+            center_of_mass: if mass > 0.0 { weighted / mass } else { center },
+            size: 2.0 * half_width,
+            bodies: offset..offset + order.len(),
+            children: Vec::new(),
+        });
+        if order.len() <= LEAF_SIZE || half_width < MIN_CELL_SIZE {
+            return index;
+        }
+
+        let octant = |i: &usize| {
+            let p = bodies[*i].position;
+            (p.x >= center.x) as usize
+                | ((p.y >= center.y) as usize) << 1
+                | ((p.z >= center.z) as usize) << 2
+        };
+        order.sort_unstable_by_key(octant);
+        let mut children = Vec::new();
+        let mut start = 0;
+        for ix in 0..8 {
+            let end = start
+                + order[start..]
+                    .iter()
+                    .take_while(|i| octant(i) == ix)
+                    .count();
+            if end > start {
+                let sign = |bit: usize| if ix & bit == 0 { -0.5 } else { 0.5 };
+                let child_center = center + half_width * Vector3::new(sign(1), sign(2), sign(4));
+                children.push(self.build(
+                    bodies,
+                    &mut order[start..end],
+                    offset + start,
+                    child_center,
+                    0.5 * half_width,
+                ));
+            }
+            start = end;
+        }
+        self.nodes[index].children = children;
+        index
+    }
+
+    /// Acceleration and potential at body `i`, cells that look smaller
+    /// than `theta` from there are treated as a single body
+    fn field(&self, gravity: &Gravity, bodies: &[Body], i: usize) -> (Vector3<f64>, f64) {
+        let position = bodies[i].position;
+        let theta2 = gravity.theta * gravity.theta;
+        let mut field = (Vector3::zero(), 0.0);
+        let mut stack = vec![0];
+        while let Some(ix) = stack.pop() {
+            let node = &self.nodes[ix];
+            if node.children.is_empty() {
+                for &j in self.order[node.bodies.clone()].iter() {
+                    if j != i {
+                        gravity.pull(&mut field, position, bodies[j].position, bodies[j].mass);
+                    }
+                }
+            } else if node.size * node.size < theta2 * (node.center_of_mass - position).magnitude2()
+            {
+                gravity.pull(&mut field, position, node.center_of_mass, node.mass);
+            } else {
+                stack.extend(node.children.iter());
+            }
+        }
+        field
+    }
+}
+
+/// Give every body the velocity of a circular orbit around the center of
+/// mass of its group, about the group's z axis, times `spin`.  Each orbit
+/// only feels the mass of the group that is closer in.
+pub fn spin_up(bodies: &mut [Body], g: f64, softening: f64, spin: f64) {
+    if spin == 0.0 {
+        return;
+    }
+    let n_groups = bodies.iter().map(|b| b.group + 1).max().unwrap_or(0);
+    for group in 0..n_groups {
+        let mut members = (0..bodies.len())
+            .filter(|&i| bodies[i].group == group)
+            .collect::<Vec<_>>();
+        let mass: f64 = members.iter().map(|&i| bodies[i].mass).sum();
+        if mass <= 0.0 {
+            continue;
+        }
+        let center = members
+            .iter()
+            .map(|&i| bodies[i].mass * bodies[i].position)
+            .sum::<Vector3<f64>>()
+            / mass;
+        let distance = |i: usize| (bodies[i].position - center).magnitude();
+        members.sort_by(|&a, &b| distance(a).partial_cmp(&distance(b)).unwrap());
+
+        let mut enclosed = 0.0;
+        for &i in members.iter() {
+            let offset = bodies[i].position - center;
+            let r2 = offset.magnitude2();
+            let tangent = Vector3::new(-offset.y, offset.x, 0.0);
+            if tangent.magnitude2() > 0.0 {
+                let soft = (r2 + softening * softening).sqrt();
+                let speed = (g * enclosed * r2 / (soft * soft * soft)).sqrt();
+                bodies[i].velocity += spin * speed * tangent.normalize();
+            }
+            enclosed += bodies[i].mass;
+        }
+    }
+}
+
+/// The registry entry
+pub fn model() -> Model {
+    Model::interacting(
+        "nbody",
+        "Gravity between all particles, stepped with leapfrog (the integrator setting is not used)",
+        |params, bodies, _chaos| {
+            let g = params.get("g");
+            let softening = params.get("softening");
+            spin_up(bodies, g, softening, params.get("spin"));
+            Box::new(Gravity::new(g, softening, params.get("theta"), bodies))
+        },
+    )
+    .param("g", 0.01, 0.0..=1000.0)
+    .param("softening", 0.05, 0.0..=10.0)
+    .param("theta", 0.0, 0.0..=2.0)
+    .param("spin", 0.0, -2.0..=2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_util::Chaos;
+
+    fn random_bodies(n: usize) -> Vec<Body> {
+        let mut chaos = Chaos::from_seed(5);
+        (0..n)
+            .map(|_| Body {
+                velocity: chaos.random_position_in_cube(0.5),
+                mass: 0.5 + chaos.unit_noise(),
+                ..Body::at(chaos.random_position_in_ball(4.0))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn figure_eight_comes_back_around() {
+        // Chenciner and Montgomery's three body choreography, G = m = 1
+        let x = Vector3::new(0.970_004_4, -0.243_087_5, 0.0);
+        let v = Vector3::new(-0.932_407_4, -0.864_731_5, 0.0);
+        let start = vec![
+            Body {
+                velocity: -0.5 * v,
+                ..Body::at(x)
+            },
+            Body {
+                velocity: -0.5 * v,
+                ..Body::at(-x)
+            },
+            Body {
+                velocity: v,
+                ..Body::at(Vector3::zero())
+            },
+        ];
+        let mut bodies = start.clone();
+        let mut gravity = Gravity::new(1.0, 0.0, 0.0, &bodies);
+        let period = 6.325_914;
+        let n_steps = 6000;
+        for _ in 0..n_steps {
+            gravity.step(&mut bodies, period / n_steps as f64);
+        }
+        for (body, start) in bodies.iter().zip(start.iter()) {
+            assert!((body.position - start.position).magnitude() < 1e-2);
+        }
+        let now = gravity.conserved(&bodies);
+        let drift = (now.energy() - gravity.initial.energy()).abs();
+        assert!(drift / gravity.initial.energy_scale() < 1e-4);
+    }
+
+    #[test]
+    fn direct_sum_conserves_momentum() {
+        let mut bodies = random_bodies(50);
+        let mut gravity = Gravity::new(1.0, 0.1, 0.0, &bodies);
+        for _ in 0..200 {
+            gravity.step(&mut bodies, 0.01);
+        }
+        let now = gravity.conserved(&bodies);
+        let drift = (now.momentum - gravity.initial.momentum).magnitude();
+        assert!(drift / gravity.initial.momentum_scale() < 1e-5);
+    }
+
+    #[test]
+    fn barnes_hut_is_close_to_the_direct_sum() {
+        let bodies = random_bodies(500);
+        let direct = Gravity::new(1.0, 0.05, 0.0, &bodies);
+        let tree = Gravity::new(1.0, 0.05, 0.5, &bodies);
+        let errors = direct
+            .accelerations
+            .iter()
+            .zip(tree.accelerations.iter())
+            .map(|(a, b)| (a - b).magnitude() / a.magnitude())
+            .collect::<Vec<_>>();
+        let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+        let worst = errors.iter().cloned().fold(0.0, f64::max);
+        assert!(mean < 0.01 && worst < 0.1);
+        let energy = (direct.initial.potential - tree.initial.potential).abs();
+        assert!(energy / direct.initial.potential.abs() < 1e-2);
+    }
+
+    #[test]
+    fn spin_goes_around_each_group() {
+        let mut bodies = random_bodies(100);
+        for (ix, body) in bodies.iter_mut().enumerate() {
+            body.velocity = Vector3::zero();
+            body.group = ix % 2;
+        }
+        spin_up(&mut bodies, 1.0, 0.05, 1.0);
+        for group in 0..2 {
+            let angular = bodies
+                .iter()
+                .filter(|b| b.group == group)
+                .map(|b| b.mass * b.position.cross(b.velocity).z)
+                .sum::<f64>();
+            assert!(angular > 0.0);
+        }
+    }
+}
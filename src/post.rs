@@ -1,36 +1,96 @@
-use bitflags::bitflags;
 use wgpu::util::DeviceExt;
 
 use crate::model::Vertex;
-use crate::quad;
+use crate::quad::{self, DrawQuad};
+use crate::scene;
 use crate::texture;
 use crate::util;
 
-bitflags! {
-  struct Flags: i32 {
-    const NONE = 0b0000000;
-    const ENABLED = 0b00000001;
-    const HORIZONTAL = 0b00000010;
-  }
-}
+/// Format of the scene and bloom textures, everything before tone mapping
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// filter modes of post.wgsl
+const MODE_BRIGHT_PASS: i32 = 0;
+const MODE_DOWNSAMPLE: i32 = 1;
+const MODE_BLUR_HORIZONTAL: i32 = 2;
+const MODE_BLUR_VERTICAL: i32 = 3;
+const MODE_TONEMAP: i32 = 4;
 
 #[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
 struct Uniforms {
-    flags: i32,
+    mode: i32,
+    threshold: f32,
+    knee: f32,
+    radius: f32,
+    weight: f32,
+    tonemap: i32,
+    exposure: f32,
+    _padding: f32,
 }
 
+#[derive(Clone, Copy)]
+enum Target {
+    Level(usize),
+    Scratch(usize),
+    Hdr,
+    Output,
+}
+
+#[derive(Clone, Copy)]
+enum Pipeline {
+    Filter,
+    Combine,
+    Tonemap,
+}
+
+// one full screen draw of the post-processing chain
+struct Pass {
+    label: String,
+    pipeline: Pipeline,
+    target: Target,
+    textures: wgpu::BindGroup,
+    uniforms: wgpu::BindGroup,
+    // the bind group refers to this
+    _uniform_buffer: wgpu::Buffer,
+}
+
+/// Sizes of the bloom levels, each half the size of the one before
+fn level_sizes(
+    size: winit::dpi::PhysicalSize<u32>,
+    max_levels: u32,
+) -> Vec<winit::dpi::PhysicalSize<u32>> {
+    let mut sizes = Vec::new();
+    let (mut width, mut height) = (size.width / 2, size.height / 2);
+    while sizes.len() < max_levels.max(1) as usize {
+        sizes.push(winit::dpi::PhysicalSize::new(width.max(1), height.max(1)));
+        if width <= 1 || height <= 1 {
+            break;
+        }
+        width /= 2;
+        height /= 2;
+    }
+    sizes
+}
+
+/// Post-processing: bloom, the final composite and tone mapping
+///
+/// The scene is drawn into `scene_texture`.  For bloom, a bright pass keeps
+/// what is above the threshold at half size, that is downsampled into a
+/// chain of levels that are each blurred with a separable Gaussian, then the
+/// levels are upsampled and added back up the chain.  The composite adds
+/// the result to the scene in `hdr_texture`, which is tone mapped to the
+/// output.  Everything up to the tone mapping is `HDR_FORMAT`.
 pub struct Post {
     pub fullscreen_quad: quad::Quad,
-    pub ping_texture: texture::Texture,
-    pub pong_texture: texture::Texture,
-    pub ping_texture_bind_group: wgpu::BindGroup,
-    pub pong_texture_bind_group: wgpu::BindGroup,
-    pub ping_buffer: wgpu::Buffer,
-    pub pong_buffer: wgpu::Buffer,
-    pub ping_uniform_bind_group: wgpu::BindGroup,
-    pub pong_uniform_bind_group: wgpu::BindGroup,
-    pub render_pipeline: wgpu::RenderPipeline,
+    pub scene_texture: texture::Texture,
+    pub hdr_texture: texture::Texture,
+    levels: Vec<texture::Texture>,
+    scratch: Vec<texture::Texture>,
+    filter_pipeline: wgpu::RenderPipeline,
+    combine_pipeline: wgpu::RenderPipeline,
+    tonemap_pipeline: wgpu::RenderPipeline,
+    passes: Vec<Pass>,
 }
 
 impl Post {
@@ -38,109 +98,81 @@ impl Post {
         device: &wgpu::Device,
         size: winit::dpi::PhysicalSize<u32>,
         format: wgpu::TextureFormat,
+        config: &scene::PostConfig,
     ) -> Self {
-        let fullscreen_quad = quad::Quad::make_fullscreen_quad(&device).unwrap();
+        let fullscreen_quad = quad::Quad::make_fullscreen_quad(device).unwrap();
 
-        let ping_texture = texture::Texture::create_target_texture(&device, size, format);
-        let pong_texture = texture::Texture::create_target_texture(&device, size, format);
+        let scene_texture = texture::Texture::create_target_texture(device, size, HDR_FORMAT);
+        let hdr_texture = texture::Texture::create_target_texture(device, size, HDR_FORMAT);
+        let sizes = level_sizes(size, config.levels);
+        let levels = sizes
+            .iter()
+            .map(|size| texture::Texture::create_target_texture(device, *size, HDR_FORMAT))
+            .collect::<Vec<_>>();
+        let scratch = sizes
+            .iter()
+            .map(|size| texture::Texture::create_target_texture(device, *size, HDR_FORMAT))
+            .collect::<Vec<_>>();
 
-        let texture_bind_group_layout = device.create_bind_group_layout(
-            &texture::Texture::bind_group_layout_descriptor(Some("texture bind group layout")),
+        let filter_layout = device.create_bind_group_layout(
+            &texture::Texture::bind_group_layout_descriptor(Some("post filter bind group layout")),
         );
-
-        let ping_texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&ping_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&ping_texture.sampler),
-                },
-            ],
-            label: Some("ping_texture_bind_group"),
-        });
-        let pong_texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Sampler {
+                comparison: false,
+                filtering: true,
+            },
+            count: None,
+        };
+        let combine_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&pong_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&pong_texture.sampler),
-                },
+                texture_entry(0),
+                sampler_entry(1),
+                texture_entry(2),
+                sampler_entry(3),
             ],
-            label: Some("pong_texture_bind_group"),
-        });
-
-        let base_flags = Flags::NONE;
-
-        let ping_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Ping Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[Uniforms {
-                flags: base_flags.bits(),
-            }]),
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("post combine bind group layout"),
         });
 
-        let pong_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Pong Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[Uniforms {
-                flags: (base_flags | Flags::HORIZONTAL).bits(),
-            }]),
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-        });
-
-        let uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-                label: Some("uniform_bind_group_layout"),
-            });
-
-        let ping_uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
-                resource: ping_buffer.as_entire_binding(),
-            }],
-            label: Some("ping_uniform_bind_group"),
-        });
-
-        let pong_uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: pong_buffer.as_entire_binding(),
+                visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
             }],
-            label: Some("pong_uniform_bind_group"),
+            label: Some("post uniform bind group layout"),
         });
 
-        let render_pipeline = {
+        let make_pipeline = |label, texture_layout, source: &'static str, format| {
             let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Post Pipeline Layout"),
-                bind_group_layouts: &[&texture_bind_group_layout, &uniform_bind_group_layout],
+                label: Some(label),
+                bind_group_layouts: &[texture_layout, &uniform_layout],
                 push_constant_ranges: &[],
             });
             let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Post Shader"),
+                label: Some(label),
                 flags: wgpu::ShaderFlags::all(),
-                source: wgpu::ShaderSource::Wgsl(include_str!("post.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
             };
             util::create_render_pipeline(
-                &device,
+                device,
                 &layout,
                 format,
                 None,
@@ -148,18 +180,230 @@ impl Post {
                 shader,
             )
         };
+        let filter_pipeline = make_pipeline(
+            "Post Filter Pipeline",
+            &filter_layout,
+            include_str!("post.wgsl"),
+            HDR_FORMAT,
+        );
+        let combine_pipeline = make_pipeline(
+            "Post Combine Pipeline",
+            &combine_layout,
+            include_str!("post_combine.wgsl"),
+            HDR_FORMAT,
+        );
+        let tonemap_pipeline = make_pipeline(
+            "Post Tonemap Pipeline",
+            &filter_layout,
+            include_str!("post.wgsl"),
+            format,
+        );
+
+        let make_pass =
+            |label: String, target: Target, sources: &[&texture::Texture], uniforms: Uniforms| {
+                let (layout, pipeline) = match (sources.len(), target) {
+                    (1, Target::Output) => (&filter_layout, Pipeline::Tonemap),
+                    (1, _) => (&filter_layout, Pipeline::Filter),
+                    _ => (&combine_layout, Pipeline::Combine),
+                };
+                let entries = sources
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(ix, source)| {
+                        vec![
+                            wgpu::BindGroupEntry {
+                                binding: 2 * ix as u32,
+                                resource: wgpu::BindingResource::TextureView(&source.view),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2 * ix as u32 + 1,
+                                resource: wgpu::BindingResource::Sampler(&source.sampler),
+                            },
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                let textures = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout,
+                    entries: &entries,
+                    label: Some(&label),
+                });
+                let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&label),
+                    contents: bytemuck::cast_slice(&[uniforms]),
+                    usage: wgpu::BufferUsage::UNIFORM,
+                });
+                let uniforms = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &uniform_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buffer.as_entire_binding(),
+                    }],
+                    label: Some(&label),
+                });
+                Pass {
+                    label,
+                    pipeline,
+                    target,
+                    textures,
+                    uniforms,
+                    _uniform_buffer: uniform_buffer,
+                }
+            };
+
+        let filter = |mode| Uniforms {
+            mode,
+            threshold: config.threshold,
+            // start fading in at half the threshold
+            knee: 0.5 * config.threshold,
+            radius: config.radius,
+            tonemap: config.tonemap as i32,
+            exposure: 2f32.powf(config.exposure),
+            ..Default::default()
+        };
+
+        let mut passes = Vec::new();
+        let n_levels = levels.len();
+        if config.bloom {
+            for ix in 0..n_levels {
+                let (source, mode) = if ix == 0 {
+                    (&scene_texture, MODE_BRIGHT_PASS)
+                } else {
+                    (&levels[ix - 1], MODE_DOWNSAMPLE)
+                };
+                passes.push(make_pass(
+                    format!("Bloom Downsample {}", ix),
+                    Target::Level(ix),
+                    &[source],
+                    filter(mode),
+                ));
+                passes.push(make_pass(
+                    format!("Bloom Blur Horizontal {}", ix),
+                    Target::Scratch(ix),
+                    &[&levels[ix]],
+                    filter(MODE_BLUR_HORIZONTAL),
+                ));
+                passes.push(make_pass(
+                    format!("Bloom Blur Vertical {}", ix),
+                    Target::Level(ix),
+                    &[&scratch[ix]],
+                    filter(MODE_BLUR_VERTICAL),
+                ));
+            }
+
+            // accumulate from the smallest level up into the scratch textures
+            for ix in (0..n_levels - 1).rev() {
+                let smaller = if ix == n_levels - 2 {
+                    &levels[ix + 1]
+                } else {
+                    &scratch[ix + 1]
+                };
+                passes.push(make_pass(
+                    format!("Bloom Upsample {}", ix),
+                    Target::Scratch(ix),
+                    &[&levels[ix], smaller],
+                    Uniforms {
+                        weight: 1.0,
+                        ..filter(0)
+                    },
+                ));
+            }
+        }
+
+        let bloom = if n_levels == 1 {
+            &levels[0]
+        } else {
+            &scratch[0]
+        };
+        let weight = if config.bloom {
+            // every level adds about as much light again
+            config.intensity / n_levels as f32
+        } else {
+            0.0
+        };
+        passes.push(make_pass(
+            "Post Composite".to_string(),
+            Target::Hdr,
+            &[&scene_texture, bloom],
+            Uniforms {
+                weight,
+                ..filter(0)
+            },
+        ));
+        passes.push(make_pass(
+            "Post Tonemap".to_string(),
+            Target::Output,
+            &[&hdr_texture],
+            filter(MODE_TONEMAP),
+        ));
 
         Self {
             fullscreen_quad,
-            ping_texture,
-            pong_texture,
-            ping_texture_bind_group,
-            pong_texture_bind_group,
-            ping_buffer,
-            pong_buffer,
-            ping_uniform_bind_group,
-            pong_uniform_bind_group,
-            render_pipeline,
+            scene_texture,
+            hdr_texture,
+            levels,
+            scratch,
+            filter_pipeline,
+            combine_pipeline,
+            tonemap_pipeline,
+            passes,
+        }
+    }
+
+    /// Run the post-processing chain on `scene_texture`, drawing the
+    /// result to `output`
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        for pass in self.passes.iter() {
+            let view = match pass.target {
+                Target::Level(ix) => &self.levels[ix].view,
+                Target::Scratch(ix) => &self.scratch[ix].view,
+                Target::Hdr => &self.hdr_texture.view,
+                Target::Output => output,
+            };
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(&pass.label),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.0,
+                            g: 0.0,
+                            b: 0.0,
+                            a: 1.0,
+                        }),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(match pass.pipeline {
+                Pipeline::Filter => &self.filter_pipeline,
+                Pipeline::Combine => &self.combine_pipeline,
+                Pipeline::Tonemap => &self.tonemap_pipeline,
+            });
+            render_pass.set_bind_group(0, &pass.textures, &[]);
+            render_pass.set_bind_group(1, &pass.uniforms, &[]);
+            render_pass.draw_quad(&self.fullscreen_quad);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_halve_down_to_one_pixel() {
+        let sizes = level_sizes(winit::dpi::PhysicalSize::new(1920, 1080), 5);
+        let widths = sizes.iter().map(|s| s.width).collect::<Vec<_>>();
+        assert_eq!(widths, vec![960, 480, 240, 120, 60]);
+        assert_eq!(sizes[4].height, 33);
+
+        let sizes = level_sizes(winit::dpi::PhysicalSize::new(16, 4), 10);
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[1], winit::dpi::PhysicalSize::new(4, 1));
+
+        assert_eq!(level_sizes(winit::dpi::PhysicalSize::new(1, 1), 0).len(), 1);
+    }
+}
@@ -1,7 +1,20 @@
+// Bloom filters, `mode` picks one of:
+// 0: bright pass, keeps what is brighter than the threshold (and downsamples)
+// 1: downsample to half size
+// 2: horizontal Gaussian blur
+// 3: vertical Gaussian blur
+// 4: tone map by `tonemap`, 0: clamp, 1: Reinhard, 2: ACES filmic
+
 // Vertex shader
 [[block]]
 struct Uniforms {
-    flags: i32;
+    mode: i32;
+    threshold: f32;
+    knee: f32;
+    radius: f32;
+    weight: f32;
+    tonemap: i32;
+    exposure: f32;
 };
 [[group(1), binding(0)]]
 var<uniform> uniforms: Uniforms;
@@ -37,24 +50,53 @@ var s_diffuse: sampler;
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     let dims = textureDimensions(t_diffuse);
-    let dx = 2.0 / f32(dims.x);
-    let dy = 2.0 / f32(dims.y);
+    let dx = 1.0 / f32(dims.x);
+    let dy = 1.0 / f32(dims.y);
+    let uv = in.tex_coords;
 
-    let enabled = (uniforms.flags & 1) > 0;
-    let horizontal = (uniforms.flags & 2) > 0;
+    var result: vec3<f32>;
+    if (uniforms.mode == 4) {
+        let color = uniforms.exposure * textureSample(t_diffuse, s_diffuse, uv).rgb;
+        if (uniforms.tonemap == 1) {
+            result = color / (vec3<f32>(1.0, 1.0, 1.0) + color);
+        } elseif (uniforms.tonemap == 2) {
+            // Narkowicz's fit of the ACES filmic curve
+            let a = 2.51;
+            let b = 0.03;
+            let c = 2.43;
+            let d = 0.59;
+            let e = 0.14;
+            result = (color * (a * color + b)) / (color * (c * color + d) + e);
+        } else {
+            result = color;
+        }
+        result = clamp(result, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(1.0, 1.0, 1.0));
+    } elseif (uniforms.mode <= 1) {
+        // four bilinear taps cover a 4x4 block of source texels
+        result = 0.25 * textureSample(t_diffuse, s_diffuse, uv + vec2<f32>(-dx, -dy)).rgb;
+        result = result + 0.25 * textureSample(t_diffuse, s_diffuse, uv + vec2<f32>(dx, -dy)).rgb;
+        result = result + 0.25 * textureSample(t_diffuse, s_diffuse, uv + vec2<f32>(-dx, dy)).rgb;
+        result = result + 0.25 * textureSample(t_diffuse, s_diffuse, uv + vec2<f32>(dx, dy)).rgb;
 
-    var result: vec4<f32>;
-    if (!enabled) {
-        result = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+        if (uniforms.mode == 0) {
+            // soft threshold, fades in over `knee` below the threshold
+            let brightness = max(result.r, max(result.g, result.b));
+            let soft = clamp(brightness - uniforms.threshold + uniforms.knee, 0.0, 2.0 * uniforms.knee);
+            let curve = soft * soft / (4.0 * uniforms.knee + 0.00001);
+            let contribution = max(curve, brightness - uniforms.threshold) / max(brightness, 0.00001);
+            result = result * contribution;
+        }
     } else {
-        result = 0.3333 * textureSample(t_diffuse, s_diffuse, in.tex_coords);
-        if (horizontal) {
-            result = result + 0.3333 * textureSample(t_diffuse, s_diffuse, in.tex_coords - vec2<f32>(dx, 0.0));
-            result = result + 0.3333 * textureSample(t_diffuse, s_diffuse, in.tex_coords + vec2<f32>(dx, 0.0));
-        } else {
-            result = result + 0.3333 * textureSample(t_diffuse, s_diffuse, in.tex_coords - vec2<f32>(0.0, dy));
-            result = result + 0.3333 * textureSample(t_diffuse, s_diffuse, in.tex_coords + vec2<f32>(0.0, dy));
+        // 9 tap Gaussian using 5 bilinear taps, stretched by the radius
+        var offset: vec2<f32> = vec2<f32>(0.0, dy * uniforms.radius);
+        if (uniforms.mode == 2) {
+            offset = vec2<f32>(dx * uniforms.radius, 0.0);
         }
+        result = 0.2270270270 * textureSample(t_diffuse, s_diffuse, uv).rgb;
+        result = result + 0.3162162162 * textureSample(t_diffuse, s_diffuse, uv + 1.3846153846 * offset).rgb;
+        result = result + 0.3162162162 * textureSample(t_diffuse, s_diffuse, uv - 1.3846153846 * offset).rgb;
+        result = result + 0.0702702703 * textureSample(t_diffuse, s_diffuse, uv + 3.2307692308 * offset).rgb;
+        result = result + 0.0702702703 * textureSample(t_diffuse, s_diffuse, uv - 3.2307692308 * offset).rgb;
     }
-    return result;
-}
\ No newline at end of file
+    return vec4<f32>(result, 1.0);
+}
@@ -0,0 +1,70 @@
+// Adds an upsampled, weighted copy of a smaller texture to a base texture:
+// the upsample-and-add steps of the bloom and the final composite
+
+// Vertex shader
+[[block]]
+struct Uniforms {
+    mode: i32;
+    threshold: f32;
+    knee: f32;
+    radius: f32;
+    weight: f32;
+    tonemap: i32;
+    exposure: f32;
+};
+[[group(1), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.clip_position = vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+// Fragment shader
+
+[[group(0), binding(0)]]
+var t_base: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_base: sampler;
+[[group(0), binding(2)]]
+var t_add: texture_2d<f32>;
+[[group(0), binding(3)]]
+var s_add: sampler;
+
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let dims = textureDimensions(t_add);
+    let dx = uniforms.radius / f32(dims.x);
+    let dy = uniforms.radius / f32(dims.y);
+    let uv = in.tex_coords;
+
+    // 3x3 tent filter over the smaller texture
+    var added: vec3<f32> = 4.0 * textureSample(t_add, s_add, uv).rgb;
+    added = added + 2.0 * textureSample(t_add, s_add, uv + vec2<f32>(-dx, 0.0)).rgb;
+    added = added + 2.0 * textureSample(t_add, s_add, uv + vec2<f32>(dx, 0.0)).rgb;
+    added = added + 2.0 * textureSample(t_add, s_add, uv + vec2<f32>(0.0, -dy)).rgb;
+    added = added + 2.0 * textureSample(t_add, s_add, uv + vec2<f32>(0.0, dy)).rgb;
+    added = added + textureSample(t_add, s_add, uv + vec2<f32>(-dx, -dy)).rgb;
+    added = added + textureSample(t_add, s_add, uv + vec2<f32>(dx, -dy)).rgb;
+    added = added + textureSample(t_add, s_add, uv + vec2<f32>(-dx, dy)).rgb;
+    added = added + textureSample(t_add, s_add, uv + vec2<f32>(dx, dy)).rgb;
+
+    let base = textureSample(t_base, s_base, uv);
+    return vec4<f32>(base.rgb + uniforms.weight * added / 16.0, base.a);
+}
@@ -1,48 +1,210 @@
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
 
+// ChaCha8 rather than StdRng so that a seed reproduces the same stream
+// across rand versions
+#[derive(Clone)]
 pub struct Chaos {
-    rng: rand::rngs::ThreadRng,
-    uniform_dist: rand::distributions::Uniform<f32>,
+    seed: u64,
+    rng: ChaCha8Rng,
+    uniform_dist: rand::distributions::Uniform<f64>,
+    // Box-Muller makes Gaussian samples in pairs, this is the second one
+    spare_gaussian: Option<f64>,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chaos {
+    /// A new source with a randomly chosen seed, see `seed()`
     pub fn new() -> Self {
+        Self::from_seed(rand::thread_rng().gen())
+    }
+
+    pub fn from_seed(seed: u64) -> Self {
         Self {
-            rng: rand::thread_rng(),
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
             uniform_dist: rand::distributions::Uniform::new(0.0, 1.0),
+            spare_gaussian: None,
         }
     }
 
-    pub fn unit_noise(&mut self) -> f32 {
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Split off an independent stream, e.g. one per particle.  The forked
+    /// stream only depends on this stream's seed and how many times it has
+    /// been sampled / forked so far.
+    pub fn fork(&mut self) -> Chaos {
+        Self::from_seed(self.rng.next_u64())
+    }
+
+    pub fn unit_noise(&mut self) -> f64 {
         self.uniform_sample() - 0.5
     }
 
-    pub fn unit_radian_noise(&mut self) -> f32 {
-        2.0 * std::f32::consts::PI * self.uniform_sample()
+    pub fn unit_radian_noise(&mut self) -> f64 {
+        2.0 * std::f64::consts::PI * self.uniform_sample()
     }
 
     pub fn random_solid_color(&mut self) -> [f32; 4] {
         [
-            self.uniform_sample(),
-            self.uniform_sample(),
-            self.uniform_sample(),
+            self.uniform_sample() as f32,
+            self.uniform_sample() as f32,
+            self.uniform_sample() as f32,
             1.0,
         ]
     }
 
-    pub fn random_position_in_cube(&mut self, max: f32) -> cgmath::Vector3<f32> {
-        cgmath::Vector3::<f32> {
+    pub fn random_position_in_cube(&mut self, max: f64) -> cgmath::Vector3<f64> {
+        cgmath::Vector3::<f64> {
             x: 2.0 * max * self.unit_noise(),
             y: 2.0 * max * self.unit_noise(),
             z: 2.0 * max * self.unit_noise(),
         }
     }
 
-    pub fn bernoulli(&mut self, p_true: f32) -> bool {
+    pub fn random_position_in_ball(&mut self, radius: f64) -> cgmath::Vector3<f64> {
+        use cgmath::InnerSpace;
+        // rejection sample the unit ball out of the cube around it
+        loop {
+            let p = self.random_position_in_cube(1.0);
+            if p.magnitude2() <= 1.0 {
+                return radius * p;
+            }
+        }
+    }
+
+    /// Uniformly random index into a collection of length `n`
+    pub fn index(&mut self, n: usize) -> usize {
+        ((self.uniform_sample() * n as f64) as usize).min(n - 1)
+    }
+
+    /// Standard normal sample (mean 0, variance 1)
+    pub fn gaussian(&mut self) -> f64 {
+        if let Some(spare) = self.spare_gaussian.take() {
+            return spare;
+        }
+        // 1 - u is in (0, 1], so the log is finite
+        let radius = (-2.0 * (1.0 - self.uniform_sample()).ln()).sqrt();
+        let angle = self.unit_radian_noise();
+        self.spare_gaussian = Some(radius * angle.sin());
+        radius * angle.cos()
+    }
+
+    /// Three independent standard normal samples, e.g. the increment of a
+    /// Wiener process over unit time
+    pub fn gaussian_vector(&mut self) -> cgmath::Vector3<f64> {
+        cgmath::Vector3::new(self.gaussian(), self.gaussian(), self.gaussian())
+    }
+
+    pub fn bernoulli(&mut self, p_true: f64) -> bool {
         self.uniform_sample() < p_true
     }
 
-    fn uniform_sample(&mut self) -> f32 {
+    fn uniform_sample(&mut self) -> f64 {
         self.uniform_dist.sample(&mut self.rng)
     }
 }
+
+/// Colored noise: an Ornstein-Uhlenbeck process in three dimensions
+///
+/// Each component is Gaussian with standard deviation `sigma` and forgets
+/// its past over `tau`, the correlation between values dt apart being
+/// exp(-dt / tau).  It is advanced with the exact update, so the
+/// statistics don't depend on the step size.
+#[derive(Clone)]
+pub struct OrnsteinUhlenbeck {
+    pub tau: f64,
+    pub sigma: f64,
+    pub value: cgmath::Vector3<f64>,
+}
+
+impl OrnsteinUhlenbeck {
+    /// Starts out with a sample of the stationary distribution
+    pub fn new(tau: f64, sigma: f64, chaos: &mut Chaos) -> Self {
+        Self {
+            tau,
+            sigma,
+            value: sigma * chaos.gaussian_vector(),
+        }
+    }
+
+    /// Move on by `dt`, returns the new value
+    pub fn advance(&mut self, dt: f64, chaos: &mut Chaos) -> cgmath::Vector3<f64> {
+        let decay = (-dt / self.tau).exp();
+        let spread = self.sigma * (1.0 - decay * decay).sqrt();
+        self.value = decay * self.value + spread * chaos.gaussian_vector();
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_stream() {
+        let mut a = Chaos::from_seed(42);
+        let mut b = Chaos::from_seed(42);
+        for _ in 0..100 {
+            assert_eq!(a.unit_noise(), b.unit_noise());
+        }
+    }
+
+    #[test]
+    fn forks_are_reproducible_and_independent() {
+        let mut a = Chaos::from_seed(7);
+        let mut b = Chaos::from_seed(7);
+        let mut a1 = a.fork();
+        let mut a2 = a.fork();
+        let mut b1 = b.fork();
+        assert_eq!(a1.seed(), b1.seed());
+        assert_ne!(a1.seed(), a2.seed());
+        assert_eq!(a1.unit_noise(), b1.unit_noise());
+        assert_ne!(a1.unit_noise(), a2.unit_noise());
+    }
+
+    #[test]
+    fn gaussian_has_unit_variance() {
+        let mut chaos = Chaos::from_seed(3);
+        let n = 100_000;
+        let samples = (0..n).map(|_| chaos.gaussian()).collect::<Vec<_>>();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+        assert!(mean.abs() < 0.01, "mean {}", mean);
+        assert!((variance - 1.0).abs() < 0.02, "variance {}", variance);
+    }
+
+    #[test]
+    fn ornstein_uhlenbeck_statistics() {
+        let mut chaos = Chaos::from_seed(4);
+        let (tau, sigma, dt) = (0.5, 2.0, 0.01);
+        let mut noise = OrnsteinUhlenbeck::new(tau, sigma, &mut chaos);
+        let lag = (tau / dt) as usize;
+        let values = (0..200_000)
+            .map(|_| noise.advance(dt, &mut chaos).x)
+            .collect::<Vec<_>>();
+        let n = values.len() as f64;
+        let variance = values.iter().map(|x| x * x).sum::<f64>() / n;
+        let lagged = values
+            .iter()
+            .zip(values.iter().skip(lag))
+            .map(|(a, b)| a * b)
+            .sum::<f64>()
+            / (n - lag as f64);
+        assert!((variance / 4.0 - 1.0).abs() < 0.1, "variance {}", variance);
+        // correlated by 1/e one correlation time apart
+        let correlation = lagged / variance;
+        assert!(
+            (correlation - (-1.0f64).exp()).abs() < 0.05,
+            "correlation {}",
+            correlation
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::scene;
+use crate::screenshot;
+
+/// Writes an uncompressed YUV4MPEG2 stream
+///
+/// Frames are converted from RGBA to limited range BT.601 YUV (luma in
+/// 16-235, chroma in 16-240) with 4:2:0 chroma subsampling (`C420jpeg`).
+/// Limited range is what ffmpeg and most players assume for YUV, the
+/// header also says so with `XCOLORRANGE=LIMITED` for the tools that read
+/// it.
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    width: u32,
+    height: u32,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Write the stream header, the frame rate is `fps_num / fps_den`
+    pub fn new(mut out: W, width: u32, height: u32, fps_num: u32, fps_den: u32) -> Result<Self> {
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420jpeg XCOLORRANGE=LIMITED",
+            width, height, fps_num, fps_den
+        )?;
+        Ok(Self { out, width, height })
+    }
+
+    /// Append one frame of tightly packed RGBA8 pixels
+    pub fn write_frame(&mut self, rgba: &[u8]) -> Result<()> {
+        let expected = (self.width * self.height * 4) as usize;
+        if rgba.len() != expected {
+            bail!(
+                "Frame has {} bytes, expected {} for {}x{}",
+                rgba.len(),
+                expected,
+                self.width,
+                self.height
+            );
+        }
+        let (y, u, v) = rgba_to_yuv420(rgba, self.width, self.height);
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&y)?;
+        self.out.write_all(&u)?;
+        self.out.write_all(&v)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn to_byte(x: f32) -> u8 {
+    x.round().clamp(0.0, 255.0) as u8
+}
+
+// limited range squeezes full range luma into 16-235 and chroma into 16-240
+const LUMA_SCALE: f32 = 219.0 / 255.0;
+const CHROMA_SCALE: f32 = 224.0 / 255.0;
+
+/// Split RGBA pixels into limited range Y, U and V planes, chroma is
+/// averaged over 2x2 blocks (rounded up for odd sizes)
+fn rgba_to_yuv420(rgba: &[u8], width: u32, height: u32) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let (width, height) = (width as usize, height as usize);
+    let (chroma_width, chroma_height) = (width / 2 + width % 2, height / 2 + height % 2);
+
+    let mut y = Vec::with_capacity(width * height);
+    let mut u = vec![0.0f32; chroma_width * chroma_height];
+    let mut v = vec![0.0f32; chroma_width * chroma_height];
+    let mut counts = vec![0.0f32; chroma_width * chroma_height];
+
+    for row in 0..height {
+        for col in 0..width {
+            let pixel = &rgba[(row * width + col) * 4..];
+            let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+            y.push(to_byte(
+                16.0 + LUMA_SCALE * (0.299 * r + 0.587 * g + 0.114 * b),
+            ));
+
+            let ix = (row / 2) * chroma_width + col / 2;
+            u[ix] += -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+            v[ix] += 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+            counts[ix] += 1.0;
+        }
+    }
+
+    let finish = |plane: Vec<f32>| -> Vec<u8> {
+        plane
+            .iter()
+            .zip(counts.iter())
+            .map(|(sum, n)| to_byte(128.0 + CHROMA_SCALE * sum / n))
+            .collect()
+    };
+    let u = finish(u);
+    let v = finish(v);
+    (y, u, v)
+}
+
+enum Sink {
+    Png(PathBuf),
+    Y4m(Y4mWriter<BufWriter<File>>),
+}
+
+/// Saves every `every`th rendered frame of an animation
+///
+/// Frames are rendered at a fixed `fps`, and the simulation is advanced by
+/// exactly `frame_dt()` per frame, so a recording doesn't depend on how
+/// fast the machine making it is.  A path ending in `.y4m` gets a video
+/// stream, anything else is a directory of numbered PNGs.
+pub struct Recorder {
+    sink: Sink,
+    scene_path: PathBuf,
+    size: winit::dpi::PhysicalSize<u32>,
+    fps: u32,
+    every: u32,
+    max_frames: Option<u64>,
+    // rendered frames to skip before the next saved one
+    skip: u32,
+    saved: u64,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        size: winit::dpi::PhysicalSize<u32>,
+        fps: u32,
+        every: u32,
+        max_frames: Option<u64>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if fps == 0 || every == 0 {
+            bail!("Frame rate and frame interval must be at least 1");
+        }
+
+        let is_video = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some(ext) if ext.eq_ignore_ascii_case("y4m")
+        );
+        let sink = if is_video {
+            let file = File::create(path)
+                .with_context(|| format!("Could not create {}", path.display()))?;
+            // keep the playback speed the same as the rendered speed
+            let writer = Y4mWriter::new(BufWriter::new(file), size.width, size.height, fps, every)?;
+            Sink::Y4m(writer)
+        } else {
+            std::fs::create_dir_all(path)
+                .with_context(|| format!("Could not create {}", path.display()))?;
+            Sink::Png(path.to_path_buf())
+        };
+        let scene_path = match sink {
+            Sink::Png(_) => path.join("scene"),
+            Sink::Y4m(_) => path.to_path_buf(),
+        };
+
+        Ok(Self {
+            sink,
+            scene_path,
+            size,
+            fps,
+            every,
+            max_frames,
+            skip: 0,
+            saved: 0,
+        })
+    }
+
+    /// Save the scene that reproduces the recording next to it
+    pub fn save_scene(&self, scene: &scene::Scene) -> Result<()> {
+        screenshot::save_scene(&self.scene_path, scene)
+    }
+
+    pub fn size(&self) -> winit::dpi::PhysicalSize<u32> {
+        self.size
+    }
+
+    /// Real time covered by one rendered frame
+    pub fn frame_dt(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.fps as f64)
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.max_frames, Some(max) if self.saved >= max)
+    }
+
+    /// Whether the next rendered frame will be saved
+    pub fn wants_frame(&self) -> bool {
+        !self.is_done() && self.skip == 0
+    }
+
+    /// Count a rendered frame, saving it if it is due.  `capture` is only
+    /// called for frames that are saved.
+    pub fn frame<F>(&mut self, capture: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if !self.wants_frame() {
+            self.skip = self.skip.saturating_sub(1);
+            return Ok(());
+        }
+
+        let rgba = capture()?;
+        match &mut self.sink {
+            Sink::Png(dir) => {
+                use image::{ImageBuffer, Rgba};
+                let path = dir.join(format!("frame_{:06}.png", self.saved));
+                ImageBuffer::<Rgba<u8>, _>::from_raw(self.size.width, self.size.height, rgba)
+                    .context("Frame has the wrong size")?
+                    .save(&path)
+                    .with_context(|| format!("Could not save {}", path.display()))?;
+            }
+            Sink::Y4m(writer) => writer.write_frame(&rgba)?,
+        }
+        self.saved += 1;
+        self.skip = self.every - 1;
+        Ok(())
+    }
+
+    /// Flush the output, returns the number of frames saved
+    pub fn finish(self) -> Result<u64> {
+        if let Sink::Y4m(writer) = self.sink {
+            writer.finish()?;
+        }
+        Ok(self.saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn y4m_stream_layout() {
+        let (width, height) = (3, 2);
+        let mut writer = Y4mWriter::new(Vec::new(), width, height, 60, 2).unwrap();
+        let white = vec![255u8; (width * height * 4) as usize];
+        writer.write_frame(&white).unwrap();
+        writer.write_frame(&white).unwrap();
+        assert!(writer.write_frame(&white[4..]).is_err());
+        let black = vec![0u8; (width * height * 4) as usize];
+        assert_eq!(rgba_to_yuv420(&black, width, height).0, vec![16; 6]);
+        let bytes = writer.finish().unwrap();
+
+        let header = b"YUV4MPEG2 W3 H2 F60:2 Ip A1:1 C420jpeg XCOLORRANGE=LIMITED\n";
+        assert!(bytes.starts_with(header));
+        // 6 luma samples and 2x1 samples for each chroma plane
+        let frame_len = b"FRAME\n".len() + 6 + 2 + 2;
+        assert_eq!(bytes.len(), header.len() + 2 * frame_len);
+        let frame = &bytes[header.len()..header.len() + frame_len];
+        assert_eq!(&frame[6..12], &[235; 6]);
+        assert_eq!(&frame[12..], &[128; 4]);
+    }
+
+    #[test]
+    fn saves_every_nth_frame() {
+        let dir = std::env::temp_dir().join(format!("wagoo-recorder-{}", std::process::id()));
+        let size = winit::dpi::PhysicalSize::new(2, 2);
+        let mut recorder = Recorder::create(&dir, size, 60, 3, Some(3)).unwrap();
+        let mut captured = Vec::new();
+        for ix in 0..20 {
+            recorder
+                .frame(|| {
+                    captured.push(ix);
+                    Ok(vec![0; 16])
+                })
+                .unwrap();
+        }
+        assert!(recorder.is_done());
+        assert_eq!(recorder.finish().unwrap(), 3);
+        assert_eq!(captured, vec![0, 3, 6]);
+        assert!(dir.join("frame_000002.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chroma_is_averaged() {
+        // red and blue side by side share one chroma sample
+        let rgba = [255, 0, 0, 255, 0, 0, 255, 255];
+        let (y, u, v) = rgba_to_yuv420(&rgba, 2, 1);
+        assert_eq!(y, vec![81, 41]);
+        assert_eq!(u, vec![to_byte(128.0 + (-0.168_736 + 0.5) * 224.0 / 2.0)]);
+        assert_eq!(v, vec![to_byte(128.0 + (0.5 - 0.081_312) * 224.0 / 2.0)]);
+    }
+}
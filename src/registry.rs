@@ -0,0 +1,367 @@
+use anyhow::*;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, RwLock};
+
+use crate::attractors;
+use crate::boids;
+use crate::charged;
+use crate::dynamics;
+use crate::integrator;
+use crate::interaction::{Body, Interaction};
+use crate::maps;
+use crate::nbody;
+use crate::rand_util::Chaos;
+use crate::sde;
+
+/// A parameter of a model
+#[derive(Debug, Clone)]
+pub struct ParamSpec {
+    pub name: String,
+    pub default: f64,
+    // values outside of this are rejected when a scene is loaded
+    pub range: RangeInclusive<f64>,
+}
+
+type BuildFn = dyn Fn(
+        &Params,
+        cgmath::Vector3<f64>,
+        integrator::Method,
+        &mut Chaos,
+    ) -> Box<dyn dynamics::DynamicSystem>
+    + Send
+    + Sync;
+
+type InteractionFn = dyn Fn(&Params, &mut [Body], &mut Chaos) -> Box<dyn Interaction> + Send + Sync;
+
+/// A dynamics model that can be named in a scene
+///
+/// Built with `Model::new` and `param`, and made available by name with
+/// `register`:
+///
+/// ```
+/// use wagoo::dynamics::Flow;
+/// use wagoo::registry::{self, Model};
+///
+/// struct Spiral {
+///     rate: f64,
+/// }
+///
+/// impl wagoo::VectorField for Spiral {
+///     fn derivative(&self, p: cgmath::Vector3<f64>, _t: f64) -> cgmath::Vector3<f64> {
+///         cgmath::Vector3::new(-p.y, p.x, -self.rate * p.z)
+///     }
+/// }
+///
+/// registry::register(
+///     Model::new("spiral", "Circles around the z axis", |params, position, method, _chaos| {
+///         Box::new(Flow::new(Spiral { rate: params.get("rate") }, position, method))
+///     })
+///     .param("rate", 0.1, 0.0..=1.0),
+/// )
+/// .unwrap();
+/// assert!(registry::get("spiral").is_ok());
+/// ```
+pub struct Model {
+    pub name: String,
+    pub description: String,
+    pub params: Vec<ParamSpec>,
+    // steps jump rather than flow, so tails are drawn as points
+    pub discrete: bool,
+    build: Box<BuildFn>,
+    // for models whose particles act on each other
+    interaction: Option<Box<InteractionFn>>,
+}
+
+/// The parameter values a model is built with, anything a scene leaves out
+/// takes the model's default
+pub struct Params<'a> {
+    model: &'a Model,
+    values: &'a BTreeMap<String, f64>,
+}
+
+impl Params<'_> {
+    pub fn get(&self, name: &str) -> f64 {
+        match self.values.get(name) {
+            Some(value) => *value,
+            None => self.model.default_value(name),
+        }
+    }
+}
+
+impl Model {
+    pub fn new<F>(name: &str, description: &str, build: F) -> Self
+    where
+        F: Fn(
+                &Params,
+                cgmath::Vector3<f64>,
+                integrator::Method,
+                &mut Chaos,
+            ) -> Box<dyn dynamics::DynamicSystem>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            params: Vec::new(),
+            discrete: false,
+            build: Box::new(build),
+            interaction: None,
+        }
+    }
+
+    /// A model where particles act on each other, they are `Body`s that
+    /// the simulation steps all at once with the `Interaction` this builds.
+    /// The bodies come with the mass and velocity of their group, which
+    /// `build` may change.
+    pub fn interacting<F>(name: &str, description: &str, build: F) -> Self
+    where
+        F: Fn(&Params, &mut [Body], &mut Chaos) -> Box<dyn Interaction> + Send + Sync + 'static,
+    {
+        Self {
+            interaction: Some(Box::new(build)),
+            ..Self::new(name, description, |_, position, _, _| {
+                Box::new(Body::at(position))
+            })
+        }
+    }
+
+    /// Add a parameter
+    pub fn param(mut self, name: &str, default: f64, range: RangeInclusive<f64>) -> Self {
+        self.params.push(ParamSpec {
+            name: name.to_string(),
+            default,
+            range,
+        });
+        self
+    }
+
+    /// Mark the model as discrete time, e.g. an iterated map
+    pub fn discrete(mut self) -> Self {
+        self.discrete = true;
+        self
+    }
+
+    fn spec(&self, name: &str) -> Option<&ParamSpec> {
+        self.params.iter().find(|p| p.name == name)
+    }
+
+    /// The default value of a parameter, panics if there is no such
+    /// parameter
+    pub fn default_value(&self, name: &str) -> f64 {
+        match self.spec(name) {
+            Some(spec) => spec.default,
+            None => panic!("No parameter {} for model {}", name, self.name),
+        }
+    }
+
+    /// Check parameter values given for this model
+    pub fn validate(&self, values: &BTreeMap<String, f64>) -> Result<()> {
+        for (name, value) in values.iter() {
+            let spec = match self.spec(name) {
+                Some(spec) => spec,
+                None => bail!(
+                    "Unknown parameter '{}' for model '{}', expected one of: {}",
+                    name,
+                    self.name,
+                    self.params
+                        .iter()
+                        .map(|p| p.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+            if !value.is_finite() {
+                bail!("Parameter '{}' must be a finite number", name);
+            }
+            if !spec.range.contains(value) {
+                bail!(
+                    "Parameter '{}' must be between {} and {}, got {}",
+                    name,
+                    spec.range.start(),
+                    spec.range.end(),
+                    value
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Instantiate the model for one particle (assumes the values were
+    /// validated)
+    pub fn build(
+        &self,
+        values: &BTreeMap<String, f64>,
+        position: cgmath::Vector3<f64>,
+        method: integrator::Method,
+        chaos: &mut Chaos,
+    ) -> Box<dyn dynamics::DynamicSystem> {
+        let params = Params {
+            model: self,
+            values,
+        };
+        (self.build)(&params, position, method, chaos)
+    }
+
+    pub fn is_interacting(&self) -> bool {
+        self.interaction.is_some()
+    }
+
+    /// Set up the interaction between the bodies of all particles, `None`
+    /// for models where each particle moves on its own
+    pub fn build_interaction(
+        &self,
+        values: &BTreeMap<String, f64>,
+        bodies: &mut [Body],
+        chaos: &mut Chaos,
+    ) -> Option<Box<dyn Interaction>> {
+        let params = Params {
+            model: self,
+            values,
+        };
+        self.interaction
+            .as_ref()
+            .map(|build| build(&params, bodies, chaos))
+    }
+}
+
+fn builtin() -> Vec<Arc<Model>> {
+    vec![
+        Model::new(
+            "lorenz",
+            "Lorenz attractor",
+            |params, position, method, _chaos| {
+                Box::new(dynamics::Flow::new(
+                    dynamics::Lorenz::new(
+                        params.get("sigma"),
+                        params.get("rho"),
+                        params.get("beta"),
+                        params.get("speed"),
+                    ),
+                    position,
+                    method,
+                ))
+            },
+        )
+        .param("sigma", 18.0, 0.0..=100.0)
+        .param("rho", 8.0, 0.0..=200.0)
+        .param("beta", 8.0 / 3.0, 0.0..=20.0)
+        .param("speed", 0.1, 0.0..=10.0),
+        Model::new(
+            "circler",
+            "Noisy circles in the XY plane, settling towards z = 0",
+            |params, position, _method, chaos| {
+                Box::new(dynamics::Circler::new(
+                    params.get("speed"),
+                    params.get("omega"),
+                    position,
+                    chaos,
+                ))
+            },
+        )
+        .param("speed", 0.01, 0.0..=1.0)
+        .param("omega", 0.01, -1.0..=1.0),
+        nbody::model(),
+        boids::model(),
+    ]
+    .into_iter()
+    .chain(attractors::models())
+    .chain(charged::models())
+    .chain(maps::models())
+    .chain(sde::models())
+    .map(Arc::new)
+    .collect()
+}
+
+static REGISTRY: Lazy<RwLock<Vec<Arc<Model>>>> = Lazy::new(|| RwLock::new(builtin()));
+
+/// Make a model available by name, names have to be unique
+pub fn register(model: Model) -> Result<()> {
+    let mut models = REGISTRY.write().unwrap();
+    if models.iter().any(|m| m.name == model.name) {
+        bail!("A model named '{}' is already registered", model.name);
+    }
+    models.push(Arc::new(model));
+    Ok(())
+}
+
+/// Look up a model by name
+pub fn get(name: &str) -> Result<Arc<Model>> {
+    let models = REGISTRY.read().unwrap();
+    match models.iter().find(|m| m.name == name) {
+        Some(model) => Ok(model.clone()),
+        None => bail!(
+            "Unknown model '{}', expected one of: {}",
+            name,
+            models
+                .iter()
+                .map(|m| m.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Every registered model, in the order they were registered
+pub fn models() -> Vec<Arc<Model>> {
+    REGISTRY.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_models_are_registered() {
+        let names = models().iter().map(|m| m.name.clone()).collect::<Vec<_>>();
+        assert!(names.contains(&"lorenz".to_string()));
+        assert!(names.contains(&"circler".to_string()));
+        assert_eq!(get("lorenz").unwrap().default_value("rho"), 8.0);
+        assert!(get("lorentz").is_err());
+    }
+
+    #[test]
+    fn params_are_checked() {
+        let lorenz = get("lorenz").unwrap();
+        let mut values = BTreeMap::new();
+        values.insert("rho".to_string(), 28.0);
+        assert!(lorenz.validate(&values).is_ok());
+        values.insert("speed".to_string(), -1.0);
+        assert!(lorenz.validate(&values).is_err());
+        values.remove("speed");
+        values.insert("sigm".to_string(), 10.0);
+        assert!(lorenz.validate(&values).is_err());
+    }
+
+    #[test]
+    fn models_can_be_registered() {
+        let model = Model::new("test_still", "Stays put", |_, position, _, _| {
+            Box::new(dynamics::Circler {
+                heading: 0.0,
+                omega: 0.0,
+                speed: 0.0,
+                position,
+            })
+        })
+        .param("unused", 1.0, 0.0..=2.0);
+        register(model).unwrap();
+        let again = Model::new("test_still", "", |_, position, _, chaos| {
+            Box::new(dynamics::Circler::new(0.0, 0.0, position, chaos))
+        });
+        assert!(register(again).is_err());
+
+        let model = get("test_still").unwrap();
+        let mut chaos = Chaos::from_seed(1);
+        let position = cgmath::Vector3::new(1.0, 2.0, 3.0);
+        let particle = model.build(
+            &BTreeMap::new(),
+            position,
+            integrator::Method::Rk4,
+            &mut chaos,
+        );
+        assert_eq!(particle.get_position(), position);
+    }
+}
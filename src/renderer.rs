@@ -0,0 +1,437 @@
+use anyhow::Result;
+use cgmath::prelude::*;
+use rayon::prelude::*;
+use wgpu::util::DeviceExt;
+
+use crate::camera;
+use crate::compute;
+use crate::model::Vertex;
+use crate::post;
+use crate::scene;
+use crate::simulation::{self, Simulation};
+use crate::sphere;
+use crate::sphere::DrawSphere;
+use crate::tail_store;
+use crate::texture;
+use crate::util;
+
+// storage buffers the tail shader binds besides the tail pages: the groups
+const TAIL_STORAGE_BUFFERS: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Uniforms {
+    view_position: [f32; 4],
+    view_proj: [[f32; 4]; 4],
+    // whether the sphere and tail shaders should premultiply alpha
+    premultiply_spheres: i32,
+    premultiply_tails: i32,
+    // whether tails are drawn as points rather than lines
+    tail_points: i32,
+    _padding: i32,
+}
+
+impl Uniforms {
+    fn new(render_config: &scene::RenderConfig, tail_points: bool) -> Self {
+        Self {
+            view_position: [0.0; 4],
+            view_proj: cgmath::Matrix4::identity().into(),
+            premultiply_spheres: render_config.sphere_blend.premultiplies() as i32,
+            premultiply_tails: render_config.tail_blend.premultiplies() as i32,
+            tail_points: tail_points as i32,
+            _padding: 0,
+        }
+    }
+
+    // UPDATED!
+    fn update_view_proj(
+        &mut self,
+        camera: &camera::Camera,
+        projection: &camera::Projection,
+        origin: cgmath::Vector3<f64>,
+    ) {
+        self.view_position = camera.eye(origin).to_homogeneous().into();
+        self.view_proj = (projection.calc_matrix() * camera.calc_matrix(origin)).into()
+    }
+}
+
+/// Draws a `Simulation` into any texture view of the given format
+///
+/// The renderer doesn't own the device or the queue, so it can be used
+/// with a window's swap chain as well as with offscreen targets.
+pub struct Renderer {
+    format: wgpu::TextureFormat,
+    size: winit::dpi::PhysicalSize<u32>,
+    render_pipeline_no_light: wgpu::RenderPipeline,
+    render_pipeline_tails: wgpu::RenderPipeline,
+    uniforms: Uniforms,
+    uniform_buffer: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+    sphere_mesh: sphere::SphereMesh,
+    sphere_instance_buffer: wgpu::Buffer,
+    tail_pages: tail_store::Pages,
+    tail_page_buffers: Vec<wgpu::Buffer>,
+    tail_group_buffer: wgpu::Buffer,
+    tail_bind_group: wgpu::BindGroup,
+    // vertices per tail, enough for the longest one
+    tail_vertex_count: u32,
+    // steps the particles when the scene uses the GPU backend
+    compute: Option<compute::ComputeSimulation>,
+    depth_texture: texture::Texture,
+    post_config: scene::PostConfig,
+    post: post::Post,
+}
+
+impl Renderer {
+    pub fn new(
+        device: &wgpu::Device,
+        size: winit::dpi::PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
+        simulation: &Simulation,
+        scene: &scene::Scene,
+    ) -> Result<Self> {
+        let post_config = &scene.post;
+        // consecutive iterates of a map aren't anywhere near each other, so
+        // joining them with lines would just be a mess
+        let tail_points = scene.dynamics.is_discrete()?;
+        let uniforms = Uniforms::new(&scene.render, tail_points);
+
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniforms]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let sphere_mesh = sphere::SphereMesh::new(device, 64, 64);
+
+        let sphere_instance_data = simulation
+            .sphere_instances
+            .iter()
+            .map(|s| s.to_raw(simulation.origin()))
+            .collect::<Vec<_>>();
+        let sphere_instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sphere instance buffer"),
+            contents: bytemuck::cast_slice(&sphere_instance_data),
+            usage: wgpu::BufferUsage::VERTEX
+                | wgpu::BufferUsage::STORAGE
+                | wgpu::BufferUsage::COPY_DST,
+        });
+
+        // all tails live in one ring split over a few storage buffers, only
+        // new rows are written
+        let other_buffers = match scene.simulation.backend {
+            scene::Backend::Cpu => TAIL_STORAGE_BUFFERS,
+            scene::Backend::Gpu => compute::STORAGE_BUFFERS,
+        };
+        let tail_pages = tail_store::check_fits(&scene.groups, &device.limits(), other_buffers)?;
+        let tail_page_buffers = (0..tail_pages.count)
+            .map(|page| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(&format!("Tail point buffer {}", page)),
+                    size: (tail_pages.len(page) * tail_store::POINT_SIZE) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect::<Vec<_>>();
+        let tail_group_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tail group buffer"),
+            contents: bytemuck::cast_slice(&simulation.tails.to_raw()),
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        });
+        let max_capacity = simulation.tails.max_capacity() as u32;
+        let tail_vertex_count = if tail_points {
+            max_capacity
+        } else {
+            2 * (max_capacity - 1)
+        };
+
+        let compute = match scene.simulation.backend {
+            scene::Backend::Cpu => None,
+            scene::Backend::Gpu => Some(compute::ComputeSimulation::new(
+                device,
+                simulation,
+                &scene.dynamics,
+                &sphere_instance_buffer,
+                &tail_group_buffer,
+                &tail_pages,
+                &tail_page_buffers,
+            )?),
+        };
+
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("uniform_bind_group_layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("uniform_bind_group"),
+        });
+
+        let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        // the groups, then the pages
+        let tail_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &(0..=tail_pages.count as u32)
+                    .map(storage_entry)
+                    .collect::<Vec<_>>(),
+                label: Some("tail_bind_group_layout"),
+            });
+
+        let tail_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &tail_bind_group_layout,
+            entries: &std::iter::once(&tail_group_buffer)
+                .chain(tail_page_buffers.iter())
+                .enumerate()
+                .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                    binding: binding as u32,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect::<Vec<_>>(),
+            label: Some("tail_bind_group"),
+        });
+
+        let depth_texture = texture::Texture::create_depth_texture(device, size, "depth_texture");
+
+        let render_pipeline_layout_no_light =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout (No Light)"),
+                bind_group_layouts: &[&uniform_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let render_pipeline_no_light = {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Normal Shader (No Light)"),
+                flags: wgpu::ShaderFlags::all(),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shader_no_light.wgsl").into()),
+            };
+            util::create_render_pipeline_with_primitive(
+                device,
+                &render_pipeline_layout_no_light,
+                post::HDR_FORMAT,
+                Some(texture::Texture::DEPTH_FORMAT),
+                &[
+                    sphere::SphereVertex::desc(),
+                    sphere::SphereInstanceRaw::desc(),
+                ],
+                shader,
+                util::triangle_list(),
+                scene.render.sphere_blend,
+            )
+        };
+
+        let render_pipeline_layout_tails =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout (Tails)"),
+                bind_group_layouts: &[&uniform_bind_group_layout, &tail_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let render_pipeline_tails = {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Tail Shader"),
+                flags: wgpu::ShaderFlags::all(),
+                source: wgpu::ShaderSource::Wgsl(
+                    tail_pages
+                        .fill_in(include_str!("tail_shader.wgsl"), 1, 1, false)
+                        .into(),
+                ),
+            };
+            let primitive = wgpu::PrimitiveState {
+                topology: if tail_points {
+                    wgpu::PrimitiveTopology::PointList
+                } else {
+                    wgpu::PrimitiveTopology::LineList
+                },
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+                polygon_mode: wgpu::PolygonMode::Fill,
+                // Requires Features::DEPTH_CLAMPING
+                clamp_depth: false,
+                // Requires Features::CONSERVATIVE_RASTERIZATION
+                conservative: false,
+            };
+
+            util::create_render_pipeline_with_primitive(
+                device,
+                &render_pipeline_layout_tails,
+                post::HDR_FORMAT,
+                Some(texture::Texture::DEPTH_FORMAT),
+                &[sphere::SphereInstanceRaw::desc()],
+                shader,
+                primitive,
+                scene.render.tail_blend,
+            )
+        };
+
+        let post = post::Post::new(device, size, format, post_config);
+
+        Ok(Self {
+            format,
+            size,
+            render_pipeline_no_light,
+            render_pipeline_tails,
+            uniforms,
+            uniform_buffer,
+            uniform_bind_group,
+            sphere_mesh,
+            sphere_instance_buffer,
+            tail_pages,
+            tail_page_buffers,
+            tail_group_buffer,
+            tail_bind_group,
+            tail_vertex_count,
+            compute,
+            depth_texture,
+            post_config: post_config.clone(),
+            post,
+        })
+    }
+
+    /// The untonemapped frame, in `post::HDR_FORMAT`
+    pub fn hdr_texture(&self) -> &wgpu::Texture {
+        &self.post.hdr_texture.texture
+    }
+
+    /// Recreate the size dependent targets
+    pub fn resize(&mut self, device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) {
+        self.size = size;
+        self.depth_texture = texture::Texture::create_depth_texture(device, size, "depth_texture");
+        self.post = post::Post::new(device, size, self.format, &self.post_config);
+    }
+
+    /// `origin` is the simulation's, see `Simulation::origin`
+    pub fn update_camera(
+        &mut self,
+        queue: &wgpu::Queue,
+        camera: &camera::Camera,
+        projection: &camera::Projection,
+        origin: cgmath::Vector3<f64>,
+    ) {
+        self.uniforms.update_view_proj(camera, projection, origin);
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms]),
+        );
+    }
+
+    /// Copy the current particle state, and the tail points recorded since
+    /// the last upload, to the GPU.  With the GPU backend, this is where
+    /// the steps the simulation has taken since are actually run.
+    pub fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue, simulation: &mut Simulation) {
+        if let Some(compute) = &self.compute {
+            let (first_step, n_steps) = simulation.take_gpu_steps();
+            compute.run(device, queue, first_step, n_steps);
+        } else {
+            let origin = simulation.origin();
+            let sphere_instance_data = simulation
+                .sphere_instances
+                .par_iter()
+                .with_min_len(simulation::MIN_PARTICLES_PER_TASK)
+                .map(|s| s.to_raw(origin))
+                .collect::<Vec<_>>();
+
+            queue.write_buffer(
+                &self.sphere_instance_buffer,
+                0,
+                bytemuck::cast_slice(&sphere_instance_data),
+            );
+        }
+        for row in simulation.tails.take_rows() {
+            for (page, start, points) in self.tail_pages.split(row.offset, row.points.len()) {
+                queue.write_buffer(
+                    &self.tail_page_buffers[page],
+                    (start * tail_store::POINT_SIZE) as wgpu::BufferAddress,
+                    bytemuck::cast_slice(&row.points[points]),
+                );
+            }
+        }
+        queue.write_buffer(
+            &self.tail_group_buffer,
+            0,
+            bytemuck::cast_slice(&simulation.tails.to_raw()),
+        );
+    }
+
+    pub fn render_to(
+        &self,
+        view: &wgpu::TextureView,
+        encoder: &mut wgpu::CommandEncoder,
+        simulation: &Simulation,
+    ) {
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &self.post.scene_texture.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.0,
+                            g: 0.0,
+                            b: 0.0,
+                            a: 1.0,
+                        }),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            render_pass.set_vertex_buffer(1, self.sphere_instance_buffer.slice(..));
+            render_pass.set_pipeline(&self.render_pipeline_no_light);
+            render_pass.draw_sphere_instanced(
+                &self.sphere_mesh,
+                &self.uniform_bind_group,
+                0..simulation.sphere_instances.len() as u32,
+            );
+
+            // every tail in one draw, the instance index picks the particle
+            render_pass.set_vertex_buffer(0, self.sphere_instance_buffer.slice(..));
+            render_pass.set_pipeline(&self.render_pipeline_tails);
+            render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.tail_bind_group, &[]);
+            render_pass.draw(
+                0..self.tail_vertex_count,
+                0..simulation.sphere_instances.len() as u32,
+            );
+        }
+
+        self.post.render(encoder, view);
+    }
+}
@@ -0,0 +1,814 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::camera;
+use crate::compute;
+use crate::dynamics;
+use crate::expression;
+use crate::integrator;
+use crate::interaction;
+use crate::rand_util::Chaos;
+use crate::registry;
+use crate::script;
+use crate::util;
+
+/// Declarative description of everything that goes into a run
+///
+/// Scenes are read from TOML files, any section or field that is left out
+/// gets the default below (which is the classic 1000 particle Lorenz scene).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scene {
+    // random seed, picked at startup when not given
+    pub seed: Option<u64>,
+    pub simulation: SimulationConfig,
+    pub dynamics: DynamicsConfig,
+    #[serde(rename = "group")]
+    pub groups: Vec<GroupConfig>,
+    pub camera: CameraConfig,
+    pub render: RenderConfig,
+    pub post: PostConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    pub fixed_dt: f64,
+    pub time_scale: f64,
+    pub max_substeps: u32,
+    // where the particles are stepped
+    pub backend: Backend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Cpu,
+    // in a compute shader, only some models and integrators are supported
+    Gpu,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DynamicsConfig {
+    pub model: String,
+    // path of the script for `model = "script"`
+    pub script: Option<String>,
+    pub integrator: integrator::Method,
+    // anything not given takes the model's default
+    pub params: BTreeMap<String, f64>,
+    // right hand sides for `model = "equations"`
+    pub equations: Option<EquationsConfig>,
+    // what `model()` built last, so scripts and equations are compiled once
+    // rather than by every caller
+    #[serde(skip)]
+    pub(crate) resolved: ResolvedModel,
+}
+
+// The fields of `DynamicsConfig` are public and get changed in place (e.g.
+// when switching models), so the model is rebuilt when they no longer match
+// the ones it was built from.  A script reloads itself in place, the cached
+// model keeps following it.
+#[derive(Default)]
+pub(crate) struct ResolvedModel(Mutex<Option<Resolved>>);
+
+#[derive(Clone)]
+struct Resolved {
+    model: String,
+    script_path: Option<String>,
+    params: BTreeMap<String, f64>,
+    equations: Option<EquationsConfig>,
+    built: Arc<registry::Model>,
+    script: Option<Arc<script::Script>>,
+}
+
+impl Clone for ResolvedModel {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl fmt::Debug for ResolvedModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ResolvedModel")
+    }
+}
+
+/// A flow given by math expressions over x, y, z, t and the parameters,
+/// e.g. `dx = "sigma * (y - x)"`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EquationsConfig {
+    pub dx: String,
+    pub dy: String,
+    pub dz: String,
+}
+
+/// A set of particles that share spawn region, looks and tail settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    pub count: usize,
+    pub radius: f32,
+    // per step chance that a disabled particle gets switched on
+    pub enable_probability: f64,
+    // mass and starting velocity of the particles, only used by models
+    // where particles act on each other
+    pub mass: f64,
+    pub velocity: [f64; 3],
+    pub spawn: SpawnConfig,
+    pub color: ColorConfig,
+    pub tail: TailConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SpawnConfig {
+    Cube { center: [f64; 3], half_width: f64 },
+    Ball { center: [f64; 3], radius: f64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ColorConfig {
+    Random,
+    Solid { rgba: [f32; 4] },
+    // each particle picks one of these at random
+    Palette { colors: Vec<[f32; 4]> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TailConfig {
+    pub capacity: usize,
+    // sample the position every `period` steps
+    pub period: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    pub position: [f64; 3],
+    // angles are in degrees
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+    pub speed: f32,
+    pub sensitivity: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    // how particles and their trails are blended into the image
+    pub sphere_blend: util::BlendMode,
+    pub tail_blend: util::BlendMode,
+    // send positions to the GPU relative to a point near the camera
+    // instead of the world's origin, for scenes far away from it
+    pub camera_relative: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostConfig {
+    // glow around bright particles and trails, older scenes call it blur
+    #[serde(alias = "blur")]
+    pub bloom: bool,
+    // brightness (0 to 1) above which things start to glow
+    pub threshold: f32,
+    // how much of the glow is added to the image
+    pub intensity: f32,
+    // spread of the blur at each level
+    pub radius: f32,
+    // number of half size levels, more levels spread the glow further
+    pub levels: u32,
+    // how the HDR image is mapped to the screen
+    pub tonemap: Tonemap,
+    // brightness adjustment in stops, applied before tone mapping
+    pub exposure: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tonemap {
+    // clip anything brighter than white
+    None = 0,
+    Reinhard = 1,
+    Aces = 2,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            simulation: SimulationConfig::default(),
+            dynamics: DynamicsConfig::default(),
+            groups: vec![GroupConfig::default()],
+            camera: CameraConfig::default(),
+            render: RenderConfig::default(),
+            post: PostConfig::default(),
+        }
+    }
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            fixed_dt: 1.0 / 60.0,
+            time_scale: 1.0,
+            max_substeps: 8,
+            backend: Backend::Cpu,
+        }
+    }
+}
+
+impl Default for DynamicsConfig {
+    fn default() -> Self {
+        Self {
+            model: "lorenz".to_string(),
+            script: None,
+            integrator: integrator::Method::Rk4,
+            params: BTreeMap::new(),
+            equations: None,
+            resolved: ResolvedModel::default(),
+        }
+    }
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            count: 1000,
+            radius: 0.1,
+            enable_probability: 0.001,
+            mass: 1.0,
+            velocity: [0.0, 0.0, 0.0],
+            spawn: SpawnConfig::Cube {
+                center: [0.0, 0.0, 0.0],
+                half_width: 4.0,
+            },
+            color: ColorConfig::Random,
+            tail: TailConfig::default(),
+        }
+    }
+}
+
+impl Default for TailConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            period: 4,
+        }
+    }
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 5.0, 10.0],
+            yaw: -90.0,
+            pitch: -20.0,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+            speed: 4.0,
+            sensitivity: 0.4,
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            sphere_blend: util::BlendMode::Replace,
+            tail_blend: util::BlendMode::Replace,
+            camera_relative: false,
+        }
+    }
+}
+
+impl Default for PostConfig {
+    fn default() -> Self {
+        Self {
+            bloom: false,
+            threshold: 0.6,
+            intensity: 1.0,
+            radius: 1.0,
+            levels: 5,
+            tonemap: Tonemap::None,
+            exposure: 0.0,
+        }
+    }
+}
+
+impl Scene {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read scene file {}", path.display()))?;
+        Self::parse_in(&text, path.parent())
+            .with_context(|| format!("Invalid scene file {}", path.display()))
+    }
+
+    /// Parse a scene, relative paths in it are left relative to the working
+    /// directory
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::parse_in(text, None)
+    }
+
+    // relative paths are resolved against `dir`, the scene file's directory
+    fn parse_in(text: &str, dir: Option<&Path>) -> Result<Self> {
+        let mut scene: Scene = toml::from_str(text)?;
+        if let Some(dir) = dir {
+            scene.dynamics.resolve_paths(dir)?;
+        }
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.simulation.validate().context("In [simulation]")?;
+        self.dynamics.validate().context("In [dynamics]")?;
+        if self.simulation.backend == Backend::Gpu {
+            compute::check_supported(&self.dynamics).context("In [simulation]")?;
+            if self.render.camera_relative {
+                bail!("In [render]: camera_relative needs the CPU backend, the GPU one works in world coordinates");
+            }
+        }
+        if self.groups.is_empty() {
+            bail!("The scene needs at least one [[group]] of particles");
+        }
+        for (ix, group) in self.groups.iter().enumerate() {
+            group
+                .validate()
+                .with_context(|| format!("In [[group]] number {}", ix + 1))?;
+        }
+        self.camera.validate().context("In [camera]")?;
+        self.post.validate().context("In [post]")?;
+        Ok(())
+    }
+}
+
+impl Scene {
+    /// Change the total number of particles, keeping the relative sizes of
+    /// the groups
+    pub fn set_particle_count(&mut self, total: usize) -> Result<()> {
+        if total < self.groups.len() {
+            bail!(
+                "Need at least one particle per group ({} groups), got {}",
+                self.groups.len(),
+                total
+            );
+        }
+        let current: usize = self.groups.iter().map(|g| g.count).sum();
+        let mut remaining = total;
+        let n_groups = self.groups.len();
+        for (ix, group) in self.groups.iter_mut().enumerate() {
+            group.count = if ix == n_groups - 1 {
+                remaining
+            } else {
+                let share = (total as f64 * group.count as f64 / current as f64).round() as usize;
+                // leave at least one particle for each of the groups that follow
+                share.max(1).min(remaining - (n_groups - 1 - ix))
+            };
+            remaining -= group.count;
+        }
+        Ok(())
+    }
+}
+
+impl SimulationConfig {
+    fn validate(&self) -> Result<()> {
+        if !is_positive(self.fixed_dt) {
+            bail!("fixed_dt must be positive, got {}", self.fixed_dt);
+        }
+        if !is_non_negative(self.time_scale) {
+            bail!("time_scale can not be negative, got {}", self.time_scale);
+        }
+        if self.max_substeps == 0 {
+            bail!("max_substeps must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+impl DynamicsConfig {
+    fn validate(&self) -> Result<()> {
+        self.model()?.validate(&self.params)?;
+        if self.equations.is_some() && self.model != "equations" {
+            bail!("[dynamics.equations] are only used with model = \"equations\"");
+        }
+        if self.script.is_some() && self.model != "script" {
+            bail!("script is only used with model = \"script\"");
+        }
+        if let integrator::Method::DormandPrince { rtol, atol } = self.integrator {
+            if !is_positive(rtol) || !is_positive(atol) {
+                bail!("Integrator tolerances must be positive");
+            }
+        }
+        Ok(())
+    }
+
+    /// The model, from the registry or, for `model = "equations"` and
+    /// `model = "script"`, compiled from the scene's equations or script.
+    /// It's built once and reused until the settings change.
+    pub fn model(&self) -> Result<Arc<registry::Model>> {
+        Ok(self.resolve()?.built)
+    }
+
+    /// The script of `model = "script"`, loaded along with the model
+    pub fn script(&self) -> Result<Option<Arc<script::Script>>> {
+        Ok(self.resolve()?.script)
+    }
+
+    fn resolve(&self) -> Result<Resolved> {
+        let mut resolved = self.resolved.0.lock().unwrap();
+        if let Some(resolved) = &*resolved {
+            if resolved.model == self.model
+                && resolved.script_path == self.script
+                && resolved.params == self.params
+                && resolved.equations == self.equations
+            {
+                return Ok(resolved.clone());
+            }
+        }
+        let (built, script) = match self.model.as_str() {
+            "script" => {
+                let script = self.load_script()?;
+                let built = Arc::new(script::model(script.clone(), &self.params));
+                (built, Some(script))
+            }
+            _ => (self.build_model()?, None),
+        };
+        let fresh = Resolved {
+            model: self.model.clone(),
+            script_path: self.script.clone(),
+            params: self.params.clone(),
+            equations: self.equations.clone(),
+            built,
+            script,
+        };
+        *resolved = Some(fresh.clone());
+        Ok(fresh)
+    }
+
+    fn build_model(&self) -> Result<Arc<registry::Model>> {
+        if self.model != "equations" {
+            return registry::get(&self.model);
+        }
+        let equations = match &self.equations {
+            Some(equations) => equations,
+            None => bail!("model = \"equations\" needs dx, dy and dz in [dynamics.equations]"),
+        };
+        let compiled = expression::Equations::compile(
+            &equations.dx,
+            &equations.dy,
+            &equations.dz,
+            &self.params,
+        )?;
+        Ok(Arc::new(expression::model(compiled, &self.params)))
+    }
+
+    // make the script's path absolute, so it still points to the same file
+    // when the scene is saved somewhere else (e.g. next to a screenshot)
+    fn resolve_paths(&mut self, dir: &Path) -> Result<()> {
+        if let Some(script) = &self.script {
+            if Path::new(script).is_relative() {
+                let dir = std::env::current_dir()?.join(dir);
+                self.script = Some(dir.join(script).to_string_lossy().into_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn load_script(&self) -> Result<Arc<script::Script>> {
+        match &self.script {
+            Some(path) => Ok(Arc::new(script::Script::load(path, &self.params)?)),
+            None => {
+                bail!("model = \"script\" needs the path of a script, e.g. script = \"swirl.rhai\"")
+            }
+        }
+    }
+
+    /// The value of a parameter, falling back to the model's default
+    pub fn param(&self, name: &str) -> Result<f64> {
+        match self.params.get(name) {
+            Some(value) => Ok(*value),
+            None => Ok(self.model()?.default_value(name)),
+        }
+    }
+
+    /// Instantiate the model for one particle, for many particles use
+    /// `model()` once rather than taking its lock for each.
+    pub fn build(
+        &self,
+        position: cgmath::Vector3<f64>,
+        chaos: &mut Chaos,
+    ) -> Result<Box<dyn dynamics::DynamicSystem>> {
+        Ok(self
+            .model()?
+            .build(&self.params, position, self.integrator, chaos))
+    }
+
+    /// Whether the model jumps from point to point rather than flowing,
+    /// its tails are drawn as points
+    pub fn is_discrete(&self) -> Result<bool> {
+        Ok(self.model()?.discrete)
+    }
+
+    /// Whether the model's particles act on each other, see
+    /// `build_interaction`
+    pub fn is_interacting(&self) -> Result<bool> {
+        Ok(self.model()?.is_interacting())
+    }
+
+    /// Set up the interaction between all particles, for models where they
+    /// act on each other
+    pub fn build_interaction(
+        &self,
+        bodies: &mut [interaction::Body],
+        chaos: &mut Chaos,
+    ) -> Result<Option<Box<dyn interaction::Interaction>>> {
+        Ok(self.model()?.build_interaction(&self.params, bodies, chaos))
+    }
+}
+
+impl GroupConfig {
+    fn validate(&self) -> Result<()> {
+        if self.count == 0 {
+            bail!("count must be at least 1");
+        }
+        if !is_positive(self.radius) {
+            bail!("radius must be positive, got {}", self.radius);
+        }
+        if !is_positive(self.mass) || !self.mass.is_finite() {
+            bail!("mass must be a positive number, got {}", self.mass);
+        }
+        if !self.velocity.iter().all(|v| v.is_finite()) {
+            bail!("velocity must be finite");
+        }
+        if !(0.0..=1.0).contains(&self.enable_probability) {
+            bail!(
+                "enable_probability must be between 0 and 1, got {}",
+                self.enable_probability
+            );
+        }
+        match self.spawn {
+            SpawnConfig::Cube { half_width, .. } if !is_non_negative(half_width) => {
+                bail!("spawn half_width can not be negative")
+            }
+            SpawnConfig::Ball { radius, .. } if !is_non_negative(radius) => {
+                bail!("spawn radius can not be negative")
+            }
+            _ => {}
+        }
+        if let ColorConfig::Palette { colors } = &self.color {
+            if colors.is_empty() {
+                bail!("color palette can not be empty");
+            }
+        }
+        if self.tail.capacity < 2 {
+            bail!("tail capacity must be at least 2");
+        }
+        if self.tail.period == 0 {
+            bail!("tail period must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+impl SpawnConfig {
+    pub fn sample(&self, chaos: &mut Chaos) -> cgmath::Vector3<f64> {
+        match self {
+            SpawnConfig::Cube { center, half_width } => {
+                cgmath::Vector3::from(*center) + chaos.random_position_in_cube(*half_width)
+            }
+            SpawnConfig::Ball { center, radius } => {
+                cgmath::Vector3::from(*center) + chaos.random_position_in_ball(*radius)
+            }
+        }
+    }
+}
+
+impl ColorConfig {
+    pub fn sample(&self, chaos: &mut Chaos) -> [f32; 4] {
+        match self {
+            ColorConfig::Random => chaos.random_solid_color(),
+            ColorConfig::Solid { rgba } => *rgba,
+            ColorConfig::Palette { colors } => colors[chaos.index(colors.len())],
+        }
+    }
+}
+
+impl CameraConfig {
+    pub fn camera(&self) -> camera::Camera {
+        camera::Camera::new(
+            self.position,
+            cgmath::Deg(self.yaw),
+            cgmath::Deg(self.pitch),
+        )
+    }
+
+    pub fn projection(&self, width: u32, height: u32) -> camera::Projection {
+        camera::Projection::new(width, height, cgmath::Deg(self.fovy), self.znear, self.zfar)
+    }
+
+    pub fn controller(&self) -> camera::CameraController {
+        camera::CameraController::new(self.speed, self.sensitivity)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !is_positive(self.fovy) || self.fovy >= 180.0 {
+            bail!("fovy must be between 0 and 180 degrees, got {}", self.fovy);
+        }
+        if !is_positive(self.znear) || !is_positive(self.zfar - self.znear) {
+            bail!("Need 0 < znear < zfar");
+        }
+        Ok(())
+    }
+}
+
+impl PostConfig {
+    fn validate(&self) -> Result<()> {
+        if !is_non_negative(self.threshold) {
+            bail!("threshold can not be negative, got {}", self.threshold);
+        }
+        if !is_non_negative(self.intensity) {
+            bail!("intensity can not be negative, got {}", self.intensity);
+        }
+        if !is_positive(self.radius) {
+            bail!("radius must be positive, got {}", self.radius);
+        }
+        if self.levels == 0 || self.levels > 12 {
+            bail!("levels must be between 1 and 12, got {}", self.levels);
+        }
+        if !self.exposure.is_finite() {
+            bail!("exposure must be a finite number");
+        }
+        Ok(())
+    }
+}
+
+// these are false for NaN, unlike their negated counterparts
+fn is_positive<T: Into<f64>>(x: T) -> bool {
+    x.into() > 0.0
+}
+
+fn is_non_negative<T: Into<f64>>(x: T) -> bool {
+    x.into() >= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_scene_parses() {
+        let scene = Scene::parse(include_str!("../scenes/lorenz.toml")).unwrap();
+        assert_eq!(scene.dynamics.model, "lorenz");
+        assert_eq!(scene.groups[0].count, 1000);
+        assert_eq!(scene.dynamics.param("rho").unwrap(), 8.0);
+
+        let scene = Scene::parse(include_str!("../scenes/aizawa.toml")).unwrap();
+        scene.validate().unwrap();
+        assert_eq!(scene.dynamics.param("scale").unwrap(), 2.5);
+
+        for text in [
+            include_str!("../scenes/magnetic_bottle.toml"),
+            include_str!("../scenes/radiation_belts.toml"),
+            include_str!("../scenes/clifford.toml"),
+            include_str!("../scenes/equations.toml"),
+            include_str!("../scenes/noisy_lorenz.toml"),
+            include_str!("../scenes/langevin.toml"),
+        ]
+        .iter()
+        {
+            Scene::parse(text).unwrap();
+        }
+
+        for text in [
+            include_str!("../scenes/galaxies.toml"),
+            include_str!("../scenes/figure_eight.toml"),
+            include_str!("../scenes/flock.toml"),
+        ]
+        .iter()
+        {
+            let scene = Scene::parse(text).unwrap();
+            assert!(scene.dynamics.is_interacting().unwrap());
+        }
+    }
+
+    #[test]
+    fn models_are_compiled_once() {
+        let mut scene = Scene::parse(include_str!("../scenes/equations.toml")).unwrap();
+        let model = scene.dynamics.model().unwrap();
+        assert!(Arc::ptr_eq(&model, &scene.dynamics.model().unwrap()));
+        assert!(Arc::ptr_eq(
+            &model,
+            &scene.clone().dynamics.model().unwrap()
+        ));
+
+        scene.dynamics.equations.as_mut().unwrap().dz = "-z".to_string();
+        assert!(!Arc::ptr_eq(&model, &scene.dynamics.model().unwrap()));
+    }
+
+    #[test]
+    fn script_paths_are_relative_to_the_scene() {
+        let scene = Scene::load(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/swirl.toml")).unwrap();
+        let script = scene.dynamics.script.clone().unwrap();
+        assert!(Path::new(&script).is_absolute());
+        assert!(script.ends_with("swirl.rhai"));
+        // so a copy saved anywhere else still finds the script
+        Scene::parse(&scene.to_toml().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn scene_round_trips() {
+        let scene = Scene {
+            seed: Some(1234),
+            ..Scene::default()
+        };
+        let text = scene.to_toml().unwrap();
+        let parsed = Scene::parse(&text).unwrap();
+        assert_eq!(parsed.seed, Some(1234));
+        assert_eq!(parsed.groups.len(), 1);
+
+        // equations are saved along with screenshots too
+        let scene = Scene::parse(include_str!("../scenes/equations.toml")).unwrap();
+        let parsed = Scene::parse(&scene.to_toml().unwrap()).unwrap();
+        assert_eq!(
+            parsed.dynamics.equations.unwrap().dx,
+            scene.dynamics.equations.unwrap().dx
+        );
+
+        // scenes saved before bloom was added
+        let old = Scene::parse("[post]\nblur = true\n").unwrap();
+        assert!(old.post.bloom);
+
+        let relative = Scene::parse("[render]\ncamera_relative = true\n").unwrap();
+        assert!(relative.render.camera_relative);
+        let relative_on_gpu =
+            "[simulation]\nbackend = \"gpu\"\n\n[render]\ncamera_relative = true\n";
+        assert!(Scene::parse(relative_on_gpu).is_err());
+
+        let additive = Scene::parse("[render]\ntail_blend = \"additive\"\n").unwrap();
+        assert_eq!(additive.render.tail_blend, util::BlendMode::Additive);
+        assert!(!additive.render.tail_blend.writes_depth());
+    }
+
+    #[test]
+    fn particle_count_is_split_across_groups() {
+        let mut scene = Scene::default();
+        scene.groups.push(GroupConfig {
+            count: 3000,
+            ..GroupConfig::default()
+        });
+        scene.set_particle_count(400).unwrap();
+        assert_eq!(scene.groups[0].count, 100);
+        assert_eq!(scene.groups[1].count, 300);
+        assert!(scene.set_particle_count(1).is_err());
+    }
+
+    #[test]
+    fn bad_scenes_are_rejected() {
+        let unknown_param = "[dynamics]\nmodel = \"lorenz\"\nparams = { sigm = 10.0 }\n";
+        let err = format!("{:#}", Scene::parse(unknown_param).unwrap_err());
+        assert!(err.contains("sigm"), "{}", err);
+
+        let unknown_model = "[dynamics]\nmodel = \"lorentz\"\n";
+        assert!(Scene::parse(unknown_model).is_err());
+
+        let empty_group = "[[group]]\ncount = 0\n";
+        let err = format!("{:#}", Scene::parse(empty_group).unwrap_err());
+        assert!(err.contains("group"), "{}", err);
+
+        let typo = "[camera]\nfov = 45.0\n";
+        assert!(Scene::parse(typo).is_err());
+
+        let adaptive_on_gpu = "[simulation]\nbackend = \"gpu\"\n\n[dynamics]\nintegrator = { method = \"dormand_prince\", rtol = 1e-4, atol = 1e-6 }\n";
+        let err = format!("{:#}", Scene::parse(adaptive_on_gpu).unwrap_err());
+        assert!(err.contains("adaptive"), "{}", err);
+
+        let typo_in_equations = "[dynamics]\nmodel = \"equations\"\nparams = { a = 1.0 }\n\n[dynamics.equations]\ndx = \"a * y\"\ndy = \"-a * x\"\ndz = \"a * (x - zz)\"\n";
+        let err = format!("{:#}", Scene::parse(typo_in_equations).unwrap_err());
+        assert!(
+            err.contains("In dz") && err.contains("Column 10: unknown name 'zz'"),
+            "{}",
+            err
+        );
+
+        let no_levels = "[post]\nbloom = true\nlevels = 0\n";
+        let err = format!("{:#}", Scene::parse(no_levels).unwrap_err());
+        assert!(err.contains("[post]"), "{}", err);
+    }
+}
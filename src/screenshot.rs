@@ -1,11 +1,46 @@
+use anyhow::*;
 use chrono::Utc;
+use std::path::Path;
 
+use crate::exr;
+use crate::scene;
 use crate::texture;
 
 pub struct ScreenShot {
     size: winit::dpi::PhysicalSize<u32>,
+    format: wgpu::TextureFormat,
     output_buffer: wgpu::Buffer,
     pub output_texture: texture::Texture,
+    // untonemapped copy of the frame, if it is wanted
+    hdr_buffer: Option<wgpu::Buffer>,
+}
+
+/// Which format to save untonemapped screenshots in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HdrFormat {
+    Exr,
+    Radiance,
+}
+
+impl std::str::FromStr for HdrFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "exr" => Ok(HdrFormat::Exr),
+            "hdr" => Ok(HdrFormat::Radiance),
+            _ => Err(anyhow!("Expected exr or hdr, got '{}'", s)),
+        }
+    }
+}
+
+impl HdrFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            HdrFormat::Exr => "exr",
+            HdrFormat::Radiance => "hdr",
+        }
+    }
 }
 
 pub fn build_path() -> std::path::PathBuf {
@@ -20,84 +55,218 @@ pub fn build_path() -> std::path::PathBuf {
     fullpath
 }
 
+/// Save the scene (including its seed) next to a screenshot so that it can
+/// be regenerated later
+pub fn save_scene<P: AsRef<std::path::Path>>(path: P, scene: &scene::Scene) -> Result<()> {
+    let scene_path = path.as_ref().with_extension("toml");
+    std::fs::write(scene_path, scene.to_toml()?)?;
+    Ok(())
+}
+
+// texture to buffer copies need rows padded to a multiple of 256 bytes
+fn padded_bytes_per_row(width: u32, bytes_per_pixel: u32) -> u32 {
+    let unpadded = bytes_per_pixel * width;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    unpadded + (align - unpadded % align) % align
+}
+
 impl ScreenShot {
     pub fn init(
         size: winit::dpi::PhysicalSize<u32>,
         format: wgpu::TextureFormat,
         device: &wgpu::Device,
     ) -> Self {
-        let u32_size = std::mem::size_of::<u32>() as u32;
-
-        let output_buffer_size = (u32_size * size.width * size.height) as wgpu::BufferAddress;
-        let output_buffer_desc = wgpu::BufferDescriptor {
-            size: output_buffer_size,
-            // this tells wpgu that we want to read this buffer from the cpu
-            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
-            label: None,
-            mapped_at_creation: false,
-        };
-        let output_buffer = device.create_buffer(&output_buffer_desc);
+        let output_buffer = create_read_buffer(device, size, 4);
 
         let output_texture = texture::Texture::create_target_texture(&device, size, format);
 
         Self {
             size,
+            format,
             output_buffer,
             output_texture,
+            hdr_buffer: None,
         }
     }
 
-    pub fn copy_back_buffer(&mut self, encoder: &mut wgpu::CommandEncoder) {
-        let u32_size = std::mem::size_of::<u32>() as u32;
-        let bytes_per_row =
-            unsafe { std::num::NonZeroU32::new_unchecked(u32_size * self.size.width) };
-        let rows_per_image = unsafe { std::num::NonZeroU32::new_unchecked(self.size.height) };
-        let texture_size = wgpu::Extent3d {
-            width: self.size.width,
-            height: self.size.height,
-            depth_or_array_layers: 1,
-        };
+    /// Also keep the untonemapped frame, see `copy_hdr_buffer`
+    pub fn with_hdr(mut self, device: &wgpu::Device) -> Self {
+        self.hdr_buffer = Some(create_read_buffer(device, self.size, HDR_BYTES_PER_PIXEL));
+        self
+    }
 
-        encoder.copy_texture_to_buffer(
-            wgpu::ImageCopyTexture {
-                texture: &self.output_texture.texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            wgpu::ImageCopyBuffer {
-                buffer: &self.output_buffer,
-                layout: wgpu::ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row: Some(bytes_per_row),
-                    rows_per_image: Some(rows_per_image),
-                },
-            },
-            texture_size,
+    pub fn copy_back_buffer(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        copy_to_buffer(
+            encoder,
+            &self.output_texture.texture,
+            &self.output_buffer,
+            self.size,
+            4,
         );
     }
 
-    pub fn save<P: AsRef<std::path::Path>>(&self, device: &wgpu::Device, path: P) {
-        {
-            let buffer_slice = self.output_buffer.slice(..);
-
-            // NOTE: We have to create the mapping THEN device.poll() before await
-            // the future. Otherwise the application will freeze.
-            use futures::executor::block_on;
-            let f = async {
-                let mapping = buffer_slice.map_async(wgpu::MapMode::Read);
-                device.poll(wgpu::Maintain::Wait);
-                mapping.await.unwrap();
-            };
-            block_on(f);
-
-            let data = buffer_slice.get_mapped_range();
-
-            use image::{ImageBuffer, Rgba};
-            let buffer =
-                ImageBuffer::<Rgba<u8>, _>::from_raw(self.size.width, self.size.height, data)
-                    .unwrap();
-            buffer.save(path).unwrap();
+    /// Copy a `post::HDR_FORMAT` texture (the renderer's untonemapped
+    /// frame), does nothing unless made `with_hdr`
+    pub fn copy_hdr_buffer(&mut self, encoder: &mut wgpu::CommandEncoder, hdr: &wgpu::Texture) {
+        if let Some(buffer) = &self.hdr_buffer {
+            copy_to_buffer(encoder, hdr, buffer, self.size, HDR_BYTES_PER_PIXEL);
+        }
+    }
+
+    /// Read back the copied texture as tightly packed RGBA8 pixels
+    pub fn read_rgba(&self, device: &wgpu::Device) -> Result<Vec<u8>> {
+        let mut pixels = read_buffer(device, &self.output_buffer, self.size, 4)?;
+
+        // swap chains usually want BGRA
+        if let wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb = self.format {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
         }
-        self.output_buffer.unmap();
+        Ok(pixels)
     }
+
+    /// Save the untonemapped frame as OpenEXR or Radiance HDR
+    pub fn save_hdr<P: AsRef<Path>>(
+        &self,
+        device: &wgpu::Device,
+        path: P,
+        format: HdrFormat,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let buffer = self
+            .hdr_buffer
+            .as_ref()
+            .context("This screenshot was not made with HDR")?;
+        let data = read_buffer(device, buffer, self.size, HDR_BYTES_PER_PIXEL)?;
+        let halves = data
+            .chunks(2)
+            .map(|bits| u16::from_le_bytes([bits[0], bits[1]]))
+            .collect::<Vec<_>>();
+
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Could not create {}", path.display()))?;
+        let out = std::io::BufWriter::new(file);
+        match format {
+            HdrFormat::Exr => exr::write_exr(out, self.size.width, self.size.height, &halves)?,
+            HdrFormat::Radiance => {
+                let pixels = halves
+                    .chunks(4)
+                    .map(|rgba| {
+                        image::Rgb([
+                            exr::half_to_f32(rgba[0]),
+                            exr::half_to_f32(rgba[1]),
+                            exr::half_to_f32(rgba[2]),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                image::codecs::hdr::HdrEncoder::new(out).encode(
+                    &pixels,
+                    self.size.width as usize,
+                    self.size.height as usize,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, device: &wgpu::Device, path: P) -> Result<()> {
+        let pixels = self.read_rgba(device)?;
+
+        use image::{ImageBuffer, Rgba};
+        let buffer =
+            ImageBuffer::<Rgba<u8>, _>::from_raw(self.size.width, self.size.height, pixels)
+                .context("Screenshot buffer has the wrong size")?;
+        buffer
+            .save(path.as_ref())
+            .with_context(|| format!("Could not save {}", path.as_ref().display()))?;
+        Ok(())
+    }
+}
+
+const HDR_BYTES_PER_PIXEL: u32 = 8;
+
+fn create_read_buffer(
+    device: &wgpu::Device,
+    size: winit::dpi::PhysicalSize<u32>,
+    bytes_per_pixel: u32,
+) -> wgpu::Buffer {
+    let output_buffer_size =
+        (padded_bytes_per_row(size.width, bytes_per_pixel) * size.height) as wgpu::BufferAddress;
+    let output_buffer_desc = wgpu::BufferDescriptor {
+        size: output_buffer_size,
+        // this tells wpgu that we want to read this buffer from the cpu
+        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        label: None,
+        mapped_at_creation: false,
+    };
+    device.create_buffer(&output_buffer_desc)
+}
+
+fn copy_to_buffer(
+    encoder: &mut wgpu::CommandEncoder,
+    texture: &wgpu::Texture,
+    buffer: &wgpu::Buffer,
+    size: winit::dpi::PhysicalSize<u32>,
+    bytes_per_pixel: u32,
+) {
+    let bytes_per_row =
+        std::num::NonZeroU32::new(padded_bytes_per_row(size.width, bytes_per_pixel));
+    let rows_per_image = std::num::NonZeroU32::new(size.height);
+    let texture_size = wgpu::Extent3d {
+        width: size.width,
+        height: size.height,
+        depth_or_array_layers: 1,
+    };
+
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::ImageCopyBuffer {
+            buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row,
+                rows_per_image,
+            },
+        },
+        texture_size,
+    );
+}
+
+/// Map a buffer filled by `copy_to_buffer` and strip the row padding
+fn read_buffer(
+    device: &wgpu::Device,
+    buffer: &wgpu::Buffer,
+    size: winit::dpi::PhysicalSize<u32>,
+    bytes_per_pixel: u32,
+) -> Result<Vec<u8>> {
+    let pixels = {
+        let buffer_slice = buffer.slice(..);
+
+        // NOTE: We have to create the mapping THEN device.poll() before await
+        // the future. Otherwise the application will freeze.
+        use futures::executor::block_on;
+        let f = async {
+            let mapping = buffer_slice.map_async(wgpu::MapMode::Read);
+            device.poll(wgpu::Maintain::Wait);
+            mapping.await
+        };
+        block_on(f)?;
+
+        let data = buffer_slice.get_mapped_range();
+
+        let row_bytes = (bytes_per_pixel * size.width) as usize;
+        let padded_row_bytes = padded_bytes_per_row(size.width, bytes_per_pixel) as usize;
+        let mut pixels = Vec::with_capacity(row_bytes * size.height as usize);
+        for row in data.chunks(padded_row_bytes) {
+            pixels.extend_from_slice(&row[..row_bytes]);
+        }
+        pixels
+    };
+    buffer.unmap();
+    Ok(pixels)
 }
@@ -0,0 +1,579 @@
+use anyhow::*;
+use cgmath::Vector3;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use crate::clock::SimulationClock;
+use crate::dynamics::DynamicSystem;
+use crate::rand_util::Chaos;
+use crate::registry::Model;
+use crate::sphere::SphereInstance;
+
+// a script stuck in a loop is stopped after this many operations (per
+// call) instead of hanging the window
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// What a script's `step(dt)` sees as `this`: one particle, which it
+/// moves by changing `x`, `y`, `z` (and `vx`, `vy`, `vz` if it keeps a
+/// velocity)
+#[derive(Clone)]
+pub struct ScriptParticle {
+    position: Vector3<f64>,
+    velocity: Vector3<f64>,
+    t: f64,
+    chaos: Chaos,
+    params: Arc<BTreeMap<String, f64>>,
+}
+
+/// What a script's `update(dt)` sees as `this`: every particle and the
+/// clock, once per step before the particles move
+#[derive(Clone)]
+pub struct ScriptWorld {
+    time: f64,
+    steps: i64,
+    positions: Vec<Vector3<f64>>,
+    colors: Vec<[f32; 4]>,
+    enabled: Vec<bool>,
+    groups: Vec<i64>,
+    chaos: Chaos,
+    params: BTreeMap<String, f64>,
+}
+
+impl ScriptWorld {
+    fn index(&self, ix: i64) -> ScriptResult<usize> {
+        if ix >= 0 && (ix as usize) < self.positions.len() {
+            Ok(ix as usize)
+        } else {
+            Err(format!("No particle {}, there are {}", ix, self.positions.len()).into())
+        }
+    }
+}
+
+fn param(params: &BTreeMap<String, f64>, name: &str) -> ScriptResult<f64> {
+    match params.get(name) {
+        Some(value) => Ok(*value),
+        None => Err(format!("Unknown parameter '{}'", name).into()),
+    }
+}
+
+fn array<T: Copy + Into<f64>>(values: &[T]) -> Array {
+    values.iter().map(|v| Dynamic::from((*v).into())).collect()
+}
+
+/// The random functions, for both kinds of `this`
+trait HasChaos: Clone + Send + Sync + 'static {
+    fn chaos(&mut self) -> &mut Chaos;
+}
+
+impl HasChaos for ScriptParticle {
+    fn chaos(&mut self) -> &mut Chaos {
+        &mut self.chaos
+    }
+}
+
+impl HasChaos for ScriptWorld {
+    fn chaos(&mut self) -> &mut Chaos {
+        &mut self.chaos
+    }
+}
+
+fn register_random<T: HasChaos>(engine: &mut Engine) {
+    engine
+        .register_fn("noise", |this: &mut T| this.chaos().unit_noise())
+        .register_fn("chance", |this: &mut T, p: f64| this.chaos().bernoulli(p))
+        .register_fn("in_ball", |this: &mut T, radius: f64| {
+            let p = this.chaos().random_position_in_ball(radius);
+            array(&[p.x, p.y, p.z])
+        });
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    engine
+        .register_type_with_name::<ScriptParticle>("Particle")
+        .register_get_set(
+            "x",
+            |p: &mut ScriptParticle| p.position.x,
+            |p: &mut ScriptParticle, v: f64| p.position.x = v,
+        )
+        .register_get_set(
+            "y",
+            |p: &mut ScriptParticle| p.position.y,
+            |p: &mut ScriptParticle, v: f64| p.position.y = v,
+        )
+        .register_get_set(
+            "z",
+            |p: &mut ScriptParticle| p.position.z,
+            |p: &mut ScriptParticle, v: f64| p.position.z = v,
+        )
+        .register_get_set(
+            "vx",
+            |p: &mut ScriptParticle| p.velocity.x,
+            |p: &mut ScriptParticle, v: f64| p.velocity.x = v,
+        )
+        .register_get_set(
+            "vy",
+            |p: &mut ScriptParticle| p.velocity.y,
+            |p: &mut ScriptParticle, v: f64| p.velocity.y = v,
+        )
+        .register_get_set(
+            "vz",
+            |p: &mut ScriptParticle| p.velocity.z,
+            |p: &mut ScriptParticle, v: f64| p.velocity.z = v,
+        )
+        .register_get("t", |p: &mut ScriptParticle| p.t)
+        .register_fn("param", |p: &mut ScriptParticle, name: &str| {
+            param(&p.params, name)
+        });
+    register_random::<ScriptParticle>(&mut engine);
+
+    engine
+        .register_type_with_name::<ScriptWorld>("World")
+        .register_get("time", |w: &mut ScriptWorld| w.time)
+        .register_get("steps", |w: &mut ScriptWorld| w.steps)
+        .register_get("count", |w: &mut ScriptWorld| w.positions.len() as i64)
+        .register_fn("param", |w: &mut ScriptWorld, name: &str| {
+            param(&w.params, name)
+        })
+        .register_fn(
+            "set_param",
+            |w: &mut ScriptWorld, name: &str, value: f64| match w.params.get_mut(name) {
+                Some(param) => {
+                    *param = value;
+                    ScriptResult::Ok(())
+                }
+                None => Err(format!("Unknown parameter '{}'", name).into()),
+            },
+        )
+        .register_fn("position", |w: &mut ScriptWorld, ix: i64| {
+            let p = w.positions[w.index(ix)?];
+            ScriptResult::Ok(array(&[p.x, p.y, p.z]))
+        })
+        .register_fn(
+            "set_position",
+            |w: &mut ScriptWorld, ix: i64, x: f64, y: f64, z: f64| {
+                let ix = w.index(ix)?;
+                w.positions[ix] = Vector3::new(x, y, z);
+                ScriptResult::Ok(())
+            },
+        )
+        .register_fn("color", |w: &mut ScriptWorld, ix: i64| {
+            ScriptResult::Ok(array(&w.colors[w.index(ix)?]))
+        })
+        .register_fn(
+            "set_color",
+            |w: &mut ScriptWorld, ix: i64, r: f64, g: f64, b: f64, a: f64| {
+                let ix = w.index(ix)?;
+                w.colors[ix] = [r as f32, g as f32, b as f32, a as f32];
+                ScriptResult::Ok(())
+            },
+        )
+        .register_fn("group", |w: &mut ScriptWorld, ix: i64| {
+            ScriptResult::Ok(w.groups[w.index(ix)?])
+        })
+        .register_fn("enabled", |w: &mut ScriptWorld, ix: i64| {
+            ScriptResult::Ok(w.enabled[w.index(ix)?])
+        })
+        .register_fn("enable", |w: &mut ScriptWorld, ix: i64| {
+            let ix = w.index(ix)?;
+            w.enabled[ix] = true;
+            ScriptResult::Ok(())
+        })
+        .register_fn("disable", |w: &mut ScriptWorld, ix: i64| {
+            let ix = w.index(ix)?;
+            w.enabled[ix] = false;
+            ScriptResult::Ok(())
+        });
+    register_random::<ScriptWorld>(&mut engine);
+    engine
+}
+
+// a version of the script
+struct Compiled {
+    ast: AST,
+    has_update: bool,
+    // when the file was last changed
+    modified: Option<SystemTime>,
+}
+
+/// Particle dynamics and scene logic written in [Rhai](https://rhai.rs)
+///
+/// The script defines `fn step(dt)`, called every step for every particle
+/// with the particle as `this`, and optionally `fn update(dt)`, called
+/// once per step before that with the whole simulation as `this`.  Both
+/// can read the scene's parameters with `this.param("name")`, and
+/// `update` can change them with `this.set_param("name", value)`.  When
+/// loaded from a file the script is reloaded whenever the file changes,
+/// see `reload_if_changed`.
+///
+/// The particles are the fixed pool of the scene's groups: `update` can
+/// `enable(i)` and `disable(i)` them and move them with
+/// `set_position(i, x, y, z)`, which is how a script spawns and kills
+/// particles, but it can't add particles beyond the groups' `count`.
+pub struct Script {
+    path: Option<PathBuf>,
+    engine: Engine,
+    compiled: RwLock<Arc<Compiled>>,
+    params: RwLock<Arc<BTreeMap<String, f64>>>,
+    // runtime errors are logged once per version of the script, rather
+    // than for every particle on every step
+    failed: AtomicBool,
+}
+
+impl Script {
+    /// Load and compile a script file
+    pub fn load<P: AsRef<Path>>(path: P, params: &BTreeMap<String, f64>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut script = Self::new(params);
+        script.path = Some(path.to_path_buf());
+        let compiled = script
+            .compile_file(path)
+            .with_context(|| format!("Invalid script {}", path.display()))?;
+        script.compiled = RwLock::new(Arc::new(compiled));
+        Ok(script)
+    }
+
+    /// Compile a script that isn't in a file, it can't be reloaded
+    pub fn from_source(source: &str, params: &BTreeMap<String, f64>) -> Result<Self> {
+        let script = Self::new(params);
+        let compiled = script.compile(source, None)?;
+        *script.compiled.write().unwrap() = Arc::new(compiled);
+        Ok(script)
+    }
+
+    fn new(params: &BTreeMap<String, f64>) -> Self {
+        let engine = engine();
+        let empty = Compiled {
+            ast: AST::empty(),
+            has_update: false,
+            modified: None,
+        };
+        Self {
+            path: None,
+            engine,
+            compiled: RwLock::new(Arc::new(empty)),
+            params: RwLock::new(Arc::new(params.clone())),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    fn compile_file(&self, path: &Path) -> Result<Compiled> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read script {}", path.display()))?;
+        self.compile(&source, modified)
+    }
+
+    fn compile(&self, source: &str, modified: Option<SystemTime>) -> Result<Compiled> {
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|err| anyhow!("{}", err))?;
+        let takes_dt = |name: &str| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == 1)
+        };
+        if !takes_dt("step") {
+            bail!("The script needs a fn step(dt)");
+        }
+        Ok(Compiled {
+            has_update: takes_dt("update"),
+            ast,
+            modified,
+        })
+    }
+
+    /// Compile the file again if it changed since it was last loaded.  If
+    /// it doesn't compile the error is logged and the old version stays.
+    pub fn reload_if_changed(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == self.compiled.read().unwrap().modified {
+            return;
+        }
+        match self.compile_file(path) {
+            Ok(compiled) => {
+                *self.compiled.write().unwrap() = Arc::new(compiled);
+                self.failed.store(false, Ordering::Relaxed);
+                log::info!("Reloaded {}", path.display());
+            }
+            Err(err) => {
+                log::error!("Could not reload {}: {:#}", path.display(), err);
+                // don't try again until it changes again
+                let mut compiled = self.compiled.write().unwrap();
+                *compiled = Arc::new(Compiled {
+                    ast: compiled.ast.clone(),
+                    has_update: compiled.has_update,
+                    modified,
+                });
+            }
+        }
+    }
+
+    /// The scene's parameter values, as the script left them
+    pub fn params(&self) -> Arc<BTreeMap<String, f64>> {
+        self.params.read().unwrap().clone()
+    }
+
+    fn call<T: Clone + Send + Sync + 'static>(&self, name: &str, this: T, dt: f64) -> Option<T> {
+        let compiled = self.compiled.read().unwrap().clone();
+        let mut this = Dynamic::from(this);
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut this);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &compiled.ast,
+            name,
+            (dt,),
+        );
+        match result {
+            Ok(_) => this.try_cast::<T>(),
+            Err(err) => {
+                if !self.failed.swap(true, Ordering::Relaxed) {
+                    let path = match &self.path {
+                        Some(path) => path.display().to_string(),
+                        None => "script".to_string(),
+                    };
+                    log::error!("In {} of {}: {}", name, path, err);
+                }
+                None
+            }
+        }
+    }
+
+    /// Run the script's `step` for one particle.  On an error the particle
+    /// stays where it is.
+    pub fn step(&self, particle: &mut Scripted, dt: f64, chaos: &mut Chaos) {
+        let this = ScriptParticle {
+            position: particle.position,
+            velocity: particle.velocity,
+            t: particle.t,
+            chaos: chaos.clone(),
+            params: self.params(),
+        };
+        if let Some(this) = self.call("step", this, dt) {
+            particle.position = this.position;
+            particle.velocity = this.velocity;
+            *chaos = this.chaos;
+        }
+    }
+
+    /// Run the script's `update`, if it has one, and apply what it changed
+    /// to the particles and parameters
+    pub fn update(
+        &self,
+        sphere_instances: &mut [SphereInstance],
+        clock: &SimulationClock,
+        chaos: &mut Chaos,
+    ) {
+        if !self.compiled.read().unwrap().has_update {
+            return;
+        }
+        let positions = sphere_instances
+            .iter()
+            .map(|s| s.dynamics.get_position())
+            .collect::<Vec<_>>();
+        let this = ScriptWorld {
+            time: clock.time(),
+            steps: clock.steps() as i64,
+            positions: positions.clone(),
+            colors: sphere_instances.iter().map(|s| s.color).collect(),
+            enabled: sphere_instances.iter().map(|s| s.enabled).collect(),
+            groups: sphere_instances.iter().map(|s| s.group as i64).collect(),
+            chaos: chaos.clone(),
+            params: self.params().as_ref().clone(),
+        };
+        let world = match self.call("update", this, clock.fixed_dt) {
+            Some(world) => world,
+            None => return,
+        };
+        for (ix, s) in sphere_instances.iter_mut().enumerate() {
+            if world.positions[ix] != positions[ix] {
+                s.dynamics.set_position(world.positions[ix]);
+            }
+            s.color = world.colors[ix];
+            s.enabled = world.enabled[ix];
+        }
+        *chaos = world.chaos;
+        *self.params.write().unwrap() = Arc::new(world.params);
+    }
+}
+
+/// A particle moved by a script
+pub struct Scripted {
+    pub script: Arc<Script>,
+    pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    pub t: f64,
+}
+
+impl DynamicSystem for Scripted {
+    fn step(&mut self, dt: f64, chaos: &mut Chaos) {
+        let script = self.script.clone();
+        script.step(self, dt, chaos);
+        self.t += dt;
+    }
+
+    fn get_position(&self) -> Vector3<f64> {
+        self.position
+    }
+
+    fn set_position(&mut self, position: Vector3<f64>) {
+        self.position = position;
+    }
+}
+
+/// A model for a scene's script, which like `expression::model` only
+/// exists once the scene is loaded.  Its parameters are the scene's.
+pub fn model(script: Arc<Script>, params: &BTreeMap<String, f64>) -> Model {
+    let mut model = Model::new(
+        "script",
+        "The scene's own script",
+        move |_params, position, _method, _chaos| {
+            Box::new(Scripted {
+                script: script.clone(),
+                position,
+                velocity: Vector3::new(0.0, 0.0, 0.0),
+                t: 0.0,
+            })
+        },
+    );
+    for (name, value) in params.iter() {
+        model = model.param(name, *value, f64::MIN..=f64::MAX);
+    }
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Scene;
+    use crate::simulation::Simulation;
+
+    fn params() -> BTreeMap<String, f64> {
+        let mut params = BTreeMap::new();
+        params.insert("speed".to_string(), 2.0);
+        params
+    }
+
+    fn particle(script: Script) -> Scripted {
+        Scripted {
+            script: Arc::new(script),
+            position: Vector3::new(1.0, 0.0, 0.0),
+            velocity: Vector3::new(0.0, 0.0, 0.0),
+            t: 0.0,
+        }
+    }
+
+    #[test]
+    fn scripts_step_particles() {
+        let source = "fn step(dt) { this.vy -= dt; this.x += this.param(\"speed\") * dt; this.y += this.vy * dt; }";
+        let mut p = particle(Script::from_source(source, &params()).unwrap());
+        let mut chaos = Chaos::from_seed(1);
+        for _ in 0..10 {
+            p.step(0.5, &mut chaos);
+        }
+        assert_eq!(p.position.x, 11.0);
+        assert_eq!(p.velocity.y, -5.0);
+        assert_eq!(p.t, 5.0);
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let err = match Script::from_source("fn step(dt) {\n    this.x += ;\n}", &params()) {
+            Ok(_) => panic!("compiled"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(Script::from_source("fn update(dt) {}", &params()).is_err());
+
+        // a runtime error leaves the particle where it was
+        let source = "fn step(dt) { this.x += this.param(\"sped\"); }";
+        let mut p = particle(Script::from_source(source, &params()).unwrap());
+        p.step(0.5, &mut Chaos::from_seed(1));
+        assert_eq!(p.position.x, 1.0);
+    }
+
+    #[test]
+    fn update_changes_particles_and_params() {
+        let path = std::env::temp_dir().join(format!("wagoo_test_{}.rhai", std::process::id()));
+        let source = "
+            fn step(dt) { this.x += this.param(\"speed\") * dt; }
+            fn update(dt) {
+                this.disable(0);
+                this.enable(1);
+                this.set_position(1, 0.0, 0.0, 0.0);
+                this.set_color(1, 1.0, 0.0, 0.0, 1.0);
+                this.set_param(\"speed\", this.param(\"speed\") + 1.0);
+            }
+        ";
+        std::fs::write(&path, source).unwrap();
+        let text = format!(
+            "[dynamics]\nmodel = \"script\"\nscript = {:?}\nparams = {{ speed = 1.0 }}\n\n[[group]]\ncount = 2\nenable_probability = 0.0\n",
+            path.display().to_string()
+        );
+        let mut scene = Scene::parse(&text).unwrap();
+        let mut simulation = Simulation::new(&mut scene).unwrap();
+        simulation.sphere_instances[0].enabled = true;
+        simulation.step();
+        let [a, b] = [
+            &simulation.sphere_instances[0],
+            &simulation.sphere_instances[1],
+        ];
+        assert!(!a.enabled && b.enabled);
+        assert_eq!(b.color, [1.0, 0.0, 0.0, 1.0]);
+        // moved from the origin at the new speed
+        let dt = simulation.clock.fixed_dt;
+        assert!((b.position()[0] - 2.0 * dt).abs() < 1e-6);
+
+        // and the file is picked up again when it changes
+        std::fs::write(&path, "fn step(dt) { this.x = 5.0; }").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        simulation.advance(std::time::Duration::from_secs_f64(dt * 1.5));
+        assert_eq!(simulation.sphere_instances[1].position()[0], 5.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn example_script_runs() {
+        let mut scene =
+            Scene::load(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/swirl.toml")).unwrap();
+        scene.seed = Some(5);
+        scene.groups[0].enable_probability = 1.0;
+        scene.groups[0].count = 100;
+        let mut simulation = Simulation::new(&mut scene).unwrap();
+        simulation.step();
+        let before = simulation
+            .sphere_instances
+            .iter()
+            .map(|s| s.position())
+            .collect::<Vec<_>>();
+        for _ in 0..100 {
+            simulation.step();
+        }
+        for (s, before) in simulation.sphere_instances.iter().zip(before.iter()) {
+            let p = s.position();
+            assert!(p.iter().all(|x| x.is_finite() && x.abs() < 10.0), "{:?}", p);
+            assert_ne!(p, *before);
+        }
+    }
+}
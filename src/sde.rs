@@ -0,0 +1,353 @@
+use cgmath::{ElementWise, Vector3, Zero};
+
+use crate::dynamics::{DynamicSystem, Lorenz};
+use crate::integrator::{Integrator, Method, VectorField};
+use crate::rand_util::{Chaos, OrnsteinUhlenbeck};
+use crate::registry::{Model, Params};
+
+/// A stochastic differential equation with diagonal noise,
+/// dX = f(X, t) dt + g(X, t) dW, each component of X getting its own
+/// Wiener process
+pub trait StochasticField: Send + Sync {
+    /// f, the deterministic part
+    fn drift(&self, state: Vector3<f64>, t: f64) -> Vector3<f64>;
+    /// g, how strongly each component is kicked around.  The spread of a
+    /// particle grows like g^2 t, whatever the step size.
+    fn diffusion(&self, state: Vector3<f64>, t: f64) -> Vector3<f64>;
+    /// dg_i/dx_i, for Milstein.  Zero for additive noise, where Milstein
+    /// is the same as Euler-Maruyama.
+    fn diffusion_derivative(&self, _state: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::zero()
+    }
+}
+
+// the drift as a vector field, for the deterministic integrators
+struct Drift<'a, F: StochasticField>(&'a F);
+
+impl<F: StochasticField> VectorField for Drift<'_, F> {
+    fn derivative(&self, state: Vector3<f64>, t: f64) -> Vector3<f64> {
+        self.0.drift(state, t)
+    }
+}
+
+/// A particle following an SDE
+///
+/// With `Method::EulerMaruyama` or `Method::Milstein` the whole step is
+/// done by that scheme.  The other methods integrate the drift as usual
+/// and add the Euler-Maruyama noise on top.  With `colored` set, the white
+/// noise dW is replaced by an Ornstein-Uhlenbeck process times dt.
+pub struct Stochastic<F: StochasticField> {
+    pub field: F,
+    pub integrator: Integrator,
+    pub position: Vector3<f64>,
+    pub t: f64,
+    pub colored: Option<OrnsteinUhlenbeck>,
+}
+
+impl<F: StochasticField> Stochastic<F> {
+    /// `correlation_time` 0 gives white noise, longer ones give colored
+    /// noise that diffuses as much over times longer than it
+    pub fn new(
+        field: F,
+        position: Vector3<f64>,
+        method: Method,
+        correlation_time: f64,
+        chaos: &mut Chaos,
+    ) -> Self {
+        // unit intensity: the correlation (1 / 2 tau) exp(-|s| / tau)
+        // integrates to 1, like the delta function of white noise
+        let colored = if correlation_time > 0.0 {
+            let sigma = (0.5 / correlation_time).sqrt();
+            Some(OrnsteinUhlenbeck::new(correlation_time, sigma, chaos))
+        } else {
+            None
+        };
+        Self {
+            field,
+            integrator: Integrator::new(method),
+            position,
+            t: 0.0,
+            colored,
+        }
+    }
+}
+
+impl<F: StochasticField> DynamicSystem for Stochastic<F> {
+    fn step(&mut self, dt: f64, chaos: &mut Chaos) {
+        let x = self.position;
+        let g = self.field.diffusion(x, self.t);
+        let drifted = self.integrator.advance(&Drift(&self.field), x, self.t, dt);
+        let kick = match self.colored.as_mut() {
+            Some(noise) => {
+                let eta = noise.value;
+                noise.advance(dt, chaos);
+                dt * g.mul_element_wise(eta)
+            }
+            None => {
+                let dw = dt.sqrt() * chaos.gaussian_vector();
+                let mut kick = g.mul_element_wise(dw);
+                if self.integrator.method == Method::Milstein {
+                    let dg = self.field.diffusion_derivative(x, self.t);
+                    let dw2 = dw.mul_element_wise(dw) - Vector3::new(dt, dt, dt);
+                    kick += 0.5 * g.mul_element_wise(dg).mul_element_wise(dw2);
+                }
+                kick
+            }
+        };
+        self.position = drifted + kick;
+        self.t += dt;
+    }
+
+    fn get_position(&self) -> Vector3<f64> {
+        self.position
+    }
+
+    fn set_position(&mut self, position: Vector3<f64>) {
+        self.position = position;
+    }
+}
+
+/// A deterministic vector field kicked around by noise of the same
+/// intensity everywhere
+pub struct Additive<F: VectorField> {
+    pub field: F,
+    pub noise: f64,
+}
+
+impl<F: VectorField> StochasticField for Additive<F> {
+    fn drift(&self, state: Vector3<f64>, t: f64) -> Vector3<f64> {
+        self.field.derivative(state, t)
+    }
+
+    fn diffusion(&self, _state: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        Vector3::new(self.noise, self.noise, self.noise)
+    }
+}
+
+/// Overdamped Langevin dynamics: Brownian particles in a potential with
+/// two wells at x = +-`separation`, held near the x axis by a spring
+///
+/// dX = -grad U dt + sqrt(2 T) dW, so at `temperature` T particles
+/// settle into the Boltzmann distribution exp(-U / T) and now and then
+/// hop over the `barrier` between the wells.
+pub struct DoubleWell {
+    pub barrier: f64,
+    pub separation: f64,
+    pub stiffness: f64,
+    pub temperature: f64,
+}
+
+impl StochasticField for DoubleWell {
+    fn drift(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        // U = barrier ((x / a)^2 - 1)^2 + stiffness (y^2 + z^2) / 2
+        let u = p.x / self.separation;
+        Vector3::new(
+            -4.0 * self.barrier * u * (u * u - 1.0) / self.separation,
+            -self.stiffness * p.y,
+            -self.stiffness * p.z,
+        )
+    }
+
+    fn diffusion(&self, _p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+        let g = (2.0 * self.temperature).sqrt();
+        Vector3::new(g, g, g)
+    }
+}
+
+/// A registry entry for an SDE, along with `correlation_time` for colored
+/// noise
+fn stochastic<F, B>(name: &str, description: &str, make: B) -> Model
+where
+    F: StochasticField + 'static,
+    B: Fn(&Params) -> F + Send + Sync + 'static,
+{
+    Model::new(name, description, move |params, position, method, chaos| {
+        Box::new(Stochastic::new(
+            make(params),
+            position,
+            method,
+            params.get("correlation_time"),
+            chaos,
+        ))
+    })
+    .param("correlation_time", 0.0, 0.0..=100.0)
+}
+
+/// The stochastic models, registered along with the other built in models
+pub fn models() -> Vec<Model> {
+    vec![
+        stochastic(
+            "noisy_lorenz",
+            "Lorenz attractor kicked around by noise of intensity `noise`",
+            |p| Additive {
+                field: Lorenz::new(p.get("sigma"), p.get("rho"), p.get("beta"), p.get("speed")),
+                noise: p.get("noise"),
+            },
+        )
+        .param("sigma", 18.0, 0.0..=100.0)
+        .param("rho", 8.0, 0.0..=200.0)
+        .param("beta", 8.0 / 3.0, 0.0..=20.0)
+        .param("speed", 0.1, 0.0..=10.0)
+        .param("noise", 0.5, 0.0..=100.0),
+        stochastic(
+            "langevin",
+            "Brownian particles hopping between two potential wells along x",
+            |p| DoubleWell {
+                barrier: p.get("barrier"),
+                separation: p.get("separation"),
+                stiffness: p.get("stiffness"),
+                temperature: p.get("temperature"),
+            },
+        )
+        .param("barrier", 1.0, 0.0..=100.0)
+        .param("separation", 2.0, 0.01..=100.0)
+        .param("stiffness", 1.0, 0.0..=100.0)
+        .param("temperature", 0.3, 0.0..=100.0),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // geometric Brownian motion, dX = mu X dt + s X dW, which has an exact
+    // solution to compare with
+    struct Geometric {
+        mu: f64,
+        s: f64,
+    }
+
+    impl StochasticField for Geometric {
+        fn drift(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+            self.mu * p
+        }
+
+        fn diffusion(&self, p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+            self.s * p
+        }
+
+        fn diffusion_derivative(&self, _p: Vector3<f64>, _t: f64) -> Vector3<f64> {
+            Vector3::new(self.s, self.s, self.s)
+        }
+    }
+
+    // spread of particles starting at the origin with no drift
+    fn variance_after(time: f64, dt: f64, correlation_time: f64) -> f64 {
+        let mut chaos = Chaos::from_seed(12);
+        let n_particles = 2000;
+        let mut total = 0.0;
+        for _ in 0..n_particles {
+            let field = Additive {
+                field: Lorenz::new(0.0, 0.0, 0.0, 0.0),
+                noise: 0.5,
+            };
+            let mut p = Stochastic::new(
+                field,
+                Vector3::zero(),
+                Method::EulerMaruyama,
+                correlation_time,
+                &mut chaos,
+            );
+            for _ in 0..(time / dt).round() as usize {
+                p.step(dt, &mut chaos);
+            }
+            total += p.position.x * p.position.x;
+        }
+        total / n_particles as f64
+    }
+
+    #[test]
+    fn noise_intensity_does_not_depend_on_dt() {
+        // 0.5^2 per unit time
+        for dt in [0.1, 1.0 / 60.0, 0.001].iter() {
+            let variance = variance_after(2.0, *dt, 0.0);
+            assert!(
+                (variance / 0.5 - 1.0).abs() < 0.1,
+                "dt {}: {}",
+                dt,
+                variance
+            );
+        }
+        // colored noise spreads as far over times well beyond its
+        // correlation time (minus tau, from the start being smooth)
+        let variance = variance_after(4.0, 1.0 / 60.0, 0.1);
+        assert!(
+            (variance / (0.25 * 3.9) - 1.0).abs() < 0.1,
+            "colored: {}",
+            variance
+        );
+    }
+
+    #[test]
+    fn milstein_is_more_accurate_than_euler_maruyama() {
+        let (mu, s, dt, n_steps) = (0.5, 0.8, 0.01, 100);
+        let error = |method: Method| {
+            let mut total = 0.0;
+            for seed in 0..200 {
+                let mut chaos = Chaos::from_seed(seed);
+                let mut p = Stochastic::new(
+                    Geometric { mu, s },
+                    Vector3::new(1.0, 1.0, 1.0),
+                    method,
+                    0.0,
+                    &mut chaos,
+                );
+                for _ in 0..n_steps {
+                    p.step(dt, &mut chaos);
+                }
+                // the same Wiener path, drawn again
+                let mut chaos = Chaos::from_seed(seed);
+                let mut w = Vector3::zero();
+                for _ in 0..n_steps {
+                    w += dt.sqrt() * chaos.gaussian_vector();
+                }
+                let t = dt * n_steps as f64;
+                let exact = ((mu - 0.5 * s * s) * t + s * w.x).exp();
+                total += (p.position.x - exact).abs();
+            }
+            total / 200.0
+        };
+        let euler_maruyama = error(Method::EulerMaruyama);
+        let milstein = error(Method::Milstein);
+        assert!(
+            milstein < 0.3 * euler_maruyama,
+            "{} vs {}",
+            milstein,
+            euler_maruyama
+        );
+    }
+
+    #[test]
+    fn langevin_settles_into_both_wells() {
+        let field = DoubleWell {
+            barrier: 1.0,
+            separation: 2.0,
+            stiffness: 1.0,
+            temperature: 0.3,
+        };
+        let mut chaos = Chaos::from_seed(2);
+        let mut left = 0;
+        let mut in_a_well = 0;
+        for _ in 0..400 {
+            let mut p = Stochastic::new(
+                DoubleWell { ..field },
+                Vector3::zero(),
+                Method::EulerMaruyama,
+                0.0,
+                &mut chaos,
+            );
+            for _ in 0..600 {
+                p.step(1.0 / 60.0, &mut chaos);
+            }
+            // the odd one is up on the barrier
+            if (p.position.x.abs() - 2.0).abs() < 1.0 {
+                in_a_well += 1;
+            }
+            if p.position.x < 0.0 {
+                left += 1;
+            }
+        }
+        assert!(in_a_well > 360, "{} of 400 in a well", in_a_well);
+        assert!(left > 150 && left < 250, "{} of 400 on the left", left);
+    }
+}
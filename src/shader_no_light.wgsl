@@ -4,6 +4,9 @@
 struct Uniforms {
     view_pos: vec4<f32>;
     view_proj: mat4x4<f32>;
+    premultiply_spheres: i32;
+    premultiply_tails: i32;
+    tail_points: i32;
 };
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniforms;
@@ -56,5 +59,8 @@ fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     if (!enabled) {
         discard;
     }
+    if (uniforms.premultiply_spheres != 0) {
+        return vec4<f32>(in.color.rgb * in.color.a, in.color.a);
+    }
     return in.color;
 }
\ No newline at end of file
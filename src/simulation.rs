@@ -0,0 +1,339 @@
+use anyhow::Result;
+use rayon::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock;
+use crate::interaction;
+use crate::rand_util;
+use crate::scene;
+use crate::script;
+use crate::sphere;
+use crate::tail_store;
+
+// fewest particles handed to a thread at once, stepping a single particle
+// is too little work to be worth the scheduling
+pub const MIN_PARTICLES_PER_TASK: usize = 64;
+// steps between reports of interacting models (e.g. energy drift) in the log
+const REPORT_PERIOD: u64 = 600;
+// with `camera_relative`, how far the camera gets from the origin before
+// it moves along.  f32 still resolves about 1e-4 this far out.
+const RECENTER_DISTANCE: f64 = 1000.0;
+
+/// The particles of a scene and the clock that advances them
+///
+/// This knows nothing about windows or the GPU, see `renderer::Renderer`
+/// for drawing a simulation.
+pub struct Simulation {
+    pub sphere_instances: Vec<sphere::SphereInstance>,
+    pub tails: tail_store::TailStore,
+    pub clock: clock::SimulationClock,
+    pub paused: bool,
+    pub backend: scene::Backend,
+    // positions go to the GPU relative to this, see `follow_camera()`
+    origin: cgmath::Vector3<f64>,
+    camera_relative: bool,
+    // steps all particles at once for models where they act on each other
+    interaction: Option<Box<dyn interaction::Interaction>>,
+    // for `model = "script"`, with its own random stream for `update`
+    script: Option<(Arc<script::Script>, rand_util::Chaos)>,
+    next_report: u64,
+    // steps left for the GPU to take, see `take_gpu_steps()`
+    gpu_steps: u32,
+    seed: u64,
+}
+
+impl Simulation {
+    /// Build the particles described by a scene.  If the scene has no seed
+    /// one is picked and written back to the scene so that saving it
+    /// reproduces this run.  Fails when the scene's script or equations
+    /// don't compile, or its model doesn't exist.
+    pub fn new(scene: &mut scene::Scene) -> Result<Self> {
+        let mut chaos = match scene.seed {
+            Some(seed) => rand_util::Chaos::from_seed(seed),
+            None => rand_util::Chaos::new(),
+        };
+        scene.seed = Some(chaos.seed());
+
+        // looked up (or compiled) once, when the scene was validated
+        let model = scene.dynamics.model()?;
+        let script = scene
+            .dynamics
+            .script()?
+            .map(|script| (script, chaos.fork()));
+        let mut sphere_instances = Vec::new();
+        for (group_index, group) in scene.groups.iter().enumerate() {
+            for _ix in 0..group.count {
+                // every particle gets its own stream so that it is
+                // reproducible independently of the others
+                let mut particle_chaos = chaos.fork();
+                let position = group.spawn.sample(&mut particle_chaos);
+                let mut dynamics = model.build(
+                    &scene.dynamics.params,
+                    position,
+                    scene.dynamics.integrator,
+                    &mut particle_chaos,
+                );
+                if let Some(body) = dynamics.body_mut() {
+                    body.mass = group.mass;
+                    body.velocity = group.velocity.into();
+                    body.group = group_index;
+                }
+                sphere_instances.push(sphere::SphereInstance::from_group(
+                    particle_chaos,
+                    dynamics,
+                    group_index,
+                    group,
+                ));
+            }
+        }
+
+        let interaction = if model.is_interacting() {
+            let mut bodies = bodies(&mut sphere_instances);
+            let interaction =
+                model.build_interaction(&scene.dynamics.params, &mut bodies, &mut chaos);
+            store_bodies(&mut sphere_instances, &bodies);
+            interaction
+        } else {
+            None
+        };
+
+        let clock = clock::SimulationClock::new(
+            scene.simulation.fixed_dt,
+            scene.simulation.time_scale,
+            scene.simulation.max_substeps,
+        );
+
+        let camera_relative = scene.render.camera_relative;
+        let origin = if camera_relative {
+            scene.camera.position.into()
+        } else {
+            cgmath::Vector3::new(0.0, 0.0, 0.0)
+        };
+
+        Ok(Self {
+            sphere_instances,
+            tails: tail_store::TailStore::new(&scene.groups),
+            clock,
+            paused: false,
+            backend: scene.simulation.backend,
+            origin,
+            camera_relative,
+            interaction,
+            script,
+            next_report: REPORT_PERIOD,
+            gpu_steps: 0,
+            seed: chaos.seed(),
+        })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The point that positions on the GPU (sphere instances, tails and the
+    /// camera) are relative to.  The world's origin unless the scene has
+    /// `camera_relative` set.
+    pub fn origin(&self) -> cgmath::Vector3<f64> {
+        self.origin
+    }
+
+    /// With `camera_relative`, move the origin to the camera once it has
+    /// wandered far from it.  Tails were recorded relative to the old
+    /// origin, so they start over.  Returns whether the origin moved.
+    pub fn follow_camera(&mut self, camera: cgmath::Point3<f64>) -> bool {
+        use cgmath::{EuclideanSpace, InnerSpace};
+        let camera = camera.to_vec();
+        if !self.camera_relative || (camera - self.origin).magnitude() <= RECENTER_DISTANCE {
+            return false;
+        }
+        self.origin = camera;
+        self.tails.clear();
+        true
+    }
+
+    /// Advance by a frame's worth of real time, returns the number of fixed
+    /// steps that were taken
+    pub fn advance(&mut self, frame_dt: Duration) -> u32 {
+        if let Some((script, _)) = &self.script {
+            script.reload_if_changed();
+        }
+        if self.paused {
+            return 0;
+        }
+        let n_steps = self.clock.advance(frame_dt);
+        for _ in 0..n_steps {
+            self.step();
+        }
+        n_steps
+    }
+
+    /// Take exactly one fixed step, regardless of pause and real time
+    pub fn step(&mut self) {
+        if self.backend == scene::Backend::Gpu {
+            // the particles are stepped by `compute::ComputeSimulation`
+            self.gpu_steps += 1;
+            self.tails.advance();
+            self.clock.tick();
+            return;
+        }
+
+        if let Some((script, chaos)) = self.script.as_mut() {
+            script.update(&mut self.sphere_instances, &self.clock, chaos);
+        }
+
+        let dt = self.clock.fixed_dt;
+        let interacting = self.interaction.is_some();
+        if let Some(interaction) = self.interaction.as_mut() {
+            let mut bodies = bodies(&mut self.sphere_instances);
+            interaction.step(&mut bodies, dt);
+            store_bodies(&mut self.sphere_instances, &bodies);
+            if self.clock.steps() + 1 >= self.next_report {
+                self.next_report += REPORT_PERIOD;
+                if let Some(report) = interaction.report(&bodies) {
+                    log::info!("Step {}: {}", self.clock.steps() + 1, report);
+                }
+            }
+        }
+
+        // particles only use their own random streams, so the result doesn't
+        // depend on how they are spread over threads
+        self.sphere_instances
+            .par_iter_mut()
+            .with_min_len(MIN_PARTICLES_PER_TASK)
+            .for_each(|sphere_instance| {
+                if sphere_instance.enabled {
+                    // interacting particles were all stepped above
+                    if !interacting {
+                        sphere_instance.update(dt);
+                    }
+                } else {
+                    // if not enabled, randomly enable
+                    let p_enable = sphere_instance.enable_probability;
+                    if sphere_instance.chaos.bernoulli(p_enable) {
+                        sphere_instance.enabled = true;
+                    }
+                }
+            });
+        let positions = self
+            .sphere_instances
+            .par_iter()
+            .with_min_len(MIN_PARTICLES_PER_TASK)
+            .map(|s| sphere::render_position(s.dynamics.get_position(), self.origin))
+            .collect::<Vec<_>>();
+        self.tails.record(positions);
+        self.clock.tick();
+    }
+
+    /// With the GPU backend, the steps taken since the last call: the
+    /// number of the first one and how many there are
+    pub fn take_gpu_steps(&mut self) -> (u64, u32) {
+        let n_steps = std::mem::take(&mut self.gpu_steps);
+        (self.clock.steps() - n_steps as u64, n_steps)
+    }
+}
+
+/// The bodies of the particles of an interacting model
+fn bodies(sphere_instances: &mut [sphere::SphereInstance]) -> Vec<interaction::Body> {
+    sphere_instances
+        .iter_mut()
+        .map(|s| {
+            *s.dynamics
+                .body_mut()
+                .expect("Particles of interacting models are bodies")
+        })
+        .collect()
+}
+
+fn store_bodies(sphere_instances: &mut [sphere::SphereInstance], bodies: &[interaction::Body]) {
+    for (s, body) in sphere_instances.iter_mut().zip(bodies.iter()) {
+        if let Some(b) = s.dynamics.body_mut() {
+            *b = *body;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(simulation: &Simulation) -> Vec<[f64; 3]> {
+        simulation
+            .sphere_instances
+            .iter()
+            .map(|s| s.position())
+            .collect()
+    }
+
+    #[test]
+    fn parallel_steps_are_deterministic() {
+        let mut scene = scene::Scene {
+            seed: Some(99),
+            ..scene::Scene::default()
+        };
+        scene.groups[0].enable_probability = 0.1;
+        let mut a = Simulation::new(&mut scene).unwrap();
+        let mut b = Simulation::new(&mut scene).unwrap();
+
+        // step the second one particle by particle on this thread
+        let dt = b.clock.fixed_dt;
+        for _ in 0..50 {
+            a.step();
+            for s in b.sphere_instances.iter_mut() {
+                if s.enabled {
+                    s.update(dt);
+                } else if s.chaos.bernoulli(s.enable_probability) {
+                    s.enabled = true;
+                }
+            }
+        }
+        assert_eq!(positions(&a), positions(&b));
+        assert!(a.sphere_instances.iter().any(|s| s.enabled));
+
+        let mut c = Simulation::new(&mut scene).unwrap();
+        for _ in 0..50 {
+            c.step();
+        }
+        assert_eq!(positions(&a), positions(&c));
+    }
+
+    #[test]
+    fn far_away_scenes_are_drawn_relative_to_the_camera() {
+        let far = 1e7;
+        let mut scene = scene::Scene::default();
+        // the particles stay where they spawned
+        scene.dynamics.params.insert("speed".to_string(), 0.0);
+        scene.groups[0].enable_probability = 1.0;
+        scene.groups[0].spawn = scene::SpawnConfig::Ball {
+            center: [far, 0.0, 0.0],
+            radius: 1.0,
+        };
+        scene.camera.position = [far, 0.0, 10.0];
+        scene.render.camera_relative = true;
+        let mut simulation = Simulation::new(&mut scene).unwrap();
+        for _ in 0..8 {
+            simulation.step();
+        }
+
+        // f32 can't tell apart points less than a unit apart out there, but
+        // the offsets from the camera keep their precision
+        let origin = simulation.origin();
+        for s in simulation.sphere_instances.iter() {
+            let p = s.dynamics.get_position();
+            let offset = sphere::render_position(p, origin);
+            assert!((offset[0] as f64 - (p.x - far)).abs() < 1e-6);
+        }
+        assert!(!simulation.tails.take_rows().is_empty());
+
+        // a short trip keeps the origin, a long one moves it and clears
+        // the tails recorded relative to the old one
+        assert!(!simulation.follow_camera(cgmath::Point3::new(far + 10.0, 0.0, 0.0)));
+        simulation.step();
+        assert!(simulation.follow_camera(cgmath::Point3::new(far + 2000.0, 0.0, 0.0)));
+        assert_eq!(
+            simulation.origin(),
+            cgmath::Vector3::new(far + 2000.0, 0.0, 0.0)
+        );
+        assert!(simulation.tails.take_rows().is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+use cgmath::{InnerSpace, Vector3};
+use std::collections::HashMap;
+
+/// Points sorted into a uniform grid of cubes, for finding the points near
+/// a position without looking at all of them
+///
+/// Only cells that have points in them are stored, so the points can be
+/// anywhere.  Built from scratch whenever the points move.
+pub struct SpatialHash {
+    cell_size: f64,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl SpatialHash {
+    /// Queries are fastest with `cell_size` about the query radius
+    pub fn new(cell_size: f64, points: &[Vector3<f64>]) -> Self {
+        let mut hash = Self {
+            cell_size,
+            cells: HashMap::new(),
+        };
+        for (ix, point) in points.iter().enumerate() {
+            let cell = hash.cell(*point);
+            hash.cells.entry(cell).or_default().push(ix);
+        }
+        hash
+    }
+
+    fn cell(&self, point: Vector3<f64>) -> [i32; 3] {
+        let p = point / self.cell_size;
+        [p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32]
+    }
+
+    /// Call `f` with the index and squared distance of every point within
+    /// `radius` of `center`, `points` have to be the ones the hash was
+    /// built from
+    pub fn for_each_neighbor<F>(
+        &self,
+        points: &[Vector3<f64>],
+        center: Vector3<f64>,
+        radius: f64,
+        mut f: F,
+    ) where
+        F: FnMut(usize, f64),
+    {
+        let reach = (radius / self.cell_size).ceil() as i32;
+        let [cx, cy, cz] = self.cell(center);
+        let radius2 = radius * radius;
+        for x in cx - reach..=cx + reach {
+            for y in cy - reach..=cy + reach {
+                for z in cz - reach..=cz + reach {
+                    if let Some(cell) = self.cells.get(&[x, y, z]) {
+                        for &ix in cell.iter() {
+                            let distance2 = (points[ix] - center).magnitude2();
+                            if distance2 <= radius2 {
+                                f(ix, distance2);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_util::Chaos;
+
+    #[test]
+    fn finds_the_same_neighbors_as_brute_force() {
+        let mut chaos = Chaos::from_seed(11);
+        let points = (0..500)
+            .map(|_| chaos.random_position_in_cube(5.0))
+            .collect::<Vec<_>>();
+        let hash = SpatialHash::new(1.0, &points);
+        for radius in [0.5, 1.0, 2.5].iter() {
+            for center in points.iter().take(50) {
+                let mut found = Vec::new();
+                hash.for_each_neighbor(&points, *center, *radius, |ix, _| found.push(ix));
+                found.sort_unstable();
+                let expected = (0..points.len())
+                    .filter(|&ix| (points[ix] - center).magnitude2() <= radius * radius)
+                    .collect::<Vec<_>>();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+}
@@ -5,17 +5,20 @@ use wgpu::util::DeviceExt;
 use crate::dynamics;
 use crate::model;
 use crate::rand_util::Chaos;
-use crate::sampler;
-use crate::tail_buffer;
+use crate::scene;
 
 pub struct SphereInstance {
     pub dynamics: Box<dyn dynamics::DynamicSystem>,
     pub radius: f32,
     pub color: [f32; 4],
-    pub heading: f32,
-    pub tail: tail_buffer::TailBuffer<SphereVertex>,
-    sampler: sampler::Sampler,
+    pub heading: f64,
+    // index of the scene group this particle belongs to
+    pub group: usize,
     pub enabled: bool,
+    // per step chance of switching on while disabled
+    pub enable_probability: f64,
+    // this particle's own random stream
+    pub chaos: Chaos,
 }
 
 bitflags! {
@@ -26,49 +29,44 @@ bitflags! {
 }
 
 impl SphereInstance {
-    pub fn randomized(chaos: &mut Chaos, dynamics: Box<dyn dynamics::DynamicSystem>) -> Self {
-        let tail_capacity = 1024;
-
+    pub fn from_group(
+        mut chaos: Chaos,
+        dynamics: Box<dyn dynamics::DynamicSystem>,
+        group_index: usize,
+        group: &scene::GroupConfig,
+    ) -> Self {
         Self {
             dynamics,
-            radius: 0.1,
-            color: chaos.random_solid_color(),
+            radius: group.radius,
+            color: group.color.sample(&mut chaos),
             heading: chaos.unit_radian_noise(),
-            tail: tail_buffer::TailBuffer::new(tail_capacity),
-            sampler: sampler::Sampler::new(4),
+            group: group_index,
             enabled: false,
+            enable_probability: group.enable_probability,
+            chaos,
         }
     }
 
-    pub fn update(&mut self, chaos: &mut Chaos) {
-        self.dynamics.step(chaos);
-        self.push_tail();
-    }
-
-    pub fn push_tail(&mut self) {
-        if self.sampler.check() {
-            let pos = self.dynamics.get_position();
-            self.tail.push(SphereVertex{
-                position: [pos.x, pos.y, pos.z]
-            });
-        }
-    }
-
-    pub fn raw_tail(&self) -> Vec<SphereVertex> {
-        self.tail.to_vec()
+    pub fn update(&mut self, dt: f64) {
+        self.dynamics.step(dt, &mut self.chaos);
     }
 
-    pub fn tail_len(&self) -> usize {
-        self.tail.len()
+    pub fn position(&self) -> [f64; 3] {
+        self.dynamics.get_position().into()
     }
 
-    pub fn to_raw(&self) -> SphereInstanceRaw {
+    /// What the shaders need to draw this particle, with its position
+    /// relative to `origin` (see `render_position`)
+    pub fn to_raw(&self, origin: cgmath::Vector3<f64>) -> SphereInstanceRaw {
+        let position = render_position(self.dynamics.get_position(), origin);
         SphereInstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.dynamics.get_position())
+            model: (cgmath::Matrix4::from_translation(position.into())
                 * cgmath::Matrix4::from_scale(self.radius))
             .into(),
             color: self.color,
             attrs: self.attrs().bits(),
+            group: self.group as u32,
+            _padding: [0; 2],
         }
     }
 
@@ -81,12 +79,27 @@ impl SphereInstance {
     }
 }
 
+/// A simulated position as the GPU sees it, in f32 and relative to
+/// `origin`
+///
+/// Particles are simulated in f64, only the offset from the origin is
+/// rounded, so things near the origin keep their precision however far it
+/// is from the world's origin.
+pub fn render_position(position: cgmath::Vector3<f64>, origin: cgmath::Vector3<f64>) -> [f32; 3] {
+    let offset = position - origin;
+    [offset.x as f32, offset.y as f32, offset.z as f32]
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct SphereInstanceRaw {
     model: [[f32; 4]; 4],
     color: [f32; 4],
     attrs: i32,
+    group: u32,
+    // compute.wgsl writes these as an array in a storage buffer, which
+    // needs a stride that is a multiple of 16
+    _padding: [u32; 2],
 }
 
 impl model::Vertex for SphereInstanceRaw {
@@ -135,6 +148,12 @@ impl model::Vertex for SphereInstanceRaw {
                     shader_location: 10,
                     format: wgpu::VertexFormat::Sint32,
                 },
+                // group
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 21]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Uint32,
+                },
             ],
         }
     }
@@ -4,49 +4,90 @@
 struct Uniforms {
     view_pos: vec4<f32>;
     view_proj: mat4x4<f32>;
+    premultiply_spheres: i32;
+    premultiply_tails: i32;
+    tail_points: i32;
 };
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniforms;
 
-struct VertexInput {
-    [[location(0)]] position: vec3<f32>;
+// every tail point of every particle as x, y, z, split over pages, see
+// tail_store.rs for the layout
+[[block]]
+struct TailPoints {
+    points: [[stride(4)]] array<f32>;
+};
+struct TailGroup {
+    first_particle: u32;
+    count: u32;
+    base: u32;
+    capacity: u32;
+    // next row to be written, and the number of rows written so far
+    head: u32;
+    len: u32;
+    // sample every `period` steps
+    period: u32;
+    padding: u32;
+};
+[[block]]
+struct TailGroups {
+    groups: [[stride(32)]] array<TailGroup>;
 };
+[[group(1), binding(0)]]
+var<storage> tail_groups: [[access(read)]] TailGroups;
+// tail pages
+
 struct InstanceInput {
-    [[location(5)]] model_matrix_0: vec4<f32>;
-    [[location(6)]] model_matrix_1: vec4<f32>;
-    [[location(7)]] model_matrix_2: vec4<f32>;
-    [[location(8)]] model_matrix_3: vec4<f32>;
     [[location(9)]] color: vec4<f32>;
     [[location(10)]] attrs: i32;
-    [[builtin(vertex_index)]] vertex_index: u32;
+    [[location(11)]] group: u32;
 };
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] color: vec4<f32>;
     [[location(1)]] attrs: i32;
-    [[location(2)]] age: f32;
+    // fades the tail out with age
+    [[location(2)]] weight: f32;
 };
 
+// Each instance is one particle's tail drawn as a line list, segment n
+// joins the points n and n + 1 samples old.  Tails shorter than the
+// longest one get their extra segments moved outside of the clip volume.
+// With `tail_points` set the tail is a point list instead, vertex n is the
+// point n samples old.
 [[stage(vertex)]]
 fn main(
-    model: VertexInput,
     instance: InstanceInput,
+    [[builtin(vertex_index)]] vertex_index: u32,
+    [[builtin(instance_index)]] instance_index: u32,
 ) -> VertexOutput {
-    let model_matrix = mat4x4<f32>(
-        instance.model_matrix_0,
-        instance.model_matrix_1,
-        instance.model_matrix_2,
-        instance.model_matrix_3,
-    );
-
-    let world_position = vec4<f32>(model.position, 1.0);
+    let group = tail_groups.groups[instance.group];
+    var age: u32 = vertex_index;
+    var last: u32 = vertex_index;
+    if (uniforms.tail_points == 0) {
+        let segment = vertex_index / 2u;
+        age = segment + vertex_index % 2u;
+        last = segment + 1u;
+    }
 
     var out: VertexOutput;
-    out.clip_position = uniforms.view_proj * world_position;
     out.color = instance.color;
     out.attrs = instance.attrs;
-    out.age = f32(instance.vertex_index);
+    out.weight = exp(-0.05 * f32(age));
+    if (uniforms.tail_points != 0) {
+        // point clouds are built up over the whole tail, only the very
+        // oldest points fade out
+        out.weight = clamp(10.0 * (1.0 - f32(age) / f32(group.capacity)), 0.0, 1.0);
+    }
+    if (last >= group.len) {
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+        return out;
+    }
+
+    let row = (group.head + group.capacity - 1u - age) % group.capacity;
+    let ix = group.base + row * group.count + instance_index - group.first_particle;
+    out.clip_position = uniforms.view_proj * vec4<f32>(tail_point(ix), 1.0);
     return out;
 }
 
@@ -55,11 +96,17 @@ fn main(
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     let enabled = (in.attrs & 1) > 0;
-    let weight = exp(-0.05 * in.age);
+    let weight = in.weight;
 
     if (!enabled || weight < 0.01) {
         discard;
     }
 
-    return weight * in.color;
-}
\ No newline at end of file
+    // fading trails get more transparent, or darker when alpha is
+    // multiplied in
+    let alpha = weight * in.color.a;
+    if (uniforms.premultiply_tails != 0) {
+        return vec4<f32>(in.color.rgb * alpha, alpha);
+    }
+    return vec4<f32>(in.color.rgb, alpha);
+}
@@ -0,0 +1,426 @@
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use crate::sampler;
+use crate::scene;
+
+/// Tails of every particle, kept as one ring buffer on the GPU
+///
+/// Each group of particles owns a block of `capacity` rows of `count`
+/// points, and all particles of a group are sampled at the same time: a
+/// sample writes one row (the current position of every particle in the
+/// group) at the group's head.  Only new rows are uploaded, and the whole
+/// store is drawn with a single instanced draw.
+///
+/// This is the CPU side, it tracks the heads and queues new rows until the
+/// renderer takes them.
+pub struct TailStore {
+    groups: Vec<TailGroup>,
+}
+
+struct TailGroup {
+    first_particle: usize,
+    count: usize,
+    // offset of the group's first point in the store
+    base: usize,
+    capacity: usize,
+    period: u8,
+    sampler: sampler::Sampler,
+    // next row to write
+    head: usize,
+    len: usize,
+    // rows that haven't been uploaded yet
+    pending: VecDeque<(usize, Vec<[f32; 3]>)>,
+}
+
+/// A row of new points and where it goes in the store, in points
+pub struct TailRow {
+    pub offset: usize,
+    pub points: Vec<[f32; 3]>,
+}
+
+/// What the tail shader needs to know about a group
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TailGroupRaw {
+    first_particle: u32,
+    count: u32,
+    base: u32,
+    capacity: u32,
+    head: u32,
+    len: u32,
+    period: u32,
+    _padding: u32,
+}
+
+/// Size of one tail point on the GPU, in bytes: x, y and z as f32
+pub const POINT_SIZE: usize = std::mem::size_of::<[f32; 3]>();
+
+/// How the store is split over storage buffers
+///
+/// A single storage binding can't be bigger than the device's
+/// `max_storage_buffer_binding_size` (only 128 MiB on D3D12), so the
+/// points go in pages of `page_points` points, each its own buffer and
+/// binding.  The shaders that use the store get a declaration for every
+/// page, see `wgsl`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pages {
+    pub count: usize,
+    pub page_points: usize,
+    n_points: usize,
+}
+
+/// Check that the tails of `groups` fit in the storage buffers a shader
+/// stage can bind with `limits`, next to `other_buffers` storage buffers of
+/// its own, and split them into pages
+pub fn check_fits(
+    groups: &[scene::GroupConfig],
+    limits: &wgpu::Limits,
+    other_buffers: u32,
+) -> Result<Pages> {
+    let group_points = |group: &scene::GroupConfig| group.count * group.tail.capacity;
+    let n_points: usize = groups.iter().map(group_points).sum();
+    let max_page_points = limits.max_storage_buffer_binding_size as usize / POINT_SIZE;
+    let page_points = max_page_points.min(n_points).max(1);
+    let count = n_points.div_ceil(page_points);
+    let max_pages = limits
+        .max_storage_buffers_per_shader_stage
+        .saturating_sub(other_buffers) as usize;
+    if count <= max_pages {
+        return Ok(Pages {
+            count,
+            page_points,
+            n_points,
+        });
+    }
+    let mib = |points: usize| (points * POINT_SIZE) as f64 / (1 << 20) as f64;
+    let details = groups
+        .iter()
+        .enumerate()
+        .map(|(ix, group)| {
+            format!(
+                "group {} has count = {} and tail.capacity = {} ({:.1} MiB)",
+                ix + 1,
+                group.count,
+                group.tail.capacity,
+                mib(group_points(group))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    bail!(
+        "Tails need {:.1} MiB in {} storage buffers of at most {:.1} MiB, but the device only allows {}, lower the count or tail.capacity of the groups: {}",
+        mib(n_points),
+        count,
+        mib(max_page_points),
+        max_pages,
+        details
+    )
+}
+
+impl Pages {
+    /// Size of page `page`, in points
+    pub fn len(&self, page: usize) -> usize {
+        self.page_points
+            .min(self.n_points.saturating_sub(page * self.page_points))
+    }
+
+    /// Split `len` points starting at `offset` along page boundaries, as
+    /// (page, offset in the page, range of the points)
+    pub fn split(&self, offset: usize, len: usize) -> Vec<(usize, usize, Range<usize>)> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let ix = offset + done;
+            let (page, start) = (ix / self.page_points, ix % self.page_points);
+            let n = (len - done).min(self.page_points - start);
+            pieces.push((page, start, done..done + n));
+            done += n;
+        }
+        pieces
+    }
+
+    /// WGSL declarations of the pages, bound in `group` from
+    /// `first_binding` on, and a function to get at point `ix` of the
+    /// store: `tail_point(ix)` when read only, otherwise
+    /// `store_tail_point(ix, p)`.  They go where the shader has a
+    /// `// tail pages` line, and need its `TailPoints` struct.
+    pub fn wgsl(&self, group: u32, first_binding: u32, writable: bool) -> String {
+        let access = if writable { "read_write" } else { "read" };
+        let mut text = String::new();
+        for page in 0..self.count {
+            text += &format!(
+                "[[group({}), binding({})]]\nvar<storage> tail_page_{}: [[access({})]] TailPoints;\n",
+                group,
+                first_binding + page as u32,
+                page,
+                access
+            );
+        }
+        if writable {
+            text += "fn store_tail_point(ix: u32, p: vec3<f32>) {\n";
+        } else {
+            text += "fn tail_point(ix: u32) -> vec3<f32> {\n";
+        }
+        text += &format!(
+            "    let page = ix / {page_points}u;\n    let i = 3u * (ix % {page_points}u);\n",
+            page_points = self.page_points
+        );
+        for page in 0..self.count {
+            let points = format!("tail_page_{}.points", page);
+            let body = if writable {
+                format!(
+                    "{p}[i] = p.x;\n        {p}[i + 1u] = p.y;\n        {p}[i + 2u] = p.z;\n        return;",
+                    p = points
+                )
+            } else {
+                format!(
+                    "return vec3<f32>({p}[i], {p}[i + 1u], {p}[i + 2u]);",
+                    p = points
+                )
+            };
+            text += &format!("    if (page == {}u) {{\n        {}\n    }}\n", page, body);
+        }
+        if !writable {
+            text += "    return vec3<f32>(0.0, 0.0, 0.0);\n";
+        }
+        text += "}\n";
+        text
+    }
+
+    /// `source` with the pages filled in, see `wgsl`
+    pub fn fill_in(&self, source: &str, group: u32, first_binding: u32, writable: bool) -> String {
+        source.replace(
+            "// tail pages\n",
+            &self.wgsl(group, first_binding, writable),
+        )
+    }
+}
+
+impl TailStore {
+    pub fn new(groups: &[scene::GroupConfig]) -> Self {
+        let mut first_particle = 0;
+        let mut base = 0;
+        let groups = groups
+            .iter()
+            .map(|group| {
+                let tail_group = TailGroup {
+                    first_particle,
+                    count: group.count,
+                    base,
+                    capacity: group.tail.capacity,
+                    period: group.tail.period,
+                    sampler: sampler::Sampler::new(group.tail.period),
+                    head: 0,
+                    len: 0,
+                    pending: VecDeque::new(),
+                };
+                first_particle += group.count;
+                base += group.count * group.tail.capacity;
+                tail_group
+            })
+            .collect();
+        Self { groups }
+    }
+
+    /// Number of points in the store
+    pub fn n_points(&self) -> usize {
+        self.groups
+            .iter()
+            .map(|group| group.count * group.capacity)
+            .sum()
+    }
+
+    /// Longest tail, in points
+    pub fn max_capacity(&self) -> usize {
+        self.groups
+            .iter()
+            .map(|group| group.capacity)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Called after every simulation step with the position of every
+    /// particle, groups that are due for a sample record a row
+    pub fn record<I>(&mut self, positions: I)
+    where
+        I: IntoIterator<Item = [f32; 3]>,
+    {
+        let mut positions = positions.into_iter();
+        for group in self.groups.iter_mut() {
+            let points = positions
+                .by_ref()
+                .take(group.count)
+                .map(|p| [p[0], p[1], p[2]]);
+            match group.sample() {
+                Some(row) => {
+                    group.pending.push_back((row, points.collect()));
+                    // rows that were overwritten before being uploaded are dropped
+                    if group.pending.len() > group.capacity {
+                        group.pending.pop_front();
+                    }
+                }
+                // still have to move past this group's particles
+                None => points.for_each(drop),
+            }
+        }
+    }
+
+    /// Like `record`, for when the points are written on the GPU (see
+    /// compute.wgsl): the heads move along but no rows are queued
+    pub fn advance(&mut self) {
+        for group in self.groups.iter_mut() {
+            group.sample();
+        }
+    }
+
+    /// Drop every tail, they start again from the particles' next samples
+    pub fn clear(&mut self) {
+        for group in self.groups.iter_mut() {
+            group.head = 0;
+            group.len = 0;
+            group.pending.clear();
+        }
+    }
+
+    /// Take the rows recorded since the last call
+    pub fn take_rows(&mut self) -> Vec<TailRow> {
+        let mut rows = Vec::new();
+        for group in self.groups.iter_mut() {
+            for (row, points) in group.pending.drain(..) {
+                rows.push(TailRow {
+                    offset: group.base + row * group.count,
+                    points,
+                });
+            }
+        }
+        rows
+    }
+
+    pub fn to_raw(&self) -> Vec<TailGroupRaw> {
+        self.groups
+            .iter()
+            .map(|group| TailGroupRaw {
+                first_particle: group.first_particle as u32,
+                count: group.count as u32,
+                base: group.base as u32,
+                capacity: group.capacity as u32,
+                head: group.head as u32,
+                len: group.len as u32,
+                period: group.period as u32,
+                _padding: 0,
+            })
+            .collect()
+    }
+}
+
+impl TailGroup {
+    /// Take a sample if one is due, returns the row it goes in
+    fn sample(&mut self) -> Option<usize> {
+        if !self.sampler.check() {
+            return None;
+        }
+        let row = self.head;
+        self.head = (self.head + 1) % self.capacity;
+        self.len = (self.len + 1).min(self.capacity);
+        Some(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(count: usize, capacity: usize, period: u8) -> scene::GroupConfig {
+        scene::GroupConfig {
+            count,
+            tail: scene::TailConfig { capacity, period },
+            ..scene::GroupConfig::default()
+        }
+    }
+
+    #[test]
+    fn rows_wrap_around_each_group() {
+        let mut store = TailStore::new(&[group(2, 3, 1), group(1, 2, 2)]);
+        assert_eq!(store.n_points(), 2 * 3 + 2);
+        assert_eq!(store.max_capacity(), 3);
+
+        for step in 0..4 {
+            let x = step as f32;
+            store.record(vec![[x, 0.0, 0.0], [x, 1.0, 0.0], [x, 2.0, 0.0]]);
+        }
+
+        let rows = store.take_rows();
+        let offsets = rows.iter().map(|row| row.offset).collect::<Vec<_>>();
+        // the first group wrapped around, so only its newest 3 rows are
+        // kept, the second group samples every other step
+        assert_eq!(offsets, vec![2, 4, 0, 6, 7]);
+        assert_eq!(rows[2].points, vec![[3.0, 0.0, 0.0], [3.0, 1.0, 0.0]]);
+        assert_eq!(rows[4].points, vec![[2.0, 2.0, 0.0]]);
+        assert!(store.take_rows().is_empty());
+
+        let raw = store.to_raw();
+        assert_eq!((raw[0].head, raw[0].len), (1, 3));
+        assert_eq!((raw[1].first_particle, raw[1].base), (2, 6));
+        assert_eq!((raw[1].head, raw[1].len), (0, 2));
+    }
+
+    #[test]
+    fn big_stores_are_split_into_pages() {
+        // what D3D12 grants: 128 MiB bindings, but plenty of them
+        let limits = wgpu::Limits {
+            max_storage_buffers_per_shader_stage: 8,
+            ..wgpu::Limits::default()
+        };
+        // 50k particles with the default tails, next to the 3 other
+        // buffers of the compute shader
+        let scene = scene::Scene {
+            groups: vec![scene::GroupConfig {
+                count: 50_000,
+                ..scene::GroupConfig::default()
+            }],
+            ..scene::Scene::default()
+        };
+        let pages = check_fits(&scene.groups, &limits, 3).unwrap();
+        assert_eq!(pages.count, 5);
+        assert!(pages.page_points * POINT_SIZE <= 128 << 20);
+        let n_points = 50_000 * 1024;
+        assert_eq!((0..5).map(|page| pages.len(page)).sum::<usize>(), n_points);
+
+        // a row across a page boundary is written in two pieces
+        let split = pages.split(pages.page_points - 2, 5);
+        assert_eq!(split, vec![(0, pages.page_points - 2, 0..2), (1, 0, 2..5)]);
+
+        let err = check_fits(&scene.groups, &wgpu::Limits::default(), 1)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("group 1 has count = 50000 and tail.capacity = 1024"));
+
+        // small stores get one page of just the right size
+        let pages = check_fits(&[group(2, 3, 1)], &wgpu::Limits::default(), 1).unwrap();
+        assert_eq!((pages.count, pages.len(0)), (1, 6));
+    }
+
+    #[test]
+    fn heads_follow_the_compute_schedule() {
+        // compute.wgsl writes step k = sample * period into row
+        // sample % capacity, and nothing on the steps in between
+        let (capacity, period) = (3, 4);
+        let mut store = TailStore::new(&[group(1, capacity, period as u8)]);
+        for sample in 0..10 {
+            for offset in 0..period {
+                let head = store.to_raw()[0].head as usize;
+                store.advance();
+                let new_head = store.to_raw()[0].head as usize;
+                if offset == 0 {
+                    assert_eq!(head, sample % capacity);
+                    assert_eq!(new_head, (head + 1) % capacity);
+                } else {
+                    assert_eq!(new_head, head);
+                }
+            }
+        }
+        assert!(store.take_rows().is_empty());
+    }
+}
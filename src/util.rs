@@ -1,12 +1,66 @@
-pub fn create_render_pipeline(
-    device: &wgpu::Device,
-    layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
-    vertex_layouts: &[wgpu::VertexBufferLayout],
-    shader: wgpu::ShaderModuleDescriptor,
-) -> wgpu::RenderPipeline {
-    let primitive = wgpu::PrimitiveState {
+use serde::{Deserialize, Serialize};
+
+/// How a pipeline's output is combined with what is already drawn
+///
+/// `Alpha` and `Additive` expect straight (non-premultiplied) color from
+/// the shader, `Replace` and `Premultiplied` expect color already multiplied
+/// by alpha, see `premultiplies()`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    // overwrite, the only mode that writes depth
+    Replace,
+    // alpha-over: src * a + dst * (1 - a)
+    Alpha,
+    // accumulate light: src * a + dst
+    Additive,
+    // alpha-over for premultiplied color: src + dst * (1 - a)
+    Premultiplied,
+}
+
+impl BlendMode {
+    pub fn blend_state(self) -> wgpu::BlendState {
+        use wgpu::{BlendComponent, BlendFactor, BlendOperation};
+        let component = |src_factor, dst_factor| BlendComponent {
+            src_factor,
+            dst_factor,
+            operation: BlendOperation::Add,
+        };
+        match self {
+            BlendMode::Replace => wgpu::BlendState {
+                alpha: BlendComponent::REPLACE,
+                color: BlendComponent::REPLACE,
+            },
+            BlendMode::Alpha => wgpu::BlendState {
+                alpha: component(BlendFactor::One, BlendFactor::OneMinusSrcAlpha),
+                color: component(BlendFactor::SrcAlpha, BlendFactor::OneMinusSrcAlpha),
+            },
+            BlendMode::Additive => wgpu::BlendState {
+                alpha: component(BlendFactor::One, BlendFactor::One),
+                color: component(BlendFactor::SrcAlpha, BlendFactor::One),
+            },
+            BlendMode::Premultiplied => wgpu::BlendState {
+                alpha: component(BlendFactor::One, BlendFactor::OneMinusSrcAlpha),
+                color: component(BlendFactor::One, BlendFactor::OneMinusSrcAlpha),
+            },
+        }
+    }
+
+    /// Translucent passes test depth but don't write it, so that they don't
+    /// hide each other
+    pub fn writes_depth(self) -> bool {
+        self == BlendMode::Replace
+    }
+
+    /// Whether the shader should multiply its color by alpha
+    pub fn premultiplies(self) -> bool {
+        matches!(self, BlendMode::Replace | BlendMode::Premultiplied)
+    }
+}
+
+/// Filled, back face culled triangles
+pub fn triangle_list() -> wgpu::PrimitiveState {
+    wgpu::PrimitiveState {
         topology: wgpu::PrimitiveTopology::TriangleList,
         strip_index_format: None,
         front_face: wgpu::FrontFace::Ccw,
@@ -17,8 +71,17 @@ pub fn create_render_pipeline(
         clamp_depth: false,
         // Requires Features::CONSERVATIVE_RASTERIZATION
         conservative: false,
-    };
+    }
+}
 
+pub fn create_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    color_format: wgpu::TextureFormat,
+    depth_format: Option<wgpu::TextureFormat>,
+    vertex_layouts: &[wgpu::VertexBufferLayout],
+    shader: wgpu::ShaderModuleDescriptor,
+) -> wgpu::RenderPipeline {
     create_render_pipeline_with_primitive(
         device,
         layout,
@@ -26,10 +89,12 @@ pub fn create_render_pipeline(
         depth_format,
         vertex_layouts,
         shader,
-        primitive,
+        triangle_list(),
+        BlendMode::Replace,
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_render_pipeline_with_primitive(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
@@ -38,6 +103,7 @@ pub fn create_render_pipeline_with_primitive(
     vertex_layouts: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
     primitive: wgpu::PrimitiveState,
+    blend: BlendMode,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(&shader);
 
@@ -54,17 +120,14 @@ pub fn create_render_pipeline_with_primitive(
             entry_point: "main",
             targets: &[wgpu::ColorTargetState {
                 format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
+                blend: Some(blend.blend_state()),
                 write_mask: wgpu::ColorWrite::ALL,
             }],
         }),
         primitive: primitive,
         depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
             format,
-            depth_write_enabled: true,
+            depth_write_enabled: blend.writes_depth(),
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),